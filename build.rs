@@ -0,0 +1,10 @@
+// Generate the gRPC server and client of proto/rublo.proto with the vendored `protoc`, so that
+// building doesn't require one to be installed. The `connect` helper of the client is left out,
+// it can't be compiled with the 2018 edition: clients are built out of a `Channel`.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::configure()
+        .build_transport(false)
+        .compile(&["proto/rublo.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,423 @@
+use crate::crypto::Key;
+use crate::storage;
+use crate::AsyncResult;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+// File name of the append-only log inside the data directory
+pub const AOF_FILENAME: &str = "appendonly.aof";
+// Size in bytes of each record header, length and crc32 of the payload
+const RECORD_HEADER_SIZE: usize = 8;
+// Bit of the record length marking encrypted payloads
+const RECORD_ENCRYPTED: u32 = 1 << 31;
+
+/// When to fsync the append-only log to disk:
+/// - `Always` after every write, slowest but no acknowledged write is ever lost
+/// - `EverySec` once a second, at most a second of writes lost on a crash
+/// - `Never` leave it to the operating system
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum FsyncPolicy {
+    #[serde(rename(deserialize = "always"))]
+    Always,
+    #[serde(rename(deserialize = "everysec"))]
+    EverySec,
+    #[serde(rename(deserialize = "no"))]
+    Never,
+}
+
+impl FsyncPolicy {
+    pub fn every_sec() -> Self {
+        FsyncPolicy::EverySec
+    }
+}
+
+/// Write operation recorded into the log, replayed on top of the last snapshot on startup
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum Operation {
+    Create {
+        name: String,
+        capacity: usize,
+        fpp: f64,
+    },
+    Set {
+        name: String,
+        key: Vec<u8>,
+    },
+    Clear {
+        name: String,
+    },
+    Drop {
+        name: String,
+    },
+    /// Creation of a filter with memory-mapped bitmaps, kept apart from `Create` so that logs
+    /// written before it existed still decode
+    CreateMapped {
+        name: String,
+        capacity: usize,
+        fpp: f64,
+    },
+}
+
+impl Operation {
+    /// Name of the filter the operation applies to
+    pub fn name(&self) -> &str {
+        match self {
+            Operation::Create { name, .. } => name,
+            Operation::Set { name, .. } => name,
+            Operation::Clear { name } => name,
+            Operation::Drop { name } => name,
+            Operation::CreateMapped { name, .. } => name,
+        }
+    }
+}
+
+/// Append-only log of the write operations received since the last successful snapshot of the
+/// filters. Each record is framed as a little endian u32 length, a crc32 of the payload and the
+/// bincode serialized `Operation`. With a key the payload is encrypted and its length flagged with
+/// `RECORD_ENCRYPTED`, the checksum then covering the encrypted payload.
+pub struct AppendOnlyLog {
+    path: PathBuf,
+    file: fs::File,
+    policy: FsyncPolicy,
+    /// Key encrypting the records, if encryption at rest is enabled
+    key: Option<Key>,
+    /// Writes not yet synced to disk
+    unsynced: bool,
+}
+
+impl AppendOnlyLog {
+    /// Open the log at `path`, creating it if missing, and return the operations it already
+    /// contains. A torn record at the end of the file, left by a crash in the middle of a write,
+    /// is discarded and trimmed away so that new records are appended after the last valid one.
+    /// Records written without encryption are read whether a `key` is given or not.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the log can't be read, holds a corrupt record followed by more data, or
+    /// holds encrypted records and `key` is missing or wrong. The log is left untouched then.
+    pub async fn open(
+        path: &Path,
+        policy: FsyncPolicy,
+        key: Option<Key>,
+    ) -> AsyncResult<(Self, Vec<Operation>)> {
+        let data = match fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let (operations, valid) =
+            Self::parse(&data, key.as_ref()).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let file = Self::open_append(path).await?;
+        if valid < data.len() {
+            warn!(
+                "discarding {} bytes of incomplete records at the end of {}",
+                data.len() - valid,
+                path.display()
+            );
+            file.set_len(valid as u64).await?;
+            file.sync_all().await?;
+        }
+        info!(
+            "{} operations found in {}",
+            operations.len(),
+            path.display()
+        );
+        let aof = AppendOnlyLog {
+            path: path.to_path_buf(),
+            file,
+            policy,
+            key,
+            unsynced: false,
+        };
+        Ok((aof, operations))
+    }
+
+    /// Append an operation to the log, syncing it right away if the policy is `Always`
+    pub async fn append(&mut self, operation: &Operation) -> AsyncResult<()> {
+        let record = self.encode(operation)?;
+        self.file.write_all(&record).await?;
+        self.file.flush().await?;
+        self.unsynced = true;
+        if self.policy == FsyncPolicy::Always {
+            self.sync().await?;
+        }
+        Ok(())
+    }
+
+    /// Sync pending writes to disk, meant to be called every second with `EverySec` policy
+    pub async fn sync(&mut self) -> AsyncResult<()> {
+        if self.unsynced {
+            self.file.sync_data().await?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the log keeping only the operations matching `keep`, used to drop the records of
+    /// the filters persisted by a snapshot while those of others are still pending. The new log
+    /// replaces the old one atomically.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the log can't be read or rewritten, or if any part of it can't be
+    /// decoded, in which case it's left untouched.
+    pub async fn retain<F>(&mut self, keep: F) -> AsyncResult<()>
+    where
+        F: Fn(&Operation) -> bool,
+    {
+        let data = fs::read(&self.path).await?;
+        let (operations, valid) = Self::parse(&data, self.key.as_ref())
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+        if valid < data.len() {
+            return Err(format!(
+                "{}: {} bytes of invalid records at the end",
+                self.path.display(),
+                data.len() - valid
+            )
+            .into());
+        }
+        let mut rewritten = Vec::new();
+        for operation in operations.iter().filter(|op| keep(op)) {
+            rewritten.extend_from_slice(&self.encode(operation)?);
+        }
+        storage::write_atomic(&self.path, &rewritten).await?;
+        self.file = Self::open_append(&self.path).await?;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    async fn open_append(path: &Path) -> AsyncResult<fs::File> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(file)
+    }
+
+    fn encode(&self, operation: &Operation) -> AsyncResult<Vec<u8>> {
+        let mut payload = bincode::serialize(operation)?;
+        let mut length = payload.len() as u32;
+        if let Some(key) = &self.key {
+            payload = key.encrypt(&payload)?;
+            length = payload.len() as u32 | RECORD_ENCRYPTED;
+        }
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+        record.extend_from_slice(&length.to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        Ok(record)
+    }
+
+    // Decode every valid record, returning them together with the number of bytes they span.
+    // Only the last record may be invalid, when it's cut short or its checksum doesn't match
+    // because a crash interrupted its write. A corrupt record followed by more data, or an intact
+    // one that can't be decrypted or decoded, is an error rather than the end of the log, so that
+    // no acknowledged write is discarded.
+    fn parse(data: &[u8], key: Option<&Key>) -> AsyncResult<(Vec<Operation>, usize)> {
+        let mut operations = Vec::new();
+        let mut offset = 0;
+        while data.len() - offset >= RECORD_HEADER_SIZE {
+            let header = &data[offset..offset + RECORD_HEADER_SIZE];
+            let length = u32::from_le_bytes(header[..4].try_into().unwrap());
+            let encrypted = length & RECORD_ENCRYPTED != 0;
+            let length = (length & !RECORD_ENCRYPTED) as usize;
+            let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+            let start = offset + RECORD_HEADER_SIZE;
+            if data.len() - start < length {
+                break;
+            }
+            let end = start + length;
+            let mut payload = Cow::Borrowed(&data[start..end]);
+            if crc32fast::hash(&payload) != checksum {
+                if end == data.len() {
+                    break;
+                }
+                return Err(format!(
+                    "corrupt record at offset {} followed by {} bytes",
+                    offset,
+                    data.len() - end
+                )
+                .into());
+            }
+            if encrypted {
+                let key = key.ok_or("encrypted log but no encryption key is configured")?;
+                let decrypted = key
+                    .decrypt(&payload)
+                    .ok_or("decryption failed: wrong encryption key")?;
+                payload = Cow::Owned(decrypted);
+            }
+            let operation = bincode::deserialize(&payload)
+                .map_err(|e| format!("undecodable record at offset {}: {}", offset, e))?;
+            operations.push(operation);
+            offset = end;
+        }
+        Ok((operations, offset))
+    }
+}
+
+#[cfg(test)]
+mod aof_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_append_replay() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join(AOF_FILENAME);
+        let operations = vec![
+            Operation::Create {
+                name: "foo".into(),
+                capacity: 5,
+                fpp: 0.01,
+            },
+            Operation::Set {
+                name: "foo".into(),
+                key: b"bar".to_vec(),
+            },
+            Operation::Clear { name: "foo".into() },
+            Operation::Drop { name: "foo".into() },
+        ];
+        let (mut aof, replayed) = AppendOnlyLog::open(&path, FsyncPolicy::Always, None)
+            .await
+            .unwrap();
+        assert!(replayed.is_empty());
+        for op in operations.iter() {
+            aof.append(op).await.unwrap();
+        }
+        drop(aof);
+        // Simulate a crash in the middle of a record
+        let mut data = fs::read(&path).await.unwrap();
+        let valid = data.len();
+        data.extend_from_slice(&[42, 0, 0, 0, 1, 2]);
+        fs::write(&path, &data).await.unwrap();
+        let (mut aof, replayed) = AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .unwrap();
+        assert_eq!(replayed, operations);
+        assert_eq!(fs::metadata(&path).await.unwrap().len() as usize, valid);
+        aof.append(&Operation::Clear { name: "foo".into() })
+            .await
+            .unwrap();
+        aof.retain(|_| false).await.unwrap();
+        aof.append(&Operation::Drop { name: "bar".into() })
+            .await
+            .unwrap();
+        drop(aof);
+        let (_, replayed) = AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .unwrap();
+        assert_eq!(replayed, vec![Operation::Drop { name: "bar".into() }]);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_record() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join(AOF_FILENAME);
+        let (mut aof, _) = AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .unwrap();
+        for name in ["foo", "bar", "baz"].iter() {
+            aof.append(&Operation::Clear {
+                name: name.to_string(),
+            })
+            .await
+            .unwrap();
+        }
+        // Flip a byte in the payload of the first record
+        let mut data = fs::read(&path).await.unwrap();
+        data[RECORD_HEADER_SIZE] ^= 0xff;
+        fs::write(&path, &data).await.unwrap();
+        let err = aof.retain(|_| true).await.err().unwrap();
+        assert!(err.to_string().contains("corrupt record at offset 0"));
+        drop(aof);
+        // The records after it are neither replayed as if they were lost nor trimmed away
+        let err = AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("corrupt record at offset 0"));
+        assert_eq!(fs::read(&path).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_log() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join(AOF_FILENAME);
+        let key = Key::from_hex(&"2a".repeat(crate::crypto::KEY_SIZE)).unwrap();
+        let plain = Operation::Clear { name: "foo".into() };
+        let encrypted = Operation::Set {
+            name: "foo".into(),
+            key: b"alice@example.com".to_vec(),
+        };
+        // A log started without encryption keeps its records readable once a key is configured
+        let (mut aof, _) = AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .unwrap();
+        aof.append(&plain).await.unwrap();
+        drop(aof);
+        let (mut aof, replayed) = AppendOnlyLog::open(&path, FsyncPolicy::Never, Some(key.clone()))
+            .await
+            .unwrap();
+        assert_eq!(replayed, vec![Operation::Clear { name: "foo".into() }]);
+        aof.append(&encrypted).await.unwrap();
+        drop(aof);
+        let data = fs::read(&path).await.unwrap();
+        assert!(!data.windows(5).any(|w| w == b"alice"));
+        let (_, replayed) = AppendOnlyLog::open(&path, FsyncPolicy::Never, Some(key))
+            .await
+            .unwrap();
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(replayed[1], encrypted);
+        // A wrong or missing key is an error, the log is left untouched
+        let wrong = Key::from_hex(&"00".repeat(crate::crypto::KEY_SIZE)).unwrap();
+        let err = AppendOnlyLog::open(&path, FsyncPolicy::Never, Some(wrong))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("wrong encryption key"));
+        assert!(AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .is_err());
+        assert_eq!(fs::read(&path).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_retain() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join(AOF_FILENAME);
+        let (mut aof, _) = AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .unwrap();
+        for name in ["foo", "bar", "foo"].iter() {
+            aof.append(&Operation::Clear {
+                name: name.to_string(),
+            })
+            .await
+            .unwrap();
+        }
+        aof.retain(|op| op.name() == "bar").await.unwrap();
+        aof.append(&Operation::Drop { name: "baz".into() })
+            .await
+            .unwrap();
+        drop(aof);
+        let (_, replayed) = AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            replayed,
+            vec![
+                Operation::Clear { name: "bar".into() },
+                Operation::Drop { name: "baz".into() }
+            ]
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use crate::crypto::Key;
+use crate::filter;
+use crate::snapshot::{self, Entry};
+use crate::storage::Compression;
+use crate::AsyncResult;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Deserialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+// Extension of the backup files, each one a snapshot archive holding a single filter
+pub const BACKUP_EXTENSION: &str = "rbk";
+
+// Directory the backups are stored into when not configured, under the data directory
+pub const DEFAULT_BACKUP_DIR: &str = "backups";
+
+// Format of the backup timestamps, used both as file names and by the rollback command
+pub const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Backup schedule, every filter changed since its last backup is copied into `dir` every
+/// `interval` seconds, keeping its `keep` most recent versions.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BackupConfig {
+    #[serde(default)]
+    dir: Option<PathBuf>,
+    #[serde(default = "BackupConfig::default_interval")]
+    interval: u64,
+    #[serde(
+        default = "BackupConfig::default_keep",
+        deserialize_with = "deserialize_keep"
+    )]
+    keep: usize,
+}
+
+// At least one version has to be kept, none would delete every backup as soon as it's written
+fn deserialize_keep<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match usize::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(
+            "invalid backup keep 0, at least 1 version must be kept",
+        )),
+        keep => Ok(keep),
+    }
+}
+
+impl BackupConfig {
+    /// By default backups are taken once an hour
+    pub fn default_interval() -> u64 {
+        3600
+    }
+
+    /// By default a day of hourly backups is kept
+    pub fn default_keep() -> usize {
+        24
+    }
+
+    /// Directory of the backups, `backups` in `data_dir` unless configured
+    pub fn dir(&self, data_dir: &Path) -> PathBuf {
+        self.dir
+            .clone()
+            .unwrap_or_else(|| data_dir.join(DEFAULT_BACKUP_DIR))
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    pub fn keep(&self) -> usize {
+        self.keep
+    }
+}
+
+/// Format a backup time as used in file names and commands
+pub fn format_timestamp(time: &DateTime<Utc>) -> String {
+    time.format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Parse a backup time formatted by `format_timestamp`
+pub fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT)
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+// Backups of a filter are kept in a directory of their own, named after the filter file
+fn filter_dir(dir: &Path, name: &str) -> PathBuf {
+    filter::ScalableBloomFilter::file_path(dir, name).with_extension("")
+}
+
+fn backup_path(dir: &Path, name: &str, time: &DateTime<Utc>) -> PathBuf {
+    filter_dir(dir, name).join(format!("{}.{}", format_timestamp(time), BACKUP_EXTENSION))
+}
+
+/// Write `entry` as the backup taken at `time` into `dir`, encrypted with `key` if given, then
+/// delete the oldest backups of the filter beyond the `keep` most recent ones.
+///
+/// # Errors
+///
+/// Returns `Err` if a backup already exists for `time` or the backup can't be written.
+pub async fn write(
+    dir: &Path,
+    entry: &Entry,
+    time: &DateTime<Utc>,
+    compression: Compression,
+    key: Option<&Key>,
+    keep: usize,
+) -> AsyncResult<PathBuf> {
+    fs::create_dir_all(filter_dir(dir, &entry.name)).await?;
+    let path = backup_path(dir, &entry.name, time);
+    snapshot::write(&path, std::slice::from_ref(entry), compression, key).await?;
+    let versions = list(dir, &entry.name).await?;
+    if versions.len() > keep {
+        for time in versions[..versions.len() - keep].iter() {
+            fs::remove_file(backup_path(dir, &entry.name, time)).await?;
+        }
+    }
+    Ok(path)
+}
+
+/// Times of the backups of the filter named `name` found in `dir`, from the oldest to the most
+/// recent one. Files not named after a timestamp are ignored.
+pub async fn list(dir: &Path, name: &str) -> io::Result<Vec<DateTime<Utc>>> {
+    let mut entries = match fs::read_dir(filter_dir(dir, name)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut versions = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(BACKUP_EXTENSION) {
+            continue;
+        }
+        if let Some(time) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(parse_timestamp)
+        {
+            versions.push(time);
+        }
+    }
+    versions.sort();
+    Ok(versions)
+}
+
+/// Read the backup of the filter named `name` taken at `time` from `dir`, decrypting it with `key`
+/// if encrypted.
+///
+/// # Errors
+///
+/// Returns `Err` if there's no such backup or it can't be read.
+pub async fn read(
+    dir: &Path,
+    name: &str,
+    time: &DateTime<Utc>,
+    key: Option<&Key>,
+) -> AsyncResult<Entry> {
+    let path = backup_path(dir, name, time);
+    if fs::metadata(&path).await.is_err() {
+        let timestamp = format_timestamp(time);
+        return Err(format!("no backup of filter {} taken at {}", name, timestamp).into());
+    }
+    let mut entries = snapshot::read(&path, key).await?;
+    match entries.pop() {
+        Some(entry) if entries.is_empty() && entry.name == name => Ok(entry),
+        _ => Err(format!("{}: not a backup of filter {}", path.display(), name).into()),
+    }
+}
+
+#[cfg(test)]
+mod backup_tests {
+    use super::*;
+    use crate::filter::FilterFiles;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn test_write_rotate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let entry = |data: &[u8]| Entry {
+            name: "foo:bar".into(),
+            location: None,
+            files: FilterFiles {
+                data: data.to_vec(),
+                bitmaps: Vec::new(),
+            },
+        };
+        let start = parse_timestamp("20261018T120000Z").unwrap();
+        let times: Vec<DateTime<Utc>> = (0..4).map(|i| start + Duration::hours(i)).collect();
+        for (i, time) in times.iter().enumerate() {
+            write(dir, &entry(&[i as u8]), time, Compression::None, None, 3)
+                .await
+                .unwrap();
+        }
+        // Only the 3 most recent backups are kept
+        assert_eq!(list(dir, "foo:bar").await.unwrap(), times[1..]);
+        assert_eq!(
+            read(dir, "foo:bar", &times[2], None).await.unwrap(),
+            entry(&[2])
+        );
+        assert!(read(dir, "foo:bar", &times[0], None).await.is_err());
+        assert!(list(dir, "baz").await.unwrap().is_empty());
+        assert_eq!(format_timestamp(&times[1]), "20261018T130000Z");
+    }
+
+    #[test]
+    fn test_config() {
+        let config: BackupConfig = serde_yaml::from_str("interval: 60").unwrap();
+        assert_eq!(config.keep(), BackupConfig::default_keep());
+        let config: BackupConfig = serde_yaml::from_str("keep: 1").unwrap();
+        assert_eq!(config.keep(), 1);
+        let err = serde_yaml::from_str::<BackupConfig>("keep: 0").unwrap_err();
+        assert!(err.to_string().contains("at least 1 version"));
+    }
+}
@@ -0,0 +1,438 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// First byte sent by clients of the binary protocol on the port of the text protocol, which
+/// can't start a text command. The server acknowledges it by sending it back.
+pub const BINARY_HANDSHAKE: u8 = 0xb1;
+
+// Longest frame accepted from a client, length prefix excluded
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+// Size of the length prefix of the frames
+const LENGTH_LEN: usize = 4;
+// Size of the request id and opcode, or status, following the length prefix
+const HEADER_LEN: usize = 5;
+// Most bytes reserved ahead for a frame not fully received, the buffer grows as the rest of it
+// arrives
+const MAX_RESERVE: usize = 1024 * 1024;
+
+// Opcodes of requests
+const OP_TEXT: u8 = 0x00;
+const OP_CREATE: u8 = 0x01;
+const OP_SET: u8 = 0x02;
+const OP_CHECK: u8 = 0x03;
+const OP_SET_BATCH: u8 = 0x04;
+const OP_CHECK_BATCH: u8 = 0x05;
+const OP_DROP: u8 = 0x06;
+const OP_CLEAR: u8 = 0x07;
+
+// Statuses of replies
+const STATUS_DONE: u8 = 0x00;
+const STATUS_PRESENT: u8 = 0x01;
+const STATUS_BATCH: u8 = 0x02;
+const STATUS_TEXT: u8 = 0x03;
+const STATUS_ERROR: u8 = 0xff;
+
+/// Operation requested by a client of the binary protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    /// Any command of the text protocol, answered with its text reply
+    Text(String),
+    /// A capacity or false-positive probability of 0 stands for the default one
+    Create {
+        name: String,
+        capacity: u64,
+        fpp: f64,
+        mmap: bool,
+        dir: Option<String>,
+    },
+    Set {
+        name: String,
+        key: Vec<u8>,
+    },
+    Check {
+        name: String,
+        key: Vec<u8>,
+    },
+    SetBatch {
+        name: String,
+        keys: Vec<Vec<u8>>,
+    },
+    CheckBatch {
+        name: String,
+        keys: Vec<Vec<u8>>,
+    },
+    Drop {
+        name: String,
+        keep_data: bool,
+    },
+    Clear {
+        name: String,
+    },
+}
+
+/// Request of a binary client. Its operation is an error when the frame is well formed but its
+/// content isn't, the error being sent back with the id of the request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinaryRequest {
+    pub id: u32,
+    pub operation: Result<Operation, String>,
+}
+
+/// Reply to a request of a binary client
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Done,
+    Present(bool),
+    Batch(Vec<bool>),
+    Text(String),
+    Error(String),
+}
+
+/// Reply sent with the id of the request it answers
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinaryResponse {
+    pub id: u32,
+    pub reply: Reply,
+}
+
+/// Error reading frames from a binary client, the connection can't be used anymore
+#[derive(Debug)]
+pub enum BinaryError {
+    Io(io::Error),
+    Protocol(String),
+}
+
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BinaryError::Io(e) => write!(f, "{}", e),
+            BinaryError::Protocol(message) => write!(f, "Protocol error: {}", message),
+        }
+    }
+}
+
+impl Error for BinaryError {}
+
+impl From<io::Error> for BinaryError {
+    fn from(e: io::Error) -> Self {
+        BinaryError::Io(e)
+    }
+}
+
+/// Codec of the binary protocol. Every frame starts with its length as a big-endian `u32`,
+/// followed by the id of the request as a `u32`, and its opcode, or the status of the reply, as
+/// a byte. Names are prefixed with their length as a byte, keys and strings with theirs as a
+/// `u32`, integers and floats are big-endian.
+#[derive(Debug, Default)]
+pub struct BinaryCodec;
+
+impl BinaryCodec {
+    pub fn new() -> Self {
+        BinaryCodec
+    }
+}
+
+// Cursor over the body of a request, failing on truncated fields
+struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.buf.len() < len {
+            return Err("truncated request".into());
+        }
+        let (field, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(field)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(self.take(4)?.get_u32())
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(self.take(8)?.get_u64())
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        Ok(self.take(8)?.get_f64())
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?).map_err(|_| "strings must be UTF-8".into())
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.u8()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| "names must be UTF-8".into())
+    }
+
+    fn keys(&mut self) -> Result<Vec<Vec<u8>>, String> {
+        let count = self.u32()? as usize;
+        // Each key takes at least its length, the count can't be trusted for the allocation
+        let mut keys = Vec::with_capacity(count.min(self.buf.len() / 4));
+        for _ in 0..count {
+            keys.push(self.bytes()?);
+        }
+        Ok(keys)
+    }
+
+    fn end(self) -> Result<(), String> {
+        if self.buf.is_empty() {
+            Ok(())
+        } else {
+            Err("trailing bytes in request".into())
+        }
+    }
+}
+
+fn parse_operation(opcode: u8, body: &[u8]) -> Result<Operation, String> {
+    let mut fields = Fields { buf: body };
+    let operation = match opcode {
+        OP_TEXT => {
+            let line = std::str::from_utf8(fields.take(body.len())?)
+                .map_err(|_| "text commands must be UTF-8".to_string())?;
+            Operation::Text(line.to_string())
+        }
+        OP_CREATE => Operation::Create {
+            name: fields.name()?,
+            capacity: fields.u64()?,
+            fpp: fields.f64()?,
+            mmap: fields.u8()? != 0,
+            dir: Some(fields.string()?).filter(|dir| !dir.is_empty()),
+        },
+        OP_SET => Operation::Set {
+            name: fields.name()?,
+            key: fields.bytes()?,
+        },
+        OP_CHECK => Operation::Check {
+            name: fields.name()?,
+            key: fields.bytes()?,
+        },
+        OP_SET_BATCH => Operation::SetBatch {
+            name: fields.name()?,
+            keys: fields.keys()?,
+        },
+        OP_CHECK_BATCH => Operation::CheckBatch {
+            name: fields.name()?,
+            keys: fields.keys()?,
+        },
+        OP_DROP => Operation::Drop {
+            name: fields.name()?,
+            keep_data: fields.u8()? != 0,
+        },
+        OP_CLEAR => Operation::Clear {
+            name: fields.name()?,
+        },
+        _ => return Err(format!("unknown opcode {:#04x}", opcode)),
+    };
+    fields.end()?;
+    Ok(operation)
+}
+
+impl Decoder for BinaryCodec {
+    type Item = BinaryRequest;
+    type Error = BinaryError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BinaryRequest>, BinaryError> {
+        if src.len() < LENGTH_LEN {
+            return Ok(None);
+        }
+        let len = (&src[..LENGTH_LEN]).get_u32() as usize;
+        if !(HEADER_LEN..=MAX_FRAME_LEN).contains(&len) {
+            return Err(BinaryError::Protocol(format!(
+                "invalid frame length {}",
+                len
+            )));
+        }
+        if src.len() < LENGTH_LEN + len {
+            src.reserve((LENGTH_LEN + len - src.len()).min(MAX_RESERVE));
+            return Ok(None);
+        }
+        src.advance(LENGTH_LEN);
+        let frame = src.split_to(len);
+        let id = (&frame[..4]).get_u32();
+        let operation = parse_operation(frame[4], &frame[HEADER_LEN..]);
+        Ok(Some(BinaryRequest { id, operation }))
+    }
+}
+
+impl Encoder<BinaryResponse> for BinaryCodec {
+    type Error = BinaryError;
+
+    fn encode(&mut self, response: BinaryResponse, dst: &mut BytesMut) -> Result<(), BinaryError> {
+        let start = dst.len();
+        // The length is written once the frame is
+        dst.put_u32(0);
+        dst.put_u32(response.id);
+        match response.reply {
+            Reply::Done => dst.put_u8(STATUS_DONE),
+            Reply::Present(present) => {
+                dst.put_u8(STATUS_PRESENT);
+                dst.put_u8(present as u8);
+            }
+            Reply::Batch(present) => {
+                dst.put_u8(STATUS_BATCH);
+                dst.put_u32(present.len() as u32);
+                dst.extend(present.into_iter().map(|p| p as u8));
+            }
+            Reply::Text(text) => {
+                dst.put_u8(STATUS_TEXT);
+                dst.put_slice(text.as_bytes());
+            }
+            Reply::Error(message) => {
+                dst.put_u8(STATUS_ERROR);
+                dst.put_slice(message.as_bytes());
+            }
+        }
+        let len = (dst.len() - start - LENGTH_LEN) as u32;
+        dst[start..start + LENGTH_LEN].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod binary_tests {
+    use super::*;
+
+    fn frame(id: u32, opcode: u8, body: &[u8]) -> Vec<u8> {
+        let mut frame = ((body.len() + HEADER_LEN) as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.push(opcode);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn decode(data: &[u8]) -> Result<Option<BinaryRequest>, BinaryError> {
+        BinaryCodec::new().decode(&mut BytesMut::from(data))
+    }
+
+    fn encode(id: u32, reply: Reply) -> Vec<u8> {
+        let mut dst = BytesMut::new();
+        BinaryCodec::new()
+            .encode(BinaryResponse { id, reply }, &mut dst)
+            .unwrap();
+        dst.to_vec()
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut codec = BinaryCodec::new();
+        let mut src = BytesMut::from(&frame(7, OP_SET, b"\x03foo\x00\x00\x00\x02a\xff")[..]);
+        let batch = frame(
+            8,
+            OP_CHECK_BATCH,
+            b"\x03foo\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00\x00\x01b",
+        );
+        src.extend_from_slice(&batch[..10]);
+        let request = codec.decode(&mut src).unwrap().unwrap();
+        let set = Operation::Set {
+            name: "foo".into(),
+            key: b"a\xff".to_vec(),
+        };
+        assert_eq!(
+            request,
+            BinaryRequest {
+                id: 7,
+                operation: Ok(set)
+            }
+        );
+        // Incomplete frames are left in the buffer until the rest is received
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&batch[10..]);
+        let request = codec.decode(&mut src).unwrap().unwrap();
+        let check = Operation::CheckBatch {
+            name: "foo".into(),
+            keys: vec![vec![], b"b".to_vec()],
+        };
+        assert_eq!(
+            request,
+            BinaryRequest {
+                id: 8,
+                operation: Ok(check)
+            }
+        );
+        assert!(src.is_empty());
+        // Announcing a large frame doesn't allocate it all upfront
+        let mut src = BytesMut::from(&(MAX_FRAME_LEN as u32).to_be_bytes()[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(src.capacity() <= 2 * MAX_RESERVE);
+        let mut create = b"\x03foo".to_vec();
+        create.extend_from_slice(&1000u64.to_be_bytes());
+        create.extend_from_slice(&0.01f64.to_be_bytes());
+        create.extend_from_slice(b"\x01\x00\x00\x00\x00");
+        let request = decode(&frame(9, OP_CREATE, &create)).unwrap().unwrap();
+        let create = Operation::Create {
+            name: "foo".into(),
+            capacity: 1000,
+            fpp: 0.01,
+            mmap: true,
+            dir: None,
+        };
+        assert_eq!(request.operation, Ok(create));
+        let request = decode(&frame(10, OP_TEXT, b"info foo")).unwrap().unwrap();
+        assert_eq!(request.operation, Ok(Operation::Text("info foo".into())));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let operation = |opcode: u8, body: &[u8]| {
+            decode(&frame(1, opcode, body))
+                .unwrap()
+                .unwrap()
+                .operation
+                .unwrap_err()
+        };
+        assert_eq!(operation(0x42, b""), "unknown opcode 0x42");
+        assert_eq!(
+            operation(OP_SET, b"\x03foo\x00\x00\x00\x02a"),
+            "truncated request"
+        );
+        assert_eq!(
+            operation(OP_CLEAR, b"\x03foox"),
+            "trailing bytes in request"
+        );
+        assert_eq!(operation(OP_CLEAR, b"\x01\xff"), "names must be UTF-8");
+        assert_eq!(
+            operation(OP_SET_BATCH, b"\x03foo\xff\xff\xff\xff"),
+            "truncated request"
+        );
+        // Frames that can't be delimited end the connection
+        let err = |data: &[u8]| decode(data).unwrap_err().to_string();
+        assert!(err(b"\x00\x00\x00\x04\x00\x00\x00\x01").contains("invalid frame length 4"));
+        assert!(err(b"\x7f\x00\x00\x00").contains("invalid frame length"));
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            encode(1, Reply::Done),
+            b"\x00\x00\x00\x05\x00\x00\x00\x01\x00"
+        );
+        assert_eq!(
+            encode(2, Reply::Present(true)),
+            b"\x00\x00\x00\x06\x00\x00\x00\x02\x01\x01"
+        );
+        assert_eq!(
+            encode(3, Reply::Batch(vec![true, false])),
+            b"\x00\x00\x00\x0b\x00\x00\x00\x03\x02\x00\x00\x00\x02\x01\x00"
+        );
+        assert_eq!(
+            encode(4, Reply::Error("bad".into())),
+            b"\x00\x00\x00\x08\x00\x00\x00\x04\xffbad"
+        );
+    }
+}
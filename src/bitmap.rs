@@ -0,0 +1,226 @@
+use bitvec::prelude::*;
+use memmap2::MmapMut;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+
+// Extension of the files holding memory-mapped bitmaps
+pub const BITMAP_EXTENSION: &str = "rbm";
+
+/// Where the bits of a filter live:
+/// - `Heap` in memory, serialized together with the filter on every snapshot
+/// - `Mapped` in a memory-mapped file, paged in and out by the operating system, which allows
+///   filters larger than the available memory and makes loading them near-instant
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum BitmapStorage {
+    #[default]
+    #[serde(rename = "heap")]
+    Heap,
+    #[serde(rename = "mmap")]
+    Mapped,
+}
+
+impl fmt::Display for BitmapStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BitmapStorage::Heap => write!(f, "heap"),
+            BitmapStorage::Mapped => write!(f, "mmap"),
+        }
+    }
+}
+
+/// Bitmap backed by a memory-mapped file, bit `i` is stored in byte `i / 8` at position `i % 8`
+/// from the least significant bit.
+pub struct MappedBitmap {
+    mmap: MmapMut,
+}
+
+impl MappedBitmap {
+    /// Create the file at `path`, sized to hold `len` bits all unset. The file is sparse, no
+    /// space is actually allocated until bits are set.
+    pub fn create(path: &Path, len: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(Self::byte_len(len) as u64)?;
+        Self::map(file)
+    }
+
+    /// Map an existing file at `path` holding `len` bits.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the file can't be opened or its size doesn't match `len`.
+    pub fn open(path: &Path, len: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        if file.metadata()?.len() != Self::byte_len(len) as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: bitmap size mismatch", path.display()),
+            ));
+        }
+        Self::map(file)
+    }
+
+    fn map(file: std::fs::File) -> io::Result<Self> {
+        // SAFETY: the file is private to the data directory and only ever accessed through this
+        // mapping, no other process is expected to truncate or write it concurrently.
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        Ok(MappedBitmap { mmap })
+    }
+
+    fn byte_len(len: usize) -> usize {
+        len.div_ceil(8)
+    }
+
+    fn get(&self, index: usize) -> bool {
+        self.mmap[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn set(&mut self, index: usize) {
+        self.mmap[index / 8] |= 1 << (index % 8);
+    }
+
+    fn clear(&mut self) {
+        for byte in self.mmap.iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    /// Write dirty pages back to the file, through `msync`
+    fn flush(&self) -> io::Result<()> {
+        self.mmap.flush()
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.mmap
+    }
+}
+
+/// Bits of a single `BloomFilter`, either on the heap or memory-mapped. A mapped bitmap is
+/// serialized as an empty one, its content being in its own file.
+pub enum Bitmap {
+    Heap(BitVec),
+    Mapped(MappedBitmap),
+}
+
+impl Bitmap {
+    pub fn heap(len: usize) -> Self {
+        Bitmap::Heap(bitvec![0; len])
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        match self {
+            Bitmap::Heap(bits) => bits[index],
+            Bitmap::Mapped(mapped) => mapped.get(index),
+        }
+    }
+
+    pub fn set(&mut self, index: usize) {
+        match self {
+            Bitmap::Heap(bits) => bits.set(index, true),
+            Bitmap::Mapped(mapped) => mapped.set(index),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        match self {
+            Bitmap::Heap(bits) => bits.fill(false),
+            Bitmap::Mapped(mapped) => mapped.clear(),
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        match self {
+            Bitmap::Heap(_) => Ok(()),
+            Bitmap::Mapped(mapped) => mapped.flush(),
+        }
+    }
+
+    /// Heap bitmap of `len` bits packed in `bytes` like in the file of a mapped bitmap, missing
+    /// bytes are taken as unset bits
+    pub fn from_bytes(len: usize, bytes: &[u8]) -> Self {
+        let mut bits = bitvec![0; len];
+        for (i, byte) in bytes.iter().enumerate().filter(|(_, b)| **b != 0) {
+            for bit in (0..8).filter(|bit| byte & (1 << bit) != 0) {
+                if i * 8 + bit < len {
+                    bits.set(i * 8 + bit, true);
+                }
+            }
+        }
+        Bitmap::Heap(bits)
+    }
+
+    /// Bits packed like in the file of a mapped bitmap, see `MappedBitmap`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Bitmap::Heap(bits) => {
+                let mut bytes = vec![0; bits.len().div_ceil(8)];
+                for i in bits.iter_ones() {
+                    bytes[i / 8] |= 1 << (i % 8);
+                }
+                bytes
+            }
+            Bitmap::Mapped(mapped) => mapped.as_bytes().to_vec(),
+        }
+    }
+
+    /// Content of the file of a mapped bitmap, `None` for a heap one
+    pub fn mapped_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Bitmap::Heap(_) => None,
+            Bitmap::Mapped(mapped) => Some(mapped.as_bytes()),
+        }
+    }
+}
+
+impl Serialize for Bitmap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Bitmap::Heap(bits) => bits.serialize(serializer),
+            Bitmap::Mapped(_) => BitVec::<usize, Lsb0>::new().serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Bitmap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        BitVec::deserialize(deserializer).map(Bitmap::Heap)
+    }
+}
+
+#[cfg(test)]
+mod bitmap_tests {
+    use super::*;
+
+    #[test]
+    fn test_mapped_bitmap() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("test.rbm");
+        let mut bitmap = Bitmap::Mapped(MappedBitmap::create(&path, 77).unwrap());
+        bitmap.set(3);
+        bitmap.set(76);
+        bitmap.flush().unwrap();
+        drop(bitmap);
+        let mut bitmap = Bitmap::Mapped(MappedBitmap::open(&path, 77).unwrap());
+        assert!(bitmap.get(3));
+        assert!(bitmap.get(76));
+        assert!(!bitmap.get(4));
+        bitmap.clear();
+        assert!(!bitmap.get(3));
+        bitmap.set(9);
+        let bytes = bitmap.to_bytes();
+        assert_eq!(bytes.len(), 10);
+        assert_eq!(bytes[1], 0b10);
+        let heap = Bitmap::from_bytes(77, &bytes);
+        assert_eq!(heap.to_bytes(), bytes);
+        assert!(heap.get(9) && !heap.get(8));
+        assert!(MappedBitmap::open(&path, 1024).is_err());
+    }
+}
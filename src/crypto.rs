@@ -0,0 +1,105 @@
+use crate::AsyncResult;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use std::fmt;
+use std::path::Path;
+use tokio::fs;
+
+// Size in bytes of an encryption key
+pub const KEY_SIZE: usize = 32;
+// Size in bytes of the random nonce prepended to every encrypted payload
+const NONCE_SIZE: usize = 12;
+
+/// Key encrypting the data written to disk with ChaCha20-Poly1305. The encryption is
+/// authenticated, decrypting with a wrong key or tampered data fails instead of returning
+/// garbage.
+#[derive(Clone, PartialEq)]
+pub struct Key([u8; KEY_SIZE]);
+
+// Keys are never printed, not even in logs
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
+
+impl Key {
+    /// Parse a key written as 64 hexadecimal digits, surrounding whitespace is ignored
+    pub fn from_hex(hex: &str) -> Result<Key, String> {
+        let hex = hex.trim();
+        if hex.len() != KEY_SIZE * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!(
+                "invalid encryption key, expected {} hexadecimal digits",
+                KEY_SIZE * 2
+            ));
+        }
+        let mut bytes = [0u8; KEY_SIZE];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        }
+        Ok(Key(bytes))
+    }
+
+    /// Read the key stored in the file at `path`, e.g. generated with `openssl rand -hex 32`
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the file can't be read or doesn't hold a valid key.
+    pub async fn from_file(path: &Path) -> AsyncResult<Key> {
+        let content = fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Key::from_hex(&content).map_err(|e| format!("{}: {}", path.display(), e))?)
+    }
+
+    /// Encrypt `plaintext` with a random nonce, returned prepended to the ciphertext
+    pub fn encrypt(&self, plaintext: &[u8]) -> AsyncResult<Vec<u8>> {
+        let cipher = ChaCha20Poly1305::new(&self.0.into());
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| "encryption failed")?;
+        let mut data = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    /// Decrypt data returned by `encrypt`, `None` if the key is wrong or the data was altered
+    pub fn decrypt(&self, data: &[u8]) -> Option<Vec<u8>> {
+        if data.len() < NONCE_SIZE {
+            return None;
+        }
+        let cipher = ChaCha20Poly1305::new(&self.0.into());
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+    }
+}
+
+#[cfg(test)]
+mod crypto_tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = Key::from_hex(&"2a".repeat(KEY_SIZE)).unwrap();
+        let data = key.encrypt(b"payload").unwrap();
+        assert_eq!(key.decrypt(&data).unwrap(), b"payload");
+        // Nonces are random, the same plaintext never encrypts the same way twice
+        assert_ne!(key.encrypt(b"payload").unwrap(), data);
+        let wrong = Key([0; KEY_SIZE]);
+        assert!(wrong.decrypt(&data).is_none());
+        let mut altered = data.clone();
+        altered[NONCE_SIZE] ^= 0xff;
+        assert!(key.decrypt(&altered).is_none());
+        assert!(key.decrypt(&data[..4]).is_none());
+    }
+
+    #[test]
+    fn test_from_hex() {
+        let key = Key::from_hex(&format!("{}\n", "0F".repeat(KEY_SIZE))).unwrap();
+        assert_eq!(key, Key([15; KEY_SIZE]));
+        assert!(Key::from_hex("0f0f").is_err());
+        assert!(Key::from_hex(&"zz".repeat(KEY_SIZE)).is_err());
+    }
+}
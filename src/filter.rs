@@ -1,24 +1,116 @@
+use crate::bitmap::{Bitmap, BitmapStorage, MappedBitmap, BITMAP_EXTENSION};
+use crate::crypto::Key;
+use crate::storage::{self, Compression, HashAlgorithm, FLAG_MAPPED, FORMAT_VERSION};
 use crate::AsyncResult;
-use bitvec::prelude::*;
+use crate::{guava, pybloom, redisbloom};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::f64;
 use std::fmt;
+use std::path::{Path, PathBuf};
 use std::result::Result;
 use tokio::fs;
 
 // Data directory used to store filters on disk
 pub const DEFAULT_DATA_DIR: &str = "rublo";
+// Extension of the files storing filters
+pub const FILTER_EXTENSION: &str = "rbl";
+// Maximum length of a filter name
+pub const MAX_NAME_LENGTH: usize = 128;
+
+/// Check that `name` is a valid filter name: 1 to `MAX_NAME_LENGTH` ASCII letters, digits, `_`,
+/// `-`, `.` or `:`, not starting with `.`. Names never contain a path separator and can't refer to
+/// a parent or hidden file.
+///
+/// # Errors
+///
+/// Returns a message describing the grammar if the name doesn't match it.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"_-.:".contains(&b));
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid filter name, expected 1 to {} letters, digits, '_', '-', '.' or ':', not \
+             starting with '.'",
+            MAX_NAME_LENGTH
+        ))
+    }
+}
+
+/// Check the initial capacity and false positive probability of a new filter: the capacity must be
+/// positive and the probability between 0 and 1, both excluded.
+///
+/// # Errors
+///
+/// Returns a message naming the invalid parameter.
+pub fn validate_parameters(capacity: usize, fpp: f64) -> Result<(), String> {
+    if capacity == 0 {
+        return Err("capacity must be greater than 0".into());
+    }
+    if !(fpp > 0. && fpp < 1.) {
+        return Err(format!(
+            "false-positive probability must be between 0 and 1, got {}",
+            fpp
+        ));
+    }
+    Ok(())
+}
+
+/// Map a filter name to a file name. Names are validated beforehand, still every byte other than
+/// ASCII letters, digits, `_`, `-` and non-leading `.` is percent-encoded, so that no name can
+/// escape the data directory or clash with reserved file names whatever the platform.
+fn encode_name(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for (i, b) in name.bytes().enumerate() {
+        if b.is_ascii_alphanumeric() || b == b'_' || b == b'-' || (b == b'.' && i > 0) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// Map a file name back to the filter name it encodes, `None` if it isn't the encoding of a valid
+/// one
+pub fn decode_name(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut chars = encoded.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'%' {
+            let hex = [chars.next()?, chars.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    let name = String::from_utf8(bytes).ok()?;
+    // Only the canonical encoding is accepted, so that the name maps back to the same file
+    if validate_name(&name).is_err() || encode_name(&name) != encoded {
+        return None;
+    }
+    Some(name)
+}
 
 #[derive(Serialize, Deserialize)]
 struct BloomFilter {
     capacity: usize,
     size: usize,
-    bitmap: BitVec,
+    bitmap: Bitmap,
     hash_count: u32,
     hits: u64,
     miss: u64,
+    /// Recorded in the header of the file of the scalable filter, shared by its layers
+    #[serde(skip)]
+    hash_algorithm: HashAlgorithm,
 }
 
 #[derive(Debug)]
@@ -32,6 +124,19 @@ impl fmt::Display for BloomFilterError {
 
 impl Error for BloomFilterError {}
 
+/// Error reading a filter file whose content is damaged, as opposed to a missing key or an I/O
+/// error: reading it again won't succeed, the file is better moved out of the way
+#[derive(Debug)]
+pub struct CorruptFileError(String);
+
+impl fmt::Display for CorruptFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for CorruptFileError {}
+
 impl BloomFilter {
     //! Create a new BloomFilter, a probabilistic space-efficient data structure which is
     //! used to test if an element is a member of a set, trading precision for efficiency
@@ -45,20 +150,83 @@ impl BloomFilter {
     //! # Panics
     //!
     //! The `new` function will panic if the size is zero or fpp is zero.
-    pub fn new(capacity: usize, fpp: f64) -> BloomFilter {
+    pub fn new(capacity: usize, fpp: f64, hash_algorithm: HashAlgorithm) -> BloomFilter {
+        let mut filter = Self::new_unallocated(capacity, fpp, hash_algorithm);
+        filter.bitmap = Bitmap::heap(filter.capacity);
+        filter
+    }
+
+    /// Create a new BloomFilter like `new`, keeping its bitmap in a memory-mapped file created
+    /// at `path`.
+    pub fn new_mapped(
+        capacity: usize,
+        fpp: f64,
+        hash_algorithm: HashAlgorithm,
+        path: &Path,
+    ) -> std::io::Result<BloomFilter> {
+        let mut filter = Self::new_unallocated(capacity, fpp, hash_algorithm);
+        filter.bitmap = Bitmap::Mapped(MappedBitmap::create(path, filter.capacity)?);
+        Ok(filter)
+    }
+
+    // Same as `new` but with an empty heap bitmap, to be replaced by a mapped one
+    fn new_unallocated(capacity: usize, fpp: f64, hash_algorithm: HashAlgorithm) -> BloomFilter {
         assert!(capacity > 0 && fpp > 0.);
-        let bitmap_size = Self::get_bitmap_size(capacity, fpp);
-        let hash_count = Self::get_optimal_hash_count(bitmap_size, capacity);
+        let (bitmap_size, hash_count) = match hash_algorithm {
+            HashAlgorithm::Gxhash32 => {
+                let bitmap_size = Self::get_bitmap_size(capacity, fpp);
+                (
+                    bitmap_size,
+                    Self::get_optimal_hash_count(bitmap_size, capacity),
+                )
+            }
+            // Sized the way RedisBloom sizes its own layers, so that they can be exported as is
+            HashAlgorithm::Murmur64A => redisbloom::layer_size(capacity, fpp),
+            HashAlgorithm::Murmur128Mitz32 | HashAlgorithm::Murmur128Mitz64 => {
+                guava::layer_size(capacity, fpp)
+            }
+            HashAlgorithm::Pybloom => pybloom::layer_size(capacity, fpp),
+        };
         BloomFilter {
             capacity: bitmap_size,
             size: 0,
-            bitmap: bitvec![0u8; bitmap_size],
+            bitmap: Bitmap::heap(0),
             hash_count,
             hits: 0,
             miss: 0,
+            hash_algorithm,
         }
     }
 
+    /// Create a BloomFilter out of the bits of `layer`, kept on the heap
+    fn from_layer(layer: &Layer, hash_algorithm: HashAlgorithm) -> BloomFilter {
+        BloomFilter {
+            capacity: layer.bits,
+            size: layer.size,
+            bitmap: Bitmap::from_bytes(layer.bits, &layer.bitmap),
+            hash_count: layer.hash_count,
+            hits: 0,
+            miss: 0,
+            hash_algorithm,
+        }
+    }
+
+    /// Replace the bitmap with the memory-mapped file at `path`, as written by a filter created
+    /// with `new_mapped`.
+    pub fn map_bitmap(&mut self, path: &Path) -> std::io::Result<()> {
+        self.bitmap = Bitmap::Mapped(MappedBitmap::open(path, self.capacity)?);
+        Ok(())
+    }
+
+    /// Write the bitmap back to its file, a no-op for filters kept on the heap
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.bitmap.flush()
+    }
+
+    pub fn mapped_bytes(&self) -> Option<&[u8]> {
+        self.bitmap.mapped_bytes()
+    }
+
     pub fn capacity(&self) -> usize {
         self.capacity
     }
@@ -93,12 +261,11 @@ impl BloomFilter {
         if self.size() == self.capacity() {
             return Err(Box::new(BloomFilterError("Full capacity reached".into())));
         }
-        for i in 0..self.hash_count {
-            let hash = (gxhash::gxhash32(bytes, i as i64) as usize) % self.capacity;
-            if allbits && self.bitmap[hash] {
+        for hash in self.positions(bytes) {
+            if allbits && self.bitmap.get(hash) {
                 allbits = false;
             }
-            self.bitmap.set(hash, true);
+            self.bitmap.set(hash);
         }
         if allbits {
             self.size += 1
@@ -107,9 +274,8 @@ impl BloomFilter {
     }
 
     pub fn check(&mut self, bytes: &[u8]) -> bool {
-        for i in 0..self.hash_count {
-            let hash = (gxhash::gxhash32(bytes, i as i64) as usize) % self.capacity;
-            if self.bitmap[hash] == false {
+        for hash in self.positions(bytes) {
+            if !self.bitmap.get(hash) {
                 self.miss += 1;
                 return false;
             }
@@ -123,6 +289,27 @@ impl BloomFilter {
         self.size = 0;
     }
 
+    // Positions in the bitmap of the bits of `bytes`, one per hash function. Native filters
+    // compute them lazily, a check usually stopping at the first unset bit.
+    fn positions<'a>(&self, bytes: &'a [u8]) -> impl Iterator<Item = usize> + 'a {
+        let (capacity, hash_count) = (self.capacity, self.hash_count);
+        let imported = match self.hash_algorithm {
+            HashAlgorithm::Gxhash32 => None,
+            HashAlgorithm::Murmur64A => Some(redisbloom::positions(bytes, capacity, hash_count)),
+            HashAlgorithm::Murmur128Mitz32 => {
+                Some(guava::positions_mitz32(bytes, capacity, hash_count))
+            }
+            HashAlgorithm::Murmur128Mitz64 => {
+                Some(guava::positions_mitz64(bytes, capacity, hash_count))
+            }
+            HashAlgorithm::Pybloom => Some(pybloom::positions(bytes, capacity, hash_count)),
+        };
+        let native = if imported.is_none() { hash_count } else { 0 };
+        (0..native)
+            .map(move |i| (gxhash::gxhash32(bytes, i as i64) as usize) % capacity)
+            .chain(imported.into_iter().flatten())
+    }
+
     #[allow(dead_code)]
     pub async fn to_file(&self, filename: &str) -> AsyncResult<()> {
         let serialized = bincode::serialize(self)?;
@@ -149,22 +336,26 @@ mod filter_tests {
 
     #[test]
     fn test_new() {
-        let bf = BloomFilter::new(5, 0.01);
+        let bf = BloomFilter::new(5, 0.01, HashAlgorithm::Gxhash32);
         assert_eq!(bf.capacity(), 48);
         assert_eq!(bf.hash_count(), 7);
-        let bf = BloomFilter::new(1500, 0.001);
+        let bf = BloomFilter::new(1500, 0.001, HashAlgorithm::Gxhash32);
         assert_eq!(bf.capacity(), 21567);
         assert_eq!(bf.hash_count(), 10);
-        let bf = BloomFilter::new(400, 0.05);
+        let bf = BloomFilter::new(400, 0.05, HashAlgorithm::Gxhash32);
         assert_eq!(bf.capacity(), 2495);
         assert_eq!(bf.hash_count(), 5);
-        let bf = BloomFilter::new(192, 0.05);
-        assert_eq!(bf.byte_space(), 149)
+        let bf = BloomFilter::new(192, 0.05, HashAlgorithm::Gxhash32);
+        assert_eq!(bf.byte_space(), 149);
+        // RedisBloom compatible layers are a whole number of 64 bits words
+        let bf = BloomFilter::new(1500, 0.001, HashAlgorithm::Murmur64A);
+        assert_eq!(bf.capacity(), 21568);
+        assert_eq!(bf.hash_count(), 10)
     }
 
     #[test]
     fn test_check() {
-        let mut bf = BloomFilter::new(5, 0.01);
+        let mut bf = BloomFilter::new(5, 0.01, HashAlgorithm::Gxhash32);
         for word in ["Vega", "Pandora", "Magnetar", "Pulsar", "Nebula"].iter() {
             bf.set(word.as_bytes()).unwrap();
         }
@@ -212,6 +403,32 @@ pub struct ScalableBloomFilter {
     scale_factor: ScaleFactor,
     creation_time: DateTime<Utc>,
     last_access_time: DateTime<Utc>,
+    /// Number of writes since the filter was last persisted, a fresh filter counts as one
+    #[serde(skip)]
+    changes: u64,
+    /// Time of the last successful write to disk, or of the load from disk
+    #[serde(skip, default = "Utc::now")]
+    last_save_time: DateTime<Utc>,
+    /// Where the bitmaps are kept, recorded in the header of the file
+    #[serde(skip)]
+    storage: BitmapStorage,
+    /// File the filter is persisted to, mapped bitmaps are stored next to it
+    #[serde(skip)]
+    path: PathBuf,
+    /// How keys are hashed, recorded in the header of the file
+    #[serde(skip)]
+    hash_algorithm: HashAlgorithm,
+}
+
+/// Bits of one layer of a scalable filter along with what's needed to check them, as exchanged
+/// with other bloom filter implementations. Bit `i` of the layer is stored in byte `i / 8` of
+/// `bitmap`, at position `i % 8` from the least significant bit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub bits: usize,
+    pub hash_count: u32,
+    pub size: usize,
+    pub bitmap: Vec<u8>,
 }
 
 impl ScalableBloomFilter {
@@ -227,6 +444,7 @@ impl ScalableBloomFilter {
     ///! let present = sbf.check(b"112.77.96.196"); // false
     ///! let present = sbf.check(b"112.78.96.196"); // true
     pub fn new(name: String, initial_capacity: usize, fpp: f64, scale_factor: ScaleFactor) -> Self {
+        let path = Self::file_path(Path::new(DEFAULT_DATA_DIR), &name);
         Self {
             name,
             initial_capacity,
@@ -235,13 +453,101 @@ impl ScalableBloomFilter {
             scale_factor,
             creation_time: Utc::now(),
             last_access_time: Utc::now(),
+            changes: 1,
+            last_save_time: Utc::now(),
+            storage: BitmapStorage::Heap,
+            path,
+            hash_algorithm: HashAlgorithm::Gxhash32,
         }
     }
 
+    /// Create a scalable filter out of the `layers` of a filter hashing keys with
+    /// `hash_algorithm`, kept on the heap. Layers added from then on hash keys the same way.
+    pub fn from_layers(
+        name: String,
+        initial_capacity: usize,
+        fpp: f64,
+        scale_factor: ScaleFactor,
+        hash_algorithm: HashAlgorithm,
+        layers: &[Layer],
+    ) -> Self {
+        let mut sbf = Self::new(name, initial_capacity, fpp, scale_factor);
+        sbf.hash_algorithm = hash_algorithm;
+        sbf.filters = layers
+            .iter()
+            .map(|layer| BloomFilter::from_layer(layer, hash_algorithm))
+            .collect();
+        sbf
+    }
+
+    /// Layers of the filter, from the oldest one
+    pub fn layers(&self) -> Vec<Layer> {
+        self.filters
+            .iter()
+            .map(|f| Layer {
+                bits: f.capacity(),
+                hash_count: f.hash_count(),
+                size: f.size(),
+                bitmap: f.bitmap.to_bytes(),
+            })
+            .collect()
+    }
+
+    /// Set where the bitmaps of the filter are kept, see `BitmapStorage`
+    pub fn with_storage(mut self, storage: BitmapStorage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Set the file the filter is persisted to, by default `<data-dir>/<name>.rbl`
+    pub fn with_path(mut self, path: PathBuf) -> Self {
+        self.path = path;
+        self
+    }
+
     pub fn name(&self) -> &String {
         &self.name
     }
 
+    pub fn storage(&self) -> BitmapStorage {
+        self.storage
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
+    }
+
+    pub fn initial_capacity(&self) -> usize {
+        self.initial_capacity
+    }
+
+    pub fn scale_factor(&self) -> ScaleFactor {
+        self.scale_factor
+    }
+
+    /// Files holding the memory-mapped bitmaps of the filter, one per layer
+    pub fn bitmap_paths(&self) -> Vec<PathBuf> {
+        match self.storage {
+            BitmapStorage::Heap => Vec::new(),
+            BitmapStorage::Mapped => (0..self.filters.len())
+                .map(|i| self.bitmap_path(i))
+                .collect(),
+        }
+    }
+
+    fn bitmap_path(&self, layer: usize) -> PathBuf {
+        Self::layer_path(&self.path, layer)
+    }
+
+    /// File holding the memory-mapped bitmap of the layer `layer` of the filter stored at `path`
+    pub fn layer_path(path: &Path, layer: usize) -> PathBuf {
+        path.with_extension(format!("{}.{}", layer, BITMAP_EXTENSION))
+    }
+
     pub fn fpp(&self) -> f64 {
         self.fpp
     }
@@ -284,6 +590,29 @@ impl ScalableBloomFilter {
         self.last_access_time
     }
 
+    /// Mark the filter as just accessed, postponing its eviction from memory
+    pub fn touch(&mut self) {
+        self.last_access_time = Utc::now();
+    }
+
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    pub fn last_save_time(&self) -> DateTime<Utc> {
+        self.last_save_time
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.changes > 0
+    }
+
+    /// Reset the change counter, to be called once the filter has been persisted
+    pub fn mark_saved(&mut self) {
+        self.changes = 0;
+        self.last_save_time = Utc::now();
+    }
+
     pub fn hash_count(&self) -> u32 {
         self.filters
             .iter()
@@ -297,6 +626,7 @@ impl ScalableBloomFilter {
             filter.clear();
         }
         self.last_access_time = Utc::now();
+        self.changes += 1;
     }
 
     ///! Sets a values into the scalable filter. The value must be provided as a `&[u8]`, before the
@@ -317,19 +647,17 @@ impl ScalableBloomFilter {
         }
         if let Some(f) = self.filters.last() {
             if f.size() == f.capacity() {
-                self.add_filter(
-                    self.initial_capacity * self.scale_factor as usize,
-                    self.fpp * FALSE_POSITIVE_PROBABILITY_RATIO,
-                );
+                let (capacity, fpp) = self.next_layer();
+                self.add_filter(capacity, fpp)?;
             }
         } else {
-            self.add_filter(
-                self.initial_capacity * self.scale_factor as usize,
-                self.fpp * FALSE_POSITIVE_PROBABILITY_RATIO,
-            );
+            let (capacity, fpp) = self.next_layer();
+            self.add_filter(capacity, fpp)?;
         }
         let filter = self.filters.last_mut().unwrap();
-        filter.set(bytes)
+        let outcome = filter.set(bytes)?;
+        self.changes += 1;
+        Ok(outcome)
     }
 
     pub fn check(&mut self, bytes: &[u8]) -> bool {
@@ -342,24 +670,190 @@ impl ScalableBloomFilter {
         return false;
     }
 
-    pub async fn to_file(&self) -> AsyncResult<()> {
-        let serialized = bincode::serialize(self)?;
-        fs::write(
-            format!("{}/{}.rbl", DEFAULT_DATA_DIR, &self.name),
-            &serialized,
-        )
-        .await?;
+    /// Path of the file storing the scalable filter named `name` in `dir`
+    pub fn file_path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}.{}", encode_name(name), FILTER_EXTENSION))
+    }
+
+    /// Delete the filter file at `path` along with its memory-mapped bitmaps if any, missing files
+    /// are ignored.
+    pub async fn remove_files(path: &Path) -> std::io::Result<()> {
+        remove_if_exists(path).await?;
+        // Layers are numbered from 0 without gaps
+        for layer in 0.. {
+            if !remove_if_exists(&Self::layer_path(path, layer)).await? {
+                break;
+            }
+        }
         Ok(())
     }
 
-    pub async fn from_file(name: &str) -> AsyncResult<ScalableBloomFilter> {
-        let data = fs::read(name).await?;
-        let filter = bincode::deserialize(&data[..])?;
+    /// Serialize the scalable filter to its file, compressing it with `compression` and
+    /// encrypting it with `key` if given. The write is atomic, either the previous content of the
+    /// file or the new one will be found on disk after a crash, see `storage::write_atomic`.
+    ///
+    /// Memory-mapped bitmaps are flushed to their own files beforehand, only the filter
+    /// metadata is serialized in that case.
+    pub async fn to_file(&self, compression: Compression, key: Option<&Key>) -> AsyncResult<()> {
+        let data = self.encode(compression, key)?;
+        storage::write_atomic(&self.path, &data).await
+    }
+
+    /// Content of the files the filter would be persisted to, read from memory
+    pub fn to_files(
+        &self,
+        compression: Compression,
+        key: Option<&Key>,
+    ) -> AsyncResult<FilterFiles> {
+        Ok(FilterFiles {
+            data: self.encode(compression, key)?,
+            bitmaps: self
+                .filters
+                .iter()
+                .filter_map(|f| f.mapped_bytes().map(|b| b.to_vec()))
+                .collect(),
+        })
+    }
+
+    fn encode(&self, compression: Compression, key: Option<&Key>) -> AsyncResult<Vec<u8>> {
+        let mut flags = 0;
+        if self.storage == BitmapStorage::Mapped {
+            for filter in self.filters.iter() {
+                filter.flush()?;
+            }
+            flags |= FLAG_MAPPED;
+        }
+        let serialized = bincode::serialize(self)?;
+        storage::encode(self.hash_algorithm, compression, flags, &serialized, key)
+    }
+
+    /// Read a scalable filter from the file at `name`, decrypting it with `key` if encrypted and
+    /// decompressing it if needed. The bitmaps of a memory-mapped filter are mapped back from
+    /// their files, without reading them.
+    pub async fn from_file(name: &str, key: Option<&Key>) -> AsyncResult<ScalableBloomFilter> {
+        let (filter, _, _) = Self::read_versioned(name, key).await?;
         Ok(filter)
     }
 
-    fn add_filter(&mut self, capacity: usize, fpp: f64) {
-        self.filters.push(BloomFilter::new(capacity, fpp))
+    /// Upgrade the file at `name` in place to the current on-disk format, returning whether it
+    /// had to be rewritten. The compression of the file is preserved, the new file is encrypted
+    /// with `key` if given. Files already up to date are left unread, encryption came after the
+    /// last format change so outdated files are never encrypted.
+    pub async fn migrate(name: &str, key: Option<&Key>) -> AsyncResult<bool> {
+        if let Some(header) = storage::read_header(Path::new(name)).await? {
+            if header.version == FORMAT_VERSION {
+                return Ok(false);
+            }
+        }
+        let (filter, _, compression) = Self::read_versioned(name, None).await?;
+        filter.to_file(compression, key).await?;
+        Ok(true)
+    }
+
+    async fn read_versioned(
+        name: &str,
+        key: Option<&Key>,
+    ) -> AsyncResult<(ScalableBloomFilter, u16, Compression)> {
+        let data = fs::read(name).await?;
+        let decoded = storage::decode(&data, key).map_err(|e| -> Box<dyn Error + Send + Sync> {
+            let message = format!("{}: {}", name, e);
+            if e.is_corrupt() {
+                Box::new(CorruptFileError(message))
+            } else {
+                message.into()
+            }
+        })?;
+        let mut filter: ScalableBloomFilter = bincode::deserialize(&decoded.payload)
+            .map_err(|e| CorruptFileError(format!("{}: corrupt filter file: {}", name, e)))?;
+        filter.path = PathBuf::from(name);
+        filter.hash_algorithm = decoded.hash_algorithm;
+        for layer in filter.filters.iter_mut() {
+            layer.hash_algorithm = decoded.hash_algorithm;
+        }
+        if decoded.flags & FLAG_MAPPED != 0 {
+            filter.storage = BitmapStorage::Mapped;
+            for (i, path) in filter.bitmap_paths().iter().enumerate() {
+                filter.filters[i]
+                    .map_bitmap(path)
+                    .map_err(|e| format!("{}: {}", name, e))?;
+            }
+        }
+        Ok((filter, decoded.version, decoded.compression))
+    }
+
+    // Capacity and false positive probability of the next layer, the layers of filters imported
+    // from RedisBloom growing the way RedisBloom grows them
+    fn next_layer(&self) -> (usize, f64) {
+        match self.hash_algorithm {
+            HashAlgorithm::Murmur64A => {
+                let count = self.filters.len() as u32;
+                (
+                    self.initial_capacity * (self.scale_factor as usize).pow(count),
+                    self.fpp * redisbloom::ERROR_TIGHTENING_RATIO.powi(count as i32),
+                )
+            }
+            _ => (
+                self.initial_capacity * self.scale_factor as usize,
+                self.fpp * FALSE_POSITIVE_PROBABILITY_RATIO,
+            ),
+        }
+    }
+
+    fn add_filter(&mut self, capacity: usize, fpp: f64) -> std::io::Result<()> {
+        let filter = match self.storage {
+            BitmapStorage::Heap => BloomFilter::new(capacity, fpp, self.hash_algorithm),
+            BitmapStorage::Mapped => BloomFilter::new_mapped(
+                capacity,
+                fpp,
+                self.hash_algorithm,
+                &self.bitmap_path(self.filters.len()),
+            )?,
+        };
+        self.filters.push(filter);
+        Ok(())
+    }
+}
+
+/// Content of the files of a persisted filter, the filter file and the memory-mapped bitmaps of
+/// each of its layers if any
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct FilterFiles {
+    pub data: Vec<u8>,
+    pub bitmaps: Vec<Vec<u8>>,
+}
+
+impl FilterFiles {
+    /// Read the filter file at `path` along with its memory-mapped bitmaps if any
+    pub async fn read(path: &Path) -> std::io::Result<FilterFiles> {
+        let data = fs::read(path).await?;
+        let mut bitmaps = Vec::new();
+        // Layers are numbered from 0 without gaps
+        for layer in 0.. {
+            match fs::read(ScalableBloomFilter::layer_path(path, layer)).await {
+                Ok(bitmap) => bitmaps.push(bitmap),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(FilterFiles { data, bitmaps })
+    }
+
+    /// Write the files to `path` and next to it, the bitmaps first so that the filter file is
+    /// never found without them
+    pub async fn write(&self, path: &Path) -> AsyncResult<()> {
+        for (layer, bitmap) in self.bitmaps.iter().enumerate() {
+            storage::write_atomic(&ScalableBloomFilter::layer_path(path, layer), bitmap).await?;
+        }
+        storage::write_atomic(path, &self.data).await
+    }
+}
+
+/// Remove the file at `path`, returning whether it existed
+async fn remove_if_exists(path: &Path) -> std::io::Result<bool> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
     }
 }
 
@@ -380,6 +874,41 @@ impl fmt::Display for ScalableBloomFilter {
 mod scalable_filter_tests {
     use super::*;
 
+    #[test]
+    fn test_name_encoding() {
+        assert!(validate_name("site-hits:2021_v1.2").is_ok());
+        for name in [
+            "", ".", "..", "../foo", "foo/bar", "foo\\bar", ".foo", "fo o", "f%oo",
+        ]
+        .iter()
+        {
+            assert!(validate_name(name).is_err());
+        }
+        assert_eq!(encode_name("site-hits:2021.v1"), "site-hits%3A2021.v1");
+        assert!(validate_parameters(1, 0.5).is_ok());
+        assert!(validate_parameters(0, 0.01).is_err());
+        for fpp in [0., 1., -0.1, 1.5, f64::NAN].iter() {
+            assert!(validate_parameters(100, *fpp).is_err());
+        }
+        assert_eq!(
+            decode_name("site-hits%3A2021.v1").unwrap(),
+            "site-hits:2021.v1"
+        );
+        // Even unvalidated names stay in the directory
+        let dir = Path::new("data");
+        for name in ["../../etc/foo", "/etc/passwd", "..", "a/../../b"].iter() {
+            let path = ScalableBloomFilter::file_path(dir, name);
+            assert_eq!(path.parent(), Some(dir));
+            assert_eq!(
+                decode_name(path.file_stem().unwrap().to_str().unwrap()),
+                None
+            );
+        }
+        assert_eq!(decode_name("site-hits:2021"), None);
+        assert_eq!(decode_name("foo%2"), None);
+        assert_eq!(decode_name("foo%zz"), None);
+    }
+
     #[test]
     fn test_set() {
         let mut sbf =
@@ -413,4 +942,213 @@ mod scalable_filter_tests {
         }
         assert_eq!(sbf.size(), 2);
     }
+
+    #[test]
+    fn test_changes() {
+        let mut sbf =
+            ScalableBloomFilter::new("test-sbf".into(), 5, 0.01, ScaleFactor::SmallScaleSize);
+        assert!(sbf.is_dirty());
+        sbf.mark_saved();
+        assert!(!sbf.is_dirty());
+        sbf.check(b"Vega");
+        assert!(!sbf.is_dirty());
+        sbf.set(b"Vega").unwrap();
+        sbf.set(b"Vega").unwrap();
+        assert_eq!(sbf.changes(), 1);
+        sbf.clear();
+        assert_eq!(sbf.changes(), 2);
+        sbf.mark_saved();
+        assert_eq!(sbf.changes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("test-sbf.rbl");
+        let mut sbf =
+            ScalableBloomFilter::new("test-sbf".into(), 5, 0.01, ScaleFactor::SmallScaleSize);
+        sbf.path = path.clone();
+        sbf.set(b"Vega").unwrap();
+        sbf.to_file(Compression::None, None).await.unwrap();
+        // A crash in the middle of the next snapshot leaves a truncated temporary file behind
+        sbf.set(b"Pandora").unwrap();
+        let serialized = bincode::serialize(&sbf).unwrap();
+        fs::write(storage::tmp_path(&path), &serialized[..serialized.len() / 2])
+            .await
+            .unwrap();
+        let mut restored = ScalableBloomFilter::from_file(path.to_str().unwrap(), None)
+            .await
+            .unwrap();
+        assert!(restored.check(b"Vega"));
+        assert!(!restored.check(b"Pandora"));
+        // A write failing before the rename must not touch the previous snapshot either
+        fs::remove_file(storage::tmp_path(&path)).await.unwrap();
+        fs::create_dir(storage::tmp_path(&path)).await.unwrap();
+        assert!(sbf.to_file(Compression::None, None).await.is_err());
+        let mut restored = ScalableBloomFilter::from_file(path.to_str().unwrap(), None)
+            .await
+            .unwrap();
+        assert!(restored.check(b"Vega"));
+        assert!(!restored.check(b"Pandora"));
+    }
+
+    #[tokio::test]
+    async fn test_compressed_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("test-sbf.rbl");
+        let mut sbf =
+            ScalableBloomFilter::new("test-sbf".into(), 5000, 0.01, ScaleFactor::SmallScaleSize);
+        sbf.path = path.clone();
+        sbf.set(b"Vega").unwrap();
+        sbf.to_file(Compression::Zstd, None).await.unwrap();
+        let on_disk = fs::metadata(&path).await.unwrap().len() as usize;
+        assert!(on_disk < sbf.byte_space() / 10);
+        let mut restored = ScalableBloomFilter::from_file(path.to_str().unwrap(), None)
+            .await
+            .unwrap();
+        assert!(restored.check(b"Vega"));
+        assert!(!restored.check(b"Pandora"));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("test-sbf.rbl");
+        let name = path.to_str().unwrap();
+        let key = Key::from_hex(&"2a".repeat(crate::crypto::KEY_SIZE)).unwrap();
+        let mut sbf =
+            ScalableBloomFilter::new("test-sbf".into(), 5000, 0.01, ScaleFactor::SmallScaleSize);
+        sbf.path = path.clone();
+        sbf.set(b"alice@example.com").unwrap();
+        sbf.to_file(Compression::Lz4, Some(&key)).await.unwrap();
+        let mut restored = ScalableBloomFilter::from_file(name, Some(&key))
+            .await
+            .unwrap();
+        assert!(restored.check(b"alice@example.com"));
+        let err = ScalableBloomFilter::from_file(name, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("no encryption key"));
+        let wrong = Key::from_hex(&"00".repeat(crate::crypto::KEY_SIZE)).unwrap();
+        let err = ScalableBloomFilter::from_file(name, Some(&wrong))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("wrong encryption key"));
+        // Encrypted files are always in the current format, nothing to migrate
+        assert!(!ScalableBloomFilter::migrate(name, Some(&key))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_mapped_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("test-sbf.rbl");
+        let mut sbf =
+            ScalableBloomFilter::new("test-sbf".into(), 5000, 0.01, ScaleFactor::SmallScaleSize)
+                .with_storage(BitmapStorage::Mapped);
+        sbf.path = path.clone();
+        for word in [
+            "Nexus", "Ilios", "Vega", "Pandora", "Magnetar", "Pulsar", "Nebula",
+        ]
+        .iter()
+        {
+            sbf.set(word.as_bytes()).unwrap();
+        }
+        assert_eq!(sbf.bitmap_paths(), vec![dir.join("test-sbf.0.rbm")]);
+        sbf.to_file(Compression::None, None).await.unwrap();
+        // Only the metadata is serialized, the bits live in the mapped file
+        let metadata = fs::metadata(&path).await.unwrap().len() as usize;
+        assert!(metadata < sbf.byte_space());
+        let mut restored = ScalableBloomFilter::from_file(path.to_str().unwrap(), None)
+            .await
+            .unwrap();
+        assert_eq!(restored.storage(), BitmapStorage::Mapped);
+        assert!(restored.check(b"Vega"));
+        assert!(restored.check(b"Nebula"));
+        assert!(!restored.check(b"Dwarf"));
+        restored.set(b"Dwarf").unwrap();
+        restored.clear();
+        assert!(!restored.check(b"Vega"));
+        fs::remove_file(dir.join("test-sbf.0.rbm")).await.unwrap();
+        assert!(ScalableBloomFilter::from_file(path.to_str().unwrap(), None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remove_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("test-sbf.rbl");
+        let mut sbf =
+            ScalableBloomFilter::new("test-sbf".into(), 1, 0.01, ScaleFactor::SmallScaleSize)
+                .with_storage(BitmapStorage::Mapped);
+        sbf.path = path.clone();
+        sbf.add_filter(5, 0.01).unwrap();
+        sbf.add_filter(10, 0.01).unwrap();
+        sbf.set(b"Vega").unwrap();
+        sbf.to_file(Compression::None, None).await.unwrap();
+        drop(sbf);
+        ScalableBloomFilter::remove_files(&path).await.unwrap();
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 0);
+        // Nothing left to remove
+        ScalableBloomFilter::remove_files(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("test-sbf.rbl");
+        let name = path.to_str().unwrap();
+        let mut sbf =
+            ScalableBloomFilter::new("test-sbf".into(), 5, 0.01, ScaleFactor::SmallScaleSize);
+        sbf.set(b"Vega").unwrap();
+        // Legacy files are plain bincode dumps of the filter
+        fs::write(&path, bincode::serialize(&sbf).unwrap())
+            .await
+            .unwrap();
+        assert!(ScalableBloomFilter::from_file(name, None)
+            .await
+            .unwrap()
+            .check(b"Vega"));
+        assert!(ScalableBloomFilter::migrate(name, None).await.unwrap());
+        assert!(fs::read(&path).await.unwrap().starts_with(storage::MAGIC));
+        assert!(!ScalableBloomFilter::migrate(name, None).await.unwrap());
+        assert!(ScalableBloomFilter::from_file(name, None)
+            .await
+            .unwrap()
+            .check(b"Vega"));
+        // A corrupt payload is rejected instead of being deserialized
+        let mut data = fs::read(&path).await.unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).await.unwrap();
+        let err = ScalableBloomFilter::from_file(name, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("checksum mismatch"));
+        // With a key the upgraded file is encrypted
+        fs::write(&path, bincode::serialize(&sbf).unwrap())
+            .await
+            .unwrap();
+        let key = Key::from_hex(&"2a".repeat(crate::crypto::KEY_SIZE)).unwrap();
+        assert!(ScalableBloomFilter::migrate(name, Some(&key))
+            .await
+            .unwrap());
+        let header = storage::read_header(&path).await.unwrap().unwrap();
+        assert_ne!(header.flags & storage::FLAG_ENCRYPTED, 0);
+        assert!(ScalableBloomFilter::from_file(name, Some(&key))
+            .await
+            .unwrap()
+            .check(b"Vega"));
+    }
 }
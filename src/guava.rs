@@ -0,0 +1,246 @@
+use crate::filter::{Layer, ScalableBloomFilter, ScaleFactor};
+use crate::storage::HashAlgorithm;
+use crate::AsyncResult;
+use std::convert::TryInto;
+use std::f64::consts::LN_2;
+use std::path::Path;
+use tokio::fs;
+
+// Ordinals of the `BloomFilterStrategies` written first by `BloomFilter.writeTo`
+const MURMUR128_MITZ_32: u8 = 0;
+const MURMUR128_MITZ_64: u8 = 1;
+// Size in bytes of the header written by `BloomFilter.writeTo`, the strategy, the number of hash
+// functions and the number of longs holding the bits
+const HEADER_SIZE: usize = 6;
+
+/// Number of bits and of hash functions of a Guava filter expecting `expected_insertions` items
+/// with a false positive probability `fpp`, the bits being rounded up to a multiple of 64.
+pub fn layer_size(expected_insertions: usize, fpp: f64) -> (usize, u32) {
+    let n = expected_insertions as f64;
+    let bits = ((-n * fpp.ln() / (LN_2 * LN_2)) as usize).max(1);
+    let hash_count = ((bits as f64 / n * LN_2).round() as u32).max(1);
+    (bits.div_ceil(64) * 64, hash_count)
+}
+
+/// Positions of the bits of `bytes` in a Guava filter of `bits` bits using the
+/// `MURMUR128_MITZ_32` strategy, double hashing the two halves of a 64 bits digest
+pub fn positions_mitz32(bytes: &[u8], bits: usize, hash_count: u32) -> Vec<usize> {
+    let (h1, _) = murmur3_128(bytes);
+    let (hash1, hash2) = (h1 as i32, (h1 >> 32) as i32);
+    (1..=hash_count as i32)
+        .map(|i| {
+            let combined = hash1.wrapping_add(i.wrapping_mul(hash2));
+            let combined = if combined < 0 { !combined } else { combined };
+            combined as usize % bits
+        })
+        .collect()
+}
+
+/// Positions of the bits of `bytes` in a Guava filter of `bits` bits using the
+/// `MURMUR128_MITZ_64` strategy, the default one, double hashing the two halves of a 128 bits
+/// digest
+pub fn positions_mitz64(bytes: &[u8], bits: usize, hash_count: u32) -> Vec<usize> {
+    let (h1, h2) = murmur3_128(bytes);
+    let mut combined = h1;
+    (0..hash_count)
+        .map(|_| {
+            let position = (combined & i64::MAX as u64) % bits as u64;
+            combined = combined.wrapping_add(h2);
+            position as usize
+        })
+        .collect()
+}
+
+// MurmurHash3_x64_128 seeded with 0, Guava's `Hashing.murmur3_128()`, returned as its two
+// halves, the first one being what `HashCode.asLong` returns
+fn murmur3_128(bytes: &[u8]) -> (u64, u64) {
+    let (mut h1, mut h2) = (0u64, 0u64);
+    let mut blocks = bytes.chunks_exact(16);
+    for block in &mut blocks {
+        h1 ^= mix_k1(u64::from_le_bytes(block[..8].try_into().unwrap()));
+        h1 = h1
+            .rotate_left(27)
+            .wrapping_add(h2)
+            .wrapping_mul(5)
+            .wrapping_add(0x52dce729);
+        h2 ^= mix_k2(u64::from_le_bytes(block[8..].try_into().unwrap()));
+        h2 = h2
+            .rotate_left(31)
+            .wrapping_add(h1)
+            .wrapping_mul(5)
+            .wrapping_add(0x38495ab5);
+    }
+    let tail = blocks.remainder();
+    let (mut k1, mut k2) = (0u64, 0u64);
+    for (i, byte) in tail.iter().enumerate() {
+        if i < 8 {
+            k1 |= (*byte as u64) << (8 * i);
+        } else {
+            k2 |= (*byte as u64) << (8 * (i - 8));
+        }
+    }
+    if tail.len() > 8 {
+        h2 ^= mix_k2(k2);
+    }
+    if !tail.is_empty() {
+        h1 ^= mix_k1(k1);
+    }
+    h1 ^= bytes.len() as u64;
+    h2 ^= bytes.len() as u64;
+    h1 = h1.wrapping_add(h2);
+    h2 = h2.wrapping_add(h1);
+    h1 = fmix64(h1);
+    h2 = fmix64(h2);
+    h1 = h1.wrapping_add(h2);
+    (h1, h2.wrapping_add(h1))
+}
+
+fn mix_k1(k1: u64) -> u64 {
+    k1.wrapping_mul(0x87c37b91114253d5)
+        .rotate_left(31)
+        .wrapping_mul(0x4cf5ad432745937f)
+}
+
+fn mix_k2(k2: u64) -> u64 {
+    k2.wrapping_mul(0x4cf5ad432745937f)
+        .rotate_left(33)
+        .wrapping_mul(0x87c37b91114253d5)
+}
+
+fn fmix64(mut k: u64) -> u64 {
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51afd7ed558ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ceb9fe1a85ec53);
+    k ^ (k >> 33)
+}
+
+/// Rebuild a filter named `name` out of the bytes written by Guava's `BloomFilter.writeTo`, keys
+/// being hashed with its strategy from then on. Keys are hashed as their UTF-8 bytes, so the
+/// Guava filter must have been built with `Funnels.stringFunnel(StandardCharsets.UTF_8)`, or
+/// `Funnels.byteArrayFunnel()` fed with the same bytes.
+///
+/// Guava records neither the number of items of a filter nor the parameters it was created with,
+/// they're estimated from its bits and number of hash functions.
+///
+/// # Errors
+///
+/// Returns `Err` if the data is truncated or uses an unknown strategy.
+pub fn load(name: String, data: &[u8]) -> Result<ScalableBloomFilter, String> {
+    if data.len() < HEADER_SIZE {
+        return Err("truncated header, not a Guava bloom filter".into());
+    }
+    let hash_algorithm = match data[0] {
+        MURMUR128_MITZ_32 => HashAlgorithm::Murmur128Mitz32,
+        MURMUR128_MITZ_64 => HashAlgorithm::Murmur128Mitz64,
+        strategy => {
+            return Err(format!(
+                "unknown strategy {}, only MURMUR128_MITZ_32 and MURMUR128_MITZ_64 are supported",
+                strategy
+            ))
+        }
+    };
+    let hash_count = data[1] as u32;
+    let longs = i32::from_be_bytes(data[2..HEADER_SIZE].try_into().unwrap());
+    if hash_count == 0 || longs <= 0 || data.len() != HEADER_SIZE + longs as usize * 8 {
+        return Err(format!(
+            "{} bytes can't hold {} longs of bits, not a Guava bloom filter",
+            data.len(),
+            longs
+        ));
+    }
+    // Bit `i` is bit `i % 64` of long `i / 64`, the longs being big-endian
+    let bitmap: Vec<u8> = data[HEADER_SIZE..]
+        .chunks_exact(8)
+        .flat_map(|long| u64::from_be_bytes(long.try_into().unwrap()).to_le_bytes())
+        .collect();
+    let bits = bitmap.len() * 8;
+    // Estimated like `BloomFilter.approximateElementCount` does
+    let set: u32 = bitmap.iter().map(|b| b.count_ones()).sum();
+    let size = -(1. - set as f64 / bits as f64).ln() * bits as f64 / hash_count as f64;
+    let capacity = ((bits as f64 * LN_2 / hash_count as f64) as usize).max(1);
+    let fpp = (-(bits as f64 / capacity as f64) * LN_2 * LN_2).exp();
+    let layer = Layer {
+        bits,
+        hash_count,
+        size: (size.round() as usize).min(bits),
+        bitmap,
+    };
+    Ok(ScalableBloomFilter::from_layers(
+        name,
+        capacity,
+        fpp,
+        ScaleFactor::SmallScaleSize,
+        hash_algorithm,
+        &[layer],
+    ))
+}
+
+/// Import the Guava filter written into the file at `path` as a filter named `name`, see `load`
+pub async fn import(name: String, path: &Path) -> AsyncResult<ScalableBloomFilter> {
+    let data = fs::read(path)
+        .await
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(load(name, &data).map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+#[cfg(test)]
+mod guava_tests {
+    use super::*;
+
+    // Keys stored into the fixtures, see tests/fixtures/GuavaFixtures.java
+    const KEYS: [&str; 5] = ["apple", "banana", "cherry", "damson", "elderberry"];
+
+    #[test]
+    fn test_murmur3_128() {
+        // Known values of Guava's own tests
+        assert_eq!(
+            murmur3_128(b"hell"),
+            (0x629942693e10f867, 0x92db0b82baeb5347)
+        );
+        assert_eq!(
+            murmur3_128(b"The quick brown fox jumps over the lazy dog"),
+            (0xe34bbc7bbc071b6c, 0x7a433ca9c49a9347)
+        );
+    }
+
+    #[test]
+    fn test_load_fixtures() {
+        let fixtures = [
+            (
+                &include_bytes!("../tests/fixtures/guava-mitz32.bin")[..],
+                HashAlgorithm::Murmur128Mitz32,
+            ),
+            (
+                &include_bytes!("../tests/fixtures/guava-mitz64.bin")[..],
+                HashAlgorithm::Murmur128Mitz64,
+            ),
+        ];
+        for (data, hash_algorithm) in fixtures.iter() {
+            let mut sbf = load("test".into(), data).unwrap();
+            assert_eq!(sbf.hash_algorithm(), *hash_algorithm);
+            assert_eq!(sbf.capacity(), 960);
+            assert_eq!(sbf.hash_count(), 7);
+            assert_eq!(sbf.size(), KEYS.len());
+            for key in KEYS.iter() {
+                assert!(sbf.check(key.as_bytes()), "{} not found", key);
+            }
+            for key in ["fig", "grape", "kiwi"].iter() {
+                assert!(!sbf.check(key.as_bytes()), "{} found", key);
+            }
+            sbf.set(b"fig").unwrap();
+            assert!(sbf.check(b"fig"));
+        }
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let data = include_bytes!("../tests/fixtures/guava-mitz64.bin");
+        let err = |data: &[u8]| load("test".into(), data).err().unwrap();
+        assert!(err(&data[..4]).contains("truncated header"));
+        assert!(err(&data[..data.len() - 1]).contains("can't hold 15 longs"));
+        let mut unknown = data.to_vec();
+        unknown[0] = 2;
+        assert!(err(&unknown).contains("unknown strategy 2"));
+    }
+}
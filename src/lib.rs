@@ -1,18 +1,125 @@
+mod aof;
+mod backup;
+mod binary;
+mod bitmap;
+mod crypto;
 mod filter;
+mod listener;
+/// Server and client of the gRPC service, generated from proto/rublo.proto
+#[allow(clippy::all)]
+pub mod grpc {
+    tonic::include_proto!("rublo");
+}
+pub mod guava;
+pub mod pybloom;
+pub mod redisbloom;
+mod resp;
 pub mod server;
+mod snapshot;
+mod storage;
+
+pub use filter::{Layer, ScalableBloomFilter, ScaleFactor, DEFAULT_DATA_DIR};
+pub use storage::HashAlgorithm;
 
 use chrono::Local;
 use log::{Level, LevelFilter, Metadata, Record, SetLoggerError};
 use serde::Deserialize;
 use serde_yaml;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 pub type AsyncResult<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
+// Address the TCP listener is bound to when not configured otherwise
+const DEFAULT_LISTEN_ON: &str = "127.0.0.1:4989";
+
 #[derive(Debug, PartialEq, Deserialize)]
 pub struct Config {
     listen_on: String,
+    /// Directory the filters are stored in
+    #[serde(default = "default_data_dir")]
+    data_dir: PathBuf,
+    /// Directories filters can be created in out of `data_dir`, by volume name. They're the only
+    /// ones accepted from clients, by name or by path.
+    #[serde(default)]
+    volumes: HashMap<String, PathBuf>,
     #[serde(default = "filter::ScaleFactor::small_scale_size")]
     scale_factor: filter::ScaleFactor,
+    /// Record every write into an append-only log, replayed on startup
+    #[serde(default)]
+    appendonly: bool,
+    #[serde(default = "aof::FsyncPolicy::every_sec")]
+    appendfsync: aof::FsyncPolicy,
+    /// Rules deciding when changed filters are written to disk
+    #[serde(default = "server::SaveRule::defaults")]
+    save: Vec<server::SaveRule>,
+    /// Compression of the filters written to disk
+    #[serde(default = "storage::Compression::none")]
+    compression: storage::Compression,
+    /// Filters loaded into memory on startup, the others are only loaded on first access
+    #[serde(default)]
+    preload: Vec<String>,
+    /// Directory the snapshot archives are written to and restored from, `snapshots` in
+    /// `data_dir` unless configured
+    #[serde(default)]
+    snapshot_dir: Option<PathBuf>,
+    /// Directory the filters are exported to and imported from, `interop` in `data_dir` unless
+    /// configured
+    #[serde(default)]
+    interop_dir: Option<PathBuf>,
+    /// Scheduled backups of the filters, disabled unless configured
+    #[serde(default)]
+    backup: Option<backup::BackupConfig>,
+    /// File holding the key encrypting the filters and the append-only log written to disk,
+    /// encryption at rest is disabled when missing
+    #[serde(default)]
+    encryption_key_file: Option<PathBuf>,
+    /// Address of an additional listener speaking RESP, the Redis protocol, disabled when
+    /// missing
+    #[serde(default)]
+    resp_listen_on: Option<String>,
+    /// Address of an additional listener speaking the bloomd protocol, disabled when missing
+    #[serde(default)]
+    bloomd_listen_on: Option<String>,
+    /// Address of the HTTP API, disabled when missing
+    #[serde(default)]
+    http_listen_on: Option<String>,
+    /// Address of the gRPC service, disabled when missing
+    #[serde(default)]
+    grpc_listen_on: Option<String>,
+    /// Path of a Unix domain socket serving the text and binary protocols, in addition to
+    /// `listen_on`, disabled when missing
+    #[serde(default)]
+    unix_socket: Option<PathBuf>,
+    /// Permissions of the Unix domain sockets created, as octal digits
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    unix_socket_mode: Option<u32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_on: DEFAULT_LISTEN_ON.into(),
+            data_dir: default_data_dir(),
+            volumes: HashMap::new(),
+            scale_factor: filter::ScaleFactor::small_scale_size(),
+            appendonly: false,
+            appendfsync: aof::FsyncPolicy::every_sec(),
+            save: server::SaveRule::defaults(),
+            compression: storage::Compression::none(),
+            preload: Vec::new(),
+            snapshot_dir: None,
+            interop_dir: None,
+            backup: None,
+            encryption_key_file: None,
+            resp_listen_on: None,
+            bloomd_listen_on: None,
+            http_listen_on: None,
+            grpc_listen_on: None,
+            unix_socket: None,
+            unix_socket_mode: None,
+        }
+    }
 }
 
 impl Config {
@@ -26,9 +133,110 @@ impl Config {
         &self.listen_on
     }
 
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    pub fn volumes(&self) -> &HashMap<String, PathBuf> {
+        &self.volumes
+    }
+
     pub fn scale_factor(&self) -> &filter::ScaleFactor {
         &self.scale_factor
     }
+
+    /// The fsync policy of the append-only log, `None` if the log is disabled
+    pub fn appendonly(&self) -> Option<aof::FsyncPolicy> {
+        if self.appendonly {
+            Some(self.appendfsync)
+        } else {
+            None
+        }
+    }
+
+    pub fn save_rules(&self) -> &[server::SaveRule] {
+        &self.save
+    }
+
+    pub fn compression(&self) -> storage::Compression {
+        self.compression
+    }
+
+    pub fn preload(&self) -> &[String] {
+        &self.preload
+    }
+
+    pub fn snapshot_dir(&self) -> PathBuf {
+        self.snapshot_dir
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join(snapshot::DEFAULT_SNAPSHOT_DIR))
+    }
+
+    pub fn interop_dir(&self) -> PathBuf {
+        self.interop_dir
+            .clone()
+            .unwrap_or_else(|| self.data_dir.join(server::DEFAULT_INTEROP_DIR))
+    }
+
+    pub fn backup(&self) -> Option<&backup::BackupConfig> {
+        self.backup.as_ref()
+    }
+
+    pub fn encryption_key_file(&self) -> Option<&Path> {
+        self.encryption_key_file.as_deref()
+    }
+
+    pub fn resp_listen_on(&self) -> Option<&str> {
+        self.resp_listen_on.as_deref()
+    }
+
+    pub fn bloomd_listen_on(&self) -> Option<&str> {
+        self.bloomd_listen_on.as_deref()
+    }
+
+    pub fn http_listen_on(&self) -> Option<&str> {
+        self.http_listen_on.as_deref()
+    }
+
+    pub fn grpc_listen_on(&self) -> Option<&str> {
+        self.grpc_listen_on.as_deref()
+    }
+
+    pub fn unix_socket(&self) -> Option<&Path> {
+        self.unix_socket.as_deref()
+    }
+
+    pub fn unix_socket_mode(&self) -> Option<u32> {
+        self.unix_socket_mode
+    }
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from(DEFAULT_DATA_DIR)
+}
+
+// Permissions written as octal digits, `660` as well as `"0660"`
+fn deserialize_mode<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Digits(u64),
+        Text(String),
+    }
+    let digits = match Mode::deserialize(deserializer)? {
+        Mode::Digits(digits) => digits.to_string(),
+        Mode::Text(text) => text,
+    };
+    match u32::from_str_radix(&digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(Some(mode)),
+        _ => Err(serde::de::Error::custom(format!(
+            "invalid permissions {}, expected octal digits such as 660",
+            digits
+        ))),
+    }
 }
 
 struct SimpleLogger;
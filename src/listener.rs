@@ -0,0 +1,271 @@
+use crate::AsyncResult;
+use futures::future::poll_fn;
+#[cfg(unix)]
+use log::error;
+use std::io;
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// Prefix of the addresses of Unix domain sockets, e.g. `unix:/run/rublo/rublo.sock`
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Listener of any of the protocols, on a TCP address or a Unix domain socket
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+/// Listener of a Unix domain socket, whose file is removed once it's dropped
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                error!("can't remove {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+impl Listener {
+    /// Bind `address`, a Unix domain socket if prefixed with `unix:` and a TCP address otherwise.
+    /// A socket left by a previous run is replaced, its permissions are set to `mode` if given.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if the address can't be bound, or if the path of the socket is taken by
+    /// something other than a socket or by a socket a server still listens on.
+    pub async fn bind(address: &str, mode: Option<u32>) -> AsyncResult<Listener> {
+        match address.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Listener::bind_unix(Path::new(path), mode).await,
+            None => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+        }
+    }
+
+    /// Bind a Unix domain socket at `path`, replacing a socket left by a previous run, its
+    /// permissions being set to `mode` if given.
+    ///
+    /// The socket is bound in a private directory next to `path` and moved to `path` once its
+    /// permissions are set, so that it's never reachable with broader ones.
+    #[cfg(unix)]
+    pub async fn bind_unix(path: &Path, mode: Option<u32>) -> AsyncResult<Listener> {
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                // Only sockets nobody listens on anymore are replaced
+                if UnixStream::connect(path).await.is_ok() {
+                    return Err(format!("{} is in use by a running server", path.display()).into());
+                }
+            }
+            Ok(_) => return Err(format!("{} exists and isn't a socket", path.display()).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| format!("{} isn't a valid socket path", path.display()))?;
+        let private_dir = path.with_file_name(format!(
+            ".{}.{}",
+            file_name.to_string_lossy(),
+            std::process::id()
+        ));
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&private_dir)?;
+        let bind = || -> io::Result<UnixListener> {
+            let tmp_path = private_dir.join(file_name);
+            let listener = UnixListener::bind(&tmp_path)?;
+            if let Some(mode) = mode {
+                std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(mode))?;
+            }
+            std::fs::rename(&tmp_path, path)?;
+            Ok(listener)
+        };
+        let listener = bind();
+        let _ = std::fs::remove_dir_all(&private_dir);
+        Ok(Listener::Unix(UnixSocket {
+            listener: listener?,
+            path: path.to_path_buf(),
+        }))
+    }
+
+    #[cfg(not(unix))]
+    pub async fn bind_unix(_path: &Path, _mode: Option<u32>) -> AsyncResult<Listener> {
+        Err("Unix domain sockets aren't supported on this platform".into())
+    }
+
+    /// Local address the listener is bound to, for logging
+    pub fn local_addr(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            #[cfg(unix)]
+            Listener::Unix(socket) => format!("{}{}", UNIX_PREFIX, socket.path.display()),
+        }
+    }
+
+    /// Poll for a connection, with the address of the peer for logging
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(Connection, String)>> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, peer)| (Connection::Tcp(stream), peer.to_string())),
+            // Clients of Unix domain sockets seldom have a name, the socket is named instead
+            #[cfg(unix)]
+            Listener::Unix(socket) => socket
+                .listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| (Connection::Unix(stream), self.local_addr())),
+        }
+    }
+
+    /// Accept a connection, with the address of the peer for logging
+    pub async fn accept(&self) -> io::Result<(Connection, String)> {
+        poll_fn(|cx| self.poll_accept(cx)).await
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+/// Connection accepted by a `Listener`
+#[derive(Debug)]
+pub enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Connections served by hyper
+impl hyper::server::accept::Accept for Listener {
+    type Conn = Connection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<Connection>>> {
+        Listener::poll_accept(self.get_mut(), cx).map(|accepted| Some(accepted.map(|(c, _)| c)))
+    }
+}
+
+/// Connections served by tonic, which doesn't need to know about the peers
+impl tonic::transport::server::Connected for Connection {
+    type ConnectInfo = ();
+
+    fn connect_info(&self) {}
+}
+
+#[cfg(all(test, unix))]
+mod listener_tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_bind_unix() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("rublo.sock");
+        let address = format!("{}{}", UNIX_PREFIX, path.display());
+        // Stale sockets are replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let listener = Listener::bind(&address, Some(0o600)).await.unwrap();
+        assert_eq!(listener.local_addr(), address);
+        let mode = tokio::fs::metadata(&path)
+            .await
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        let (mut connection, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, address);
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        connection.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        // Unlike the ones a server listens on
+        let err = Listener::bind(&address, None).await.unwrap_err();
+        assert!(err.to_string().contains("in use by a running server"));
+        // The socket is bound in a private directory, removed once done
+        let entries = std::fs::read_dir(dir).unwrap().count();
+        assert_eq!(entries, 1);
+        drop(listener);
+        assert!(!path.exists());
+        // Anything else than a socket is left alone
+        let file = dir.join("rublo.yaml");
+        tokio::fs::write(&file, b"").await.unwrap();
+        let address = format!("{}{}", UNIX_PREFIX, file.display());
+        let err = Listener::bind(&address, None).await.unwrap_err();
+        assert!(err.to_string().contains("isn't a socket"));
+        let listener = Listener::bind("127.0.0.1:0", Some(0o600)).await.unwrap();
+        assert!(listener.local_addr().starts_with("127.0.0.1:"));
+    }
+}
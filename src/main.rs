@@ -1,11 +1,24 @@
 use log::info;
 use rublo::server;
-use tokio::net::TcpListener;
 
 #[tokio::main]
 async fn main() -> rublo::AsyncResult<()> {
     rublo::init_logging().expect("Can't enable logging");
-    let listener = TcpListener::bind("127.0.0.1:4989".to_string()).await?;
-    info!("listening on ::4989");
-    server::run(listener).await
+    let args: Vec<String> = std::env::args().collect();
+    // `rublo migrate [config.yaml]` upgrades persisted filters to the current format and exits
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        let config = match args.get(2) {
+            Some(path) => rublo::Config::from_file(path).map_err(|e| e.to_string())?,
+            None => rublo::Config::default(),
+        };
+        return server::migrate(&config).await;
+    }
+    // `rublo [config.yaml]` otherwise, running with defaults if no configuration is given
+    let config = match args.get(1) {
+        Some(path) => rublo::Config::from_file(path).map_err(|e| e.to_string())?,
+        None => rublo::Config::default(),
+    };
+    let listener = server::Listener::bind(config.listen_on(), config.unix_socket_mode()).await?;
+    info!("listening on {}", config.listen_on());
+    server::run(listener, config).await
 }
@@ -0,0 +1,174 @@
+use crate::filter::{Layer, ScalableBloomFilter, ScaleFactor};
+use crate::storage::HashAlgorithm;
+use crate::AsyncResult;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::convert::TryInto;
+use std::f64::consts::LN_2;
+use std::path::Path;
+use tokio::fs;
+
+// Size in bytes of the header written by `BloomFilter.tofile`, the error rate, the number of
+// slices, the bits per slice, the capacity and the count of items
+const HEADER_SIZE: usize = 40;
+
+/// Number of bits and of slices, one per hash function, of a pybloom filter holding `capacity`
+/// items with a false positive probability `error_rate`.
+pub fn layer_size(capacity: usize, error_rate: f64) -> (usize, u32) {
+    let slices = ((1. / error_rate).ln() / LN_2).ceil().max(1.) as usize;
+    let bits_per_slice =
+        (capacity as f64 * error_rate.ln().abs() / (slices as f64 * LN_2 * LN_2)).ceil() as usize;
+    (slices * bits_per_slice.max(1), slices as u32)
+}
+
+/// Positions of the bits of `bytes` in a pybloom filter of `bits` bits split into `hash_count`
+/// slices, one bit per slice. The bit of each slice is taken from a digest of `bytes` salted with
+/// a digest of a counter, split into words wide enough to index a slice. The digest is the
+/// shortest of MD5, SHA-1, SHA-256, SHA-384 and SHA-512 giving a word to every slice, or
+/// SHA-512 repeated with more salts for the largest filters.
+pub fn positions(bytes: &[u8], bits: usize, hash_count: u32) -> Vec<usize> {
+    let slices = hash_count as usize;
+    let bits_per_slice = bits / slices;
+    let word_size = if bits_per_slice >= 1 << 31 {
+        8
+    } else if bits_per_slice >= 1 << 15 {
+        4
+    } else {
+        2
+    };
+    let digest: fn(u32, &[u8]) -> Vec<u8> = match 8 * slices * word_size {
+        hash_bits if hash_bits > 384 => salted::<Sha512>,
+        hash_bits if hash_bits > 256 => salted::<Sha384>,
+        hash_bits if hash_bits > 160 => salted::<Sha256>,
+        hash_bits if hash_bits > 128 => salted::<Sha1>,
+        _ => salted::<Md5>,
+    };
+    let mut words = Vec::with_capacity(slices);
+    let mut salt = 0;
+    while words.len() < slices {
+        words.extend(digest(salt, bytes).chunks_exact(word_size).map(|chunk| {
+            let mut word = [0; 8];
+            word[..word_size].copy_from_slice(chunk);
+            u64::from_le_bytes(word)
+        }));
+        salt += 1;
+    }
+    words
+        .iter()
+        .take(slices)
+        .enumerate()
+        .map(|(slice, word)| slice * bits_per_slice + (word % bits_per_slice as u64) as usize)
+        .collect()
+}
+
+// Digest of `bytes` salted with the digest of `salt`
+fn salted<D: Digest>(salt: u32, bytes: &[u8]) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.update(D::digest(salt.to_le_bytes()));
+    hasher.update(bytes);
+    hasher.finalize().to_vec()
+}
+
+/// Rebuild a filter named `name` out of the bytes written by pybloom's `BloomFilter.tofile`,
+/// keys being hashed like pybloom does from then on. pybloom hashes strings as their UTF-8 bytes,
+/// like rublo keys. Only `BloomFilter` files are supported, not `ScalableBloomFilter` ones.
+///
+/// # Errors
+///
+/// Returns `Err` if the data is truncated or its header is inconsistent.
+pub fn load(name: String, data: &[u8]) -> Result<ScalableBloomFilter, String> {
+    if data.len() < HEADER_SIZE {
+        return Err("truncated header, not a pybloom BloomFilter".into());
+    }
+    let field = |i: usize| u64::from_le_bytes(data[i * 8..i * 8 + 8].try_into().unwrap());
+    let error_rate = f64::from_bits(field(0));
+    let (slices, bits_per_slice, capacity, count) = (field(1), field(2), field(3), field(4));
+    let bits = slices
+        .checked_mul(bits_per_slice)
+        .filter(|bits| *bits > 0 && slices <= u32::MAX as u64);
+    let valid = error_rate > 0. && error_rate < 1. && capacity > 0;
+    match bits {
+        Some(bits) if valid && data.len() as u64 == HEADER_SIZE as u64 + bits.div_ceil(8) => {
+            let layer = Layer {
+                bits: bits as usize,
+                hash_count: slices as u32,
+                size: count as usize,
+                bitmap: data[HEADER_SIZE..].to_vec(),
+            };
+            Ok(ScalableBloomFilter::from_layers(
+                name,
+                capacity as usize,
+                error_rate,
+                ScaleFactor::SmallScaleSize,
+                HashAlgorithm::Pybloom,
+                &[layer],
+            ))
+        }
+        _ => Err(format!(
+            "{} slices of {} bits in {} bytes, not a pybloom BloomFilter (ScalableBloomFilter files aren't supported)",
+            slices,
+            bits_per_slice,
+            data.len()
+        )),
+    }
+}
+
+/// Import the pybloom filter written into the file at `path` as a filter named `name`, see
+/// `load`
+pub async fn import(name: String, path: &Path) -> AsyncResult<ScalableBloomFilter> {
+    let data = fs::read(path)
+        .await
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(load(name, &data).map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+#[cfg(test)]
+mod pybloom_tests {
+    use super::*;
+
+    // Keys stored into the fixtures, see tests/fixtures/pybloom_fixtures.py
+    const KEYS: [&str; 5] = ["apple", "banana", "cherry", "damson", "elderberry"];
+
+    #[test]
+    fn test_load_fixtures() {
+        let fixtures = [
+            (
+                &include_bytes!("../tests/fixtures/pybloom-md5.bin")[..],
+                layer_size(100, 0.01),
+            ),
+            (
+                &include_bytes!("../tests/fixtures/pybloom-sha256.bin")[..],
+                layer_size(1000, 0.0001),
+            ),
+        ];
+        assert_eq!(fixtures[0].1, (959, 7));
+        assert_eq!(fixtures[1].1, (19180, 14));
+        for (data, (bits, hash_count)) in fixtures.iter() {
+            let mut sbf = load("test".into(), data).unwrap();
+            assert_eq!(sbf.hash_algorithm(), HashAlgorithm::Pybloom);
+            assert_eq!(sbf.capacity(), *bits);
+            assert_eq!(sbf.hash_count(), *hash_count);
+            assert_eq!(sbf.size(), KEYS.len());
+            for key in KEYS.iter() {
+                assert!(sbf.check(key.as_bytes()), "{} not found", key);
+            }
+            for key in ["fig", "grape", "kiwi"].iter() {
+                assert!(!sbf.check(key.as_bytes()), "{} found", key);
+            }
+            sbf.set(b"fig").unwrap();
+            assert!(sbf.check(b"fig"));
+        }
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let data = include_bytes!("../tests/fixtures/pybloom-md5.bin");
+        let err = |data: &[u8]| load("test".into(), data).err().unwrap();
+        assert!(err(&data[..39]).contains("truncated header"));
+        assert!(err(&data[..data.len() - 1]).contains("7 slices of 137 bits"));
+        let mut zero = data.to_vec();
+        zero[8..16].copy_from_slice(&0u64.to_le_bytes());
+        assert!(err(&zero).contains("not a pybloom BloomFilter"));
+    }
+}
@@ -0,0 +1,556 @@
+use crate::filter::{Layer, ScalableBloomFilter, ScaleFactor};
+use crate::storage::{self, HashAlgorithm};
+use crate::AsyncResult;
+use std::convert::TryInto;
+use std::f64::consts::LN_2;
+use std::path::Path;
+use tokio::fs;
+
+// Options of a RedisBloom filter, `BLOOM_OPT_*` in its sources
+const OPT_NOROUND: u32 = 1;
+const OPT_FORCE64: u32 = 4;
+const OPT_NO_SCALING: u32 = 8;
+// Sizes in bytes of the packed header of a dump and of the description of each of its layers
+const HEADER_SIZE: usize = 20;
+const LINK_SIZE: usize = 53;
+// Seed of the first MurmurHash64A digest of a key, the second one is seeded with the first
+const MURMUR_SEED: u64 = 0xc6a4a7935bd1e995;
+// Size of the chunk header of an export file, the iterator and the length of the chunk
+const CHUNK_HEADER_SIZE: usize = 16;
+// Largest chunk of bits produced by `dump`, BF.LOADCHUNK accepts chunks of any size
+pub const MAX_CHUNK_SIZE: usize = 10 * 1024 * 1024;
+// Ratio between the false positive probabilities of two consecutive RedisBloom layers
+pub const ERROR_TIGHTENING_RATIO: f64 = 0.5;
+
+/// A chunk of a RedisBloom filter as returned by `BF.SCANDUMP`, along with the iterator returned
+/// with it, to be passed as is to `BF.LOADCHUNK`. The first chunk holds the parameters of the
+/// filter, the following ones its bits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub iterator: i64,
+    pub data: Vec<u8>,
+}
+
+// Description of a layer in the header of a dump, `dumpedChainLink` in RedisBloom
+#[derive(Debug, Clone, PartialEq)]
+struct Link {
+    bytes: u64,
+    bits: u64,
+    size: u64,
+    error: f64,
+    bpe: f64,
+    hashes: u32,
+    entries: u64,
+    n2: u8,
+}
+
+// Header of a dump, `dumpedChainHeader` in RedisBloom, all little-endian and packed
+#[derive(Debug, Clone, PartialEq)]
+struct Header {
+    size: u64,
+    options: u32,
+    growth: u32,
+    links: Vec<Link>,
+}
+
+// Reader of little-endian integers from a buffer whose length has been checked beforehand
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let (head, tail) = self.0.split_at(N);
+        self.0 = tail;
+        head.try_into().unwrap()
+    }
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(HEADER_SIZE + self.links.len() * LINK_SIZE);
+        data.extend_from_slice(&self.size.to_le_bytes());
+        data.extend_from_slice(&(self.links.len() as u32).to_le_bytes());
+        data.extend_from_slice(&self.options.to_le_bytes());
+        data.extend_from_slice(&self.growth.to_le_bytes());
+        for link in self.links.iter() {
+            data.extend_from_slice(&link.bytes.to_le_bytes());
+            data.extend_from_slice(&link.bits.to_le_bytes());
+            data.extend_from_slice(&link.size.to_le_bytes());
+            data.extend_from_slice(&link.error.to_le_bytes());
+            data.extend_from_slice(&link.bpe.to_le_bytes());
+            data.extend_from_slice(&link.hashes.to_le_bytes());
+            data.extend_from_slice(&link.entries.to_le_bytes());
+            data.push(link.n2);
+        }
+        data
+    }
+
+    fn decode(data: &[u8]) -> Result<Header, String> {
+        if data.len() < HEADER_SIZE {
+            return Err("truncated header, not a RedisBloom filter dump".into());
+        }
+        let mut reader = Reader(data);
+        let size = u64::from_le_bytes(reader.take());
+        let count = u32::from_le_bytes(reader.take()) as usize;
+        let options = u32::from_le_bytes(reader.take());
+        let growth = u32::from_le_bytes(reader.take());
+        // Headers written before RedisBloom 2.2 lack the growth and don't match either
+        if count == 0 || data.len() != HEADER_SIZE + count * LINK_SIZE {
+            return Err(format!(
+                "a header of {} bytes can't describe {} layers, not a RedisBloom 2.2 or later filter dump",
+                data.len(),
+                count
+            ));
+        }
+        let links = (0..count)
+            .map(|_| Link {
+                bytes: u64::from_le_bytes(reader.take()),
+                bits: u64::from_le_bytes(reader.take()),
+                size: u64::from_le_bytes(reader.take()),
+                error: f64::from_le_bytes(reader.take()),
+                bpe: f64::from_le_bytes(reader.take()),
+                hashes: u32::from_le_bytes(reader.take()),
+                entries: u64::from_le_bytes(reader.take()),
+                n2: u8::from_le_bytes(reader.take()),
+            })
+            .collect();
+        Ok(Header {
+            size,
+            options,
+            growth,
+            links,
+        })
+    }
+
+    // Check that rublo can hash keys and scale the filter exactly like RedisBloom does
+    fn validate(&self) -> Result<(), String> {
+        if self.options & OPT_FORCE64 == 0 {
+            return Err("the filter hashes keys with 32 bits MurmurHash2, only filters created by RedisBloom 2.0 or later, with 64 bits hashing, are supported".into());
+        }
+        if self.options & OPT_NO_SCALING != 0 {
+            return Err("non-scaling filters (NONSCALING) aren't supported".into());
+        }
+        if self.growth != ScaleFactor::SmallScaleSize as u32
+            && self.growth != ScaleFactor::LargeScaleSize as u32
+        {
+            return Err(format!(
+                "expansion {} isn't supported, it must be 2 or 4 to match the small and large scale factors",
+                self.growth
+            ));
+        }
+        for (i, link) in self.links.iter().enumerate() {
+            if link.bits == 0
+                || link.bits != link.bytes.saturating_mul(8)
+                || link.hashes == 0
+                || link.entries == 0
+                || !(link.error > 0. && link.error < 1.)
+            {
+                return Err(format!(
+                    "layer {} is invalid: {} bits in {} bytes, {} hash functions, {} entries, error rate {}",
+                    i, link.bits, link.bytes, link.hashes, link.entries, link.error
+                ));
+            }
+            // Layers rounded to a power of two bits take positions modulo 2^n2, which only
+            // matches their size from 64 bits on
+            if link.n2 != 0 && (link.n2 >= 64 || 1 << link.n2 != link.bits) {
+                return Err(format!(
+                    "layer {} takes bit positions modulo 2^{} instead of its {} bits, which isn't supported",
+                    i, link.n2, link.bits
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Number of bits and of hash functions of a RedisBloom layer holding `entries` items with a
+/// false positive probability `error`, the bits being rounded up to a multiple of 64.
+pub fn layer_size(entries: usize, error: f64) -> (usize, u32) {
+    let bpe = -error.ln() / (LN_2 * LN_2);
+    let bits = ((entries as f64 * bpe) as usize).max(1);
+    (bits.div_ceil(64) * 64, (LN_2 * bpe).ceil() as u32)
+}
+
+/// Positions of the bits of `bytes` in a RedisBloom layer of `bits` bits, hash function `i`
+/// setting bit `(a + i * b) % bits` where `a` and `b` are two MurmurHash64A digests of `bytes`
+pub fn positions(bytes: &[u8], bits: usize, hash_count: u32) -> Vec<usize> {
+    let a = murmur64a(bytes, MURMUR_SEED);
+    let b = murmur64a(bytes, a);
+    (0..hash_count as u64)
+        .map(|i| (a.wrapping_add(i.wrapping_mul(b)) % bits as u64) as usize)
+        .collect()
+}
+
+// MurmurHash64A by Austin Appleby, as bundled with RedisBloom
+fn murmur64a(bytes: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4a7935bd1e995;
+    const R: u32 = 47;
+    let mut h = seed ^ (bytes.len() as u64).wrapping_mul(M);
+    let mut words = bytes.chunks_exact(8);
+    for word in &mut words {
+        let mut k = u64::from_le_bytes(word.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = words.remainder();
+    if !tail.is_empty() {
+        for (i, byte) in tail.iter().enumerate() {
+            h ^= (*byte as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+/// Dump `filter` as the chunks `BF.SCANDUMP` returns for the same RedisBloom filter, its header
+/// first and then its bits, to be fed to `BF.LOADCHUNK` in order.
+///
+/// Only filters hashing keys like RedisBloom, i.e. loaded from it, can be dumped. The capacity
+/// and error rate of each layer aren't kept by rublo, they're derived from the initial capacity
+/// and the scale factor of the filter, like RedisBloom sizes its layers.
+///
+/// # Errors
+///
+/// Returns `Err` if the filter doesn't hash keys like RedisBloom.
+pub fn dump(filter: &ScalableBloomFilter) -> Result<Vec<Chunk>, String> {
+    if filter.hash_algorithm() != HashAlgorithm::Murmur64A {
+        return Err(format!(
+            "filter {} hashes keys with {}, only filters imported from RedisBloom can be exported",
+            filter.name(),
+            filter.hash_algorithm()
+        ));
+    }
+    let layers = filter.layers();
+    let growth = filter.scale_factor() as u32;
+    let mut entries = filter.initial_capacity() as u64;
+    let mut links = Vec::with_capacity(layers.len());
+    for layer in layers.iter() {
+        let bpe = layer.bits as f64 / entries as f64;
+        links.push(Link {
+            bytes: layer.bitmap.len() as u64,
+            bits: layer.bits as u64,
+            size: layer.size as u64,
+            error: (-bpe * LN_2 * LN_2).exp(),
+            bpe,
+            hashes: layer.hash_count,
+            entries,
+            n2: 0,
+        });
+        entries = entries.saturating_mul(growth as u64);
+    }
+    let header = Header {
+        size: links.iter().map(|l| l.size).sum(),
+        options: OPT_NOROUND | OPT_FORCE64,
+        growth,
+        links,
+    };
+    let mut chunks = vec![Chunk {
+        iterator: 1,
+        data: header.encode(),
+    }];
+    // The iterator of a chunk of bits is 1 past its end, counted across all the layers
+    let mut position = 0;
+    for layer in layers.iter() {
+        for data in layer.bitmap.chunks(MAX_CHUNK_SIZE) {
+            position += data.len();
+            chunks.push(Chunk {
+                iterator: 1 + position as i64,
+                data: data.to_vec(),
+            });
+        }
+    }
+    Ok(chunks)
+}
+
+/// Rebuild a filter named `name` out of the chunks returned by `BF.SCANDUMP` for a RedisBloom
+/// filter, in the same order. The filter keeps hashing keys like RedisBloom, so that it answers
+/// the same and can be dumped back with `dump`.
+///
+/// The RedisBloom filter must use 64 bits hashing (the default since RedisBloom 2.0), be scaling
+/// and have an expansion of 2 or 4, matching the small and large scale factors.
+///
+/// # Errors
+///
+/// Returns `Err` if the chunks are incomplete or out of order, or if the parameters of the filter
+/// don't match the ones above.
+pub fn load(name: String, chunks: &[Chunk]) -> Result<ScalableBloomFilter, String> {
+    let (first, rest) = chunks.split_first().ok_or("empty dump")?;
+    if first.iterator != 1 {
+        return Err(format!(
+            "the dump must start with the header returned for iterator 1, not {}",
+            first.iterator
+        ));
+    }
+    let header = Header::decode(&first.data)?;
+    header.validate()?;
+    let mut bits = Vec::new();
+    // The final empty chunk returned by BF.SCANDUMP, with iterator 0, carries nothing
+    for chunk in rest.iter().filter(|c| !c.data.is_empty()) {
+        let expected = 1 + (bits.len() + chunk.data.len()) as i64;
+        if chunk.iterator != expected {
+            return Err(format!(
+                "chunk with iterator {} out of order, expected {}",
+                chunk.iterator, expected
+            ));
+        }
+        bits.extend_from_slice(&chunk.data);
+    }
+    let total: u64 = header.links.iter().map(|l| l.bytes).sum();
+    if bits.len() as u64 != total {
+        return Err(format!(
+            "incomplete dump, {} bytes of bits out of {}",
+            bits.len(),
+            total
+        ));
+    }
+    let mut offset = 0;
+    let mut layers = Vec::with_capacity(header.links.len());
+    for link in header.links.iter() {
+        let end = offset + link.bytes as usize;
+        layers.push(Layer {
+            bits: link.bits as usize,
+            hash_count: link.hashes,
+            size: link.size as usize,
+            bitmap: bits[offset..end].to_vec(),
+        });
+        offset = end;
+    }
+    let scale_factor = if header.growth == ScaleFactor::LargeScaleSize as u32 {
+        ScaleFactor::LargeScaleSize
+    } else {
+        ScaleFactor::SmallScaleSize
+    };
+    let first_link = &header.links[0];
+    Ok(ScalableBloomFilter::from_layers(
+        name,
+        first_link.entries as usize,
+        first_link.error,
+        scale_factor,
+        HashAlgorithm::Murmur64A,
+        &layers,
+    ))
+}
+
+/// Write `chunks` into a new file at `path`, each one as its iterator (i64) and its length in
+/// bytes (u64), both little-endian, followed by its data.
+///
+/// # Errors
+///
+/// Returns `Err` if a file already exists at `path` or it can't be written.
+pub async fn write(path: &Path, chunks: &[Chunk]) -> AsyncResult<()> {
+    if fs::metadata(path).await.is_ok() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+    let length = chunks
+        .iter()
+        .map(|c| CHUNK_HEADER_SIZE + c.data.len())
+        .sum();
+    let mut data = Vec::with_capacity(length);
+    for chunk in chunks.iter() {
+        data.extend_from_slice(&chunk.iterator.to_le_bytes());
+        data.extend_from_slice(&(chunk.data.len() as u64).to_le_bytes());
+        data.extend_from_slice(&chunk.data);
+    }
+    storage::write_atomic(path, &data).await
+}
+
+/// Read the chunks written by `write` into the file at `path`
+///
+/// # Errors
+///
+/// Returns `Err` if the file can't be read or is truncated.
+pub async fn read(path: &Path) -> AsyncResult<Vec<Chunk>> {
+    let data = fs::read(path)
+        .await
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut chunks = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        if rest.len() < CHUNK_HEADER_SIZE {
+            return Err(format!("{}: truncated chunk", path.display()).into());
+        }
+        let mut reader = Reader(rest);
+        let iterator = i64::from_le_bytes(reader.take());
+        let length = u64::from_le_bytes(reader.take());
+        if length > reader.0.len() as u64 {
+            return Err(format!("{}: truncated chunk", path.display()).into());
+        }
+        let (chunk, tail) = reader.0.split_at(length as usize);
+        chunks.push(Chunk {
+            iterator,
+            data: chunk.to_vec(),
+        });
+        rest = tail;
+    }
+    Ok(chunks)
+}
+
+/// Export `filter` into a new file at `path`, see `dump` and `write`
+pub async fn export(filter: &ScalableBloomFilter, path: &Path) -> AsyncResult<()> {
+    let chunks = dump(filter)?;
+    write(path, &chunks).await
+}
+
+/// Import the RedisBloom filter exported into the file at `path` as a filter named `name`, see
+/// `read` and `load`
+pub async fn import(name: String, path: &Path) -> AsyncResult<ScalableBloomFilter> {
+    let chunks = read(path).await?;
+    Ok(load(name, &chunks).map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+#[cfg(test)]
+mod redisbloom_tests {
+    use super::*;
+
+    // Header and bits of a filter created with `BF.RESERVE test 0.01 100` and holding `foo` and
+    // `bar`, the bits being set from the MurmurHash64A digests of the keys. It's built after the
+    // layout of RedisBloom, tests/fixtures/capture_redisbloom.py captures the same filter from a
+    // RedisBloom server.
+    fn redisbloom_dump() -> Vec<Chunk> {
+        let (bits, hashes) = layer_size(100, 0.01);
+        let mut bitmap = vec![0u8; bits / 8];
+        for key in [&b"foo"[..], b"bar"].iter() {
+            for x in positions(key, bits, hashes) {
+                bitmap[x / 8] |= 1 << (x % 8);
+            }
+        }
+        let header = Header {
+            size: 2,
+            options: OPT_NOROUND | OPT_FORCE64,
+            growth: 2,
+            links: vec![Link {
+                bytes: bitmap.len() as u64,
+                bits: bits as u64,
+                size: 2,
+                error: 0.01,
+                bpe: -(0.01f64).ln() / (LN_2 * LN_2),
+                hashes,
+                entries: 100,
+                n2: 0,
+            }],
+        };
+        let (head, tail) = bitmap.split_at(64);
+        vec![
+            Chunk {
+                iterator: 1,
+                data: header.encode(),
+            },
+            Chunk {
+                iterator: 65,
+                data: head.to_vec(),
+            },
+            Chunk {
+                iterator: 1 + bitmap.len() as i64,
+                data: tail.to_vec(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_murmur64a() {
+        // Digests of the reference implementation, MurmurHash64A of SMHasher, every tail length
+        // going through its own branch
+        let digests = [
+            0x0000000000000000,
+            0x0a5d08d6299c4888,
+            0xed1ee14aa9f597b0,
+            0xb26b00896960cb14,
+            0x92b7977746710c51,
+            0x02c468f7605bfecb,
+            0x99cda13344b54b1f,
+            0xae9ebd2095279402,
+            0x758f67d162b2d202,
+            0x4977490251674330,
+        ];
+        for (n, digest) in digests.iter().enumerate() {
+            assert_eq!(murmur64a(&b"123456789"[..n], 0), *digest);
+        }
+        assert_eq!(murmur64a(b"foo", 1), 0xe4b88a2c2c217c0e);
+        assert_eq!(murmur64a(b"foo", MURMUR_SEED), 0x822b4f99b121f10d);
+    }
+
+    #[test]
+    fn test_load_dump() {
+        let chunks = redisbloom_dump();
+        let mut sbf = load("test".into(), &chunks).unwrap();
+        assert_eq!(sbf.hash_algorithm(), HashAlgorithm::Murmur64A);
+        assert_eq!(sbf.size(), 2);
+        assert_eq!(sbf.capacity(), 960);
+        assert!(sbf.check(b"foo"));
+        assert!(sbf.check(b"bar"));
+        assert!(!sbf.check(b"baz"));
+        // The bits go back unchanged, split in chunks of their own
+        let dumped = dump(&sbf).unwrap();
+        assert_eq!(dumped.len(), 2);
+        let header = Header::decode(&dumped[0].data).unwrap();
+        let original = Header::decode(&chunks[0].data).unwrap();
+        assert_eq!(header.links[0].entries, 100);
+        assert_eq!(header.links[0].hashes, original.links[0].hashes);
+        assert!((header.links[0].error - 0.01).abs() < 0.001);
+        assert_eq!(dumped[1].iterator, 121);
+        assert_eq!(
+            dumped[1].data,
+            [&chunks[1].data[..], &chunks[2].data].concat()
+        );
+        // Keys set from then on are found by RedisBloom too
+        sbf.set(b"baz").unwrap();
+        let reloaded = load("test".into(), &dump(&sbf).unwrap()).unwrap();
+        assert_eq!(reloaded.layers(), sbf.layers());
+    }
+
+    #[test]
+    fn test_load_mismatch() {
+        let err = |chunks: &[Chunk]| load("test".into(), chunks).err().unwrap();
+        let chunks = redisbloom_dump();
+        let mut header = Header::decode(&chunks[0].data).unwrap();
+        let with_header = |header: &Header| {
+            let mut chunks = chunks.clone();
+            chunks[0].data = header.encode();
+            chunks
+        };
+        header.options = OPT_NOROUND;
+        assert!(err(&with_header(&header)).contains("32 bits"));
+        header.options = OPT_FORCE64 | OPT_NO_SCALING;
+        assert!(err(&with_header(&header)).contains("NONSCALING"));
+        header.options = OPT_FORCE64;
+        header.growth = 3;
+        assert!(err(&with_header(&header)).contains("expansion 3"));
+        header.growth = 4;
+        header.links[0].n2 = 3;
+        assert!(err(&with_header(&header)).contains("modulo 2^3"));
+        assert!(err(&chunks[1..]).contains("iterator 1"));
+        assert!(err(&chunks[..2]).contains("incomplete"));
+        assert!(err(&[chunks[0].clone(), chunks[2].clone()]).contains("out of order"));
+        assert!(err(&[Chunk {
+            iterator: 1,
+            data: chunks[0].data[..16].to_vec(),
+        }])
+        .contains("not a RedisBloom"));
+        let gxhash = ScalableBloomFilter::new("gx".into(), 100, 0.01, ScaleFactor::SmallScaleSize);
+        assert!(dump(&gxhash).unwrap_err().contains("gxhash"));
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("test.rdb");
+        let sbf = load("test".into(), &redisbloom_dump()).unwrap();
+        export(&sbf, &path).await.unwrap();
+        // Existing files are never overwritten
+        assert!(export(&sbf, &path).await.is_err());
+        let mut imported = import("other".into(), &path).await.unwrap();
+        assert_eq!(imported.name(), "other");
+        assert!(imported.check(b"foo"));
+        assert_eq!(imported.layers(), sbf.layers());
+        let data = fs::read(&path).await.unwrap();
+        fs::write(&path, &data[..data.len() - 1]).await.unwrap();
+        let err = import("test".into(), &path).await.err().unwrap();
+        assert!(err.to_string().contains("truncated chunk"));
+    }
+}
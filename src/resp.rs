@@ -0,0 +1,326 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+// Longest bulk string accepted from a client, the default `proto-max-bulk-len` of Redis
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+// Most arguments accepted in a single command
+const MAX_ARGS: i64 = 1024 * 1024;
+// Longest inline command or header line accepted from a client
+const MAX_LINE_LEN: usize = 64 * 1024;
+// Most bytes reserved ahead for a bulk string not fully received, the buffer grows as the rest
+// of it arrives
+const MAX_RESERVE: usize = 1024 * 1024;
+
+/// Reply sent to a RESP client, encoded according to the protocol version of the connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<Frame>),
+    /// Sent as a flat array of keys and values to RESP2 clients
+    Map(Vec<(Frame, Frame)>),
+}
+
+/// Error reading commands from a RESP client, the connection can't be used anymore
+#[derive(Debug)]
+pub enum RespError {
+    Io(io::Error),
+    Protocol(String),
+}
+
+impl fmt::Display for RespError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RespError::Io(e) => write!(f, "{}", e),
+            RespError::Protocol(message) => write!(f, "Protocol error: {}", message),
+        }
+    }
+}
+
+impl Error for RespError {}
+
+impl From<io::Error> for RespError {
+    fn from(e: io::Error) -> Self {
+        RespError::Io(e)
+    }
+}
+
+/// Codec of the Redis serialization protocol. Commands are read as arrays of bulk strings, the
+/// way client libraries send them, or as inline commands split on spaces, the way `telnet`
+/// sends them. Replies are written in RESP2 until the client switches to RESP3 with `HELLO`.
+#[derive(Debug)]
+pub struct RespCodec {
+    protocol: u8,
+    multibulk: Option<Multibulk>,
+}
+
+// Command array partly received, kept across calls so that the arguments already read aren't
+// parsed again each time more data arrives
+#[derive(Debug)]
+struct Multibulk {
+    args: Vec<Vec<u8>>,
+    remaining: usize,
+    // Position in the buffer of the next bulk string
+    pos: usize,
+}
+
+impl RespCodec {
+    pub fn new() -> Self {
+        RespCodec {
+            protocol: 2,
+            multibulk: None,
+        }
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: u8) {
+        self.protocol = protocol;
+    }
+
+    fn write(&self, frame: &Frame, dst: &mut BytesMut) {
+        match frame {
+            Frame::Simple(s) => write_line(dst, b'+', s),
+            Frame::Error(e) => write_line(dst, b'-', e),
+            Frame::Integer(i) => write_line(dst, b':', &i.to_string()),
+            Frame::Bulk(data) => {
+                write_line(dst, b'$', &data.len().to_string());
+                dst.put_slice(data);
+                dst.put_slice(b"\r\n");
+            }
+            Frame::Array(items) => {
+                write_line(dst, b'*', &items.len().to_string());
+                for item in items {
+                    self.write(item, dst);
+                }
+            }
+            Frame::Map(pairs) => {
+                if self.protocol >= 3 {
+                    write_line(dst, b'%', &pairs.len().to_string());
+                } else {
+                    write_line(dst, b'*', &(pairs.len() * 2).to_string());
+                }
+                for (key, value) in pairs {
+                    self.write(key, dst);
+                    self.write(value, dst);
+                }
+            }
+        }
+    }
+}
+
+impl Default for RespCodec {
+    fn default() -> Self {
+        RespCodec::new()
+    }
+}
+
+// Write a line of the given type, line breaks can't be escaped in simple strings and errors
+fn write_line(dst: &mut BytesMut, kind: u8, line: &str) {
+    dst.put_u8(kind);
+    dst.extend(
+        line.bytes()
+            .map(|b| if b == b'\r' || b == b'\n' { b' ' } else { b }),
+    );
+    dst.put_slice(b"\r\n");
+}
+
+// The line of `src` starting at `start`, without its line break, and the position following it
+fn read_line(src: &[u8], start: usize) -> Option<(&[u8], usize)> {
+    let end = start + src[start..].iter().position(|b| *b == b'\n')?;
+    let line = &src[start..end];
+    Some((line.strip_suffix(b"\r").unwrap_or(line), end + 1))
+}
+
+fn parse_length(line: &[u8]) -> Option<i64> {
+    std::str::from_utf8(line).ok()?.parse().ok()
+}
+
+impl Decoder for RespCodec {
+    type Item = Vec<Vec<u8>>;
+    type Error = RespError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<Vec<u8>>>, RespError> {
+        loop {
+            let mut multibulk = match self.multibulk.take() {
+                Some(multibulk) => multibulk,
+                None => {
+                    if src.is_empty() {
+                        return Ok(None);
+                    }
+                    let (line, pos) = match read_line(src, 0) {
+                        Some(found) => found,
+                        None if src.len() > MAX_LINE_LEN => {
+                            return Err(RespError::Protocol("too big request line".into()))
+                        }
+                        None => return Ok(None),
+                    };
+                    if line.first() != Some(&b'*') {
+                        let args: Vec<Vec<u8>> = line
+                            .split(|b| b.is_ascii_whitespace())
+                            .filter(|arg| !arg.is_empty())
+                            .map(|arg| arg.to_vec())
+                            .collect();
+                        src.advance(pos);
+                        // Empty lines are skipped
+                        if args.is_empty() {
+                            continue;
+                        }
+                        return Ok(Some(args));
+                    }
+                    let count = match parse_length(&line[1..]) {
+                        Some(count) if count <= MAX_ARGS => count.max(0) as usize,
+                        _ => return Err(RespError::Protocol("invalid multibulk length".into())),
+                    };
+                    // Each argument takes at least 4 bytes, no more is reserved than what's
+                    // already been received could hold
+                    Multibulk {
+                        args: Vec::with_capacity(count.min(src.len() / 4)),
+                        remaining: count,
+                        pos,
+                    }
+                }
+            };
+            while multibulk.remaining > 0 {
+                let pos = multibulk.pos;
+                let (line, next) = match read_line(src, pos) {
+                    Some(found) => found,
+                    None if src.len() - pos > MAX_LINE_LEN => {
+                        return Err(RespError::Protocol("too big bulk length line".into()))
+                    }
+                    None => {
+                        self.multibulk = Some(multibulk);
+                        return Ok(None);
+                    }
+                };
+                if line.first() != Some(&b'$') {
+                    return Err(RespError::Protocol(format!(
+                        "expected '$', got '{}'",
+                        line.first().map(|b| *b as char).unwrap_or(' ')
+                    )));
+                }
+                let len = match parse_length(&line[1..]) {
+                    Some(len) if (0..=MAX_BULK_LEN).contains(&len) => len as usize,
+                    _ => return Err(RespError::Protocol("invalid bulk length".into())),
+                };
+                if src.len() < next + len + 2 {
+                    src.reserve((next + len + 2 - src.len()).min(MAX_RESERVE));
+                    self.multibulk = Some(multibulk);
+                    return Ok(None);
+                }
+                if &src[next + len..next + len + 2] != b"\r\n" {
+                    return Err(RespError::Protocol("bulk string not terminated".into()));
+                }
+                multibulk.args.push(src[next..next + len].to_vec());
+                multibulk.pos = next + len + 2;
+                multibulk.remaining -= 1;
+            }
+            src.advance(multibulk.pos);
+            // Empty or null arrays are skipped like empty lines
+            if !multibulk.args.is_empty() {
+                return Ok(Some(multibulk.args));
+            }
+        }
+    }
+}
+
+impl Encoder<Frame> for RespCodec {
+    type Error = RespError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), RespError> {
+        self.write(&frame, dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod resp_tests {
+    use super::*;
+
+    fn decode(codec: &mut RespCodec, data: &[u8]) -> Result<Option<Vec<Vec<u8>>>, RespError> {
+        codec.decode(&mut BytesMut::from(data))
+    }
+
+    fn encode(protocol: u8, frame: Frame) -> Vec<u8> {
+        let mut codec = RespCodec::new();
+        codec.set_protocol(protocol);
+        let mut dst = BytesMut::new();
+        codec.encode(frame, &mut dst).unwrap();
+        dst.to_vec()
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut codec = RespCodec::new();
+        let mut src = BytesMut::from(&b"*3\r\n$6\r\nBF.ADD\r\n$3\r\nfoo\r\n$4\r\na \r\n\r\n"[..]);
+        src.extend_from_slice(b"*3\r\n$9\r\nBF.EXISTS\r\n$3\r\nfoo");
+        let args = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(
+            args,
+            vec![b"BF.ADD".to_vec(), b"foo".to_vec(), b"a \r\n".to_vec()]
+        );
+        // Incomplete commands are left in the buffer until the rest is received
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"\r\n");
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"$1\r\n\xff\r\n");
+        let args = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(
+            args,
+            vec![b"BF.EXISTS".to_vec(), b"foo".to_vec(), vec![0xff]]
+        );
+        assert!(src.is_empty());
+        // Announcing a large bulk string doesn't allocate it all upfront
+        let mut src = BytesMut::from(&b"*1\r\n$536870912\r\n"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert!(src.capacity() <= 2 * MAX_RESERVE);
+        // Nor does announcing many arguments, those received are kept while the rest arrives
+        let mut codec = RespCodec::new();
+        let mut src = BytesMut::from(&b"*1048576\r\n$1\r\na\r\n$1"[..]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        let multibulk = codec.multibulk.as_ref().unwrap();
+        assert!(multibulk.args.capacity() < 16);
+        assert_eq!(multibulk.args, vec![b"a".to_vec()]);
+        assert_eq!(multibulk.pos, 17);
+        let mut codec = RespCodec::new();
+        // Inline commands, empty lines and arrays being skipped
+        let args = decode(&mut codec, b"\r\n*0\r\nPING  hello\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(args, vec![b"PING".to_vec(), b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let mut codec = RespCodec::new();
+        let err = |data: &[u8]| decode(&mut RespCodec::new(), data).unwrap_err().to_string();
+        assert!(err(b"*x\r\n").contains("invalid multibulk length"));
+        assert!(err(b"*1\r\n:1\r\n").contains("expected '$', got ':'"));
+        assert!(err(b"*1\r\n$-1\r\n").contains("invalid bulk length"));
+        assert!(err(b"*1\r\n$1000000000\r\n").contains("invalid bulk length"));
+        assert!(err(b"*1\r\n$1\r\nab\r\n").contains("not terminated"));
+        assert!(err(&[b'a'; MAX_LINE_LEN + 1]).contains("too big request line"));
+        assert!(decode(&mut codec, &[b'a'; MAX_LINE_LEN]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_encode() {
+        let info = Frame::Map(vec![(
+            Frame::Simple("Capacity".into()),
+            Frame::Integer(100),
+        )]);
+        assert_eq!(encode(2, info.clone()), b"*2\r\n+Capacity\r\n:100\r\n");
+        assert_eq!(encode(3, info), b"%1\r\n+Capacity\r\n:100\r\n");
+        let frame = Frame::Array(vec![Frame::Bulk(b"a\r\n".to_vec()), Frame::Integer(-1)]);
+        assert_eq!(encode(2, frame), b"*2\r\n$3\r\na\r\n\r\n:-1\r\n");
+        let error = Frame::Error("ERR two\nlines".into());
+        assert_eq!(encode(2, error), b"-ERR two lines\r\n");
+    }
+}
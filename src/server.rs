@@ -1,29 +1,70 @@
-use crate::filter::{ScalableBloomFilter, ScaleFactor, DEFAULT_DATA_DIR};
-use crate::AsyncResult;
+use crate::aof::{AppendOnlyLog, FsyncPolicy, Operation, AOF_FILENAME};
+use crate::backup::{self, BackupConfig};
+use crate::binary::{
+    BinaryCodec, BinaryResponse, Operation as BinaryOperation, Reply, BINARY_HANDSHAKE,
+};
+use crate::bitmap::{BitmapStorage, BITMAP_EXTENSION};
+use crate::crypto::Key;
+use crate::filter::{self, FilterFiles, ScalableBloomFilter, ScaleFactor, FILTER_EXTENSION};
+use crate::grpc::{self, rublo_server::RubloServer};
+use crate::listener::Connection;
+pub use crate::listener::Listener;
+use crate::resp::{Frame, RespCodec};
+use crate::snapshot;
+use crate::storage::{self, Compression, TMP_EXTENSION};
+use crate::{guava, pybloom, redisbloom};
+use crate::{AsyncResult, Config};
+use bytes::BufMut;
 use chrono::{DateTime, NaiveDateTime, Utc};
-use futures::SinkExt;
+use futures::{SinkExt, Stream};
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, StatusCode};
 use log::{error, info};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::convert::TryFrom;
+use std::ffi::OsStr;
 use std::fmt;
-use std::ops::DerefMut;
-use std::path::Path;
+use std::io::ErrorKind;
+use std::ops::{Deref, DerefMut};
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
 use std::result::Result;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::{collections::HashMap, collections::HashSet, net::SocketAddr};
 use tokio::fs;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{Framed, LinesCodec};
+use tonic::{Status, Streaming};
 
 // Fixed size exponential backoff value
 const BACKOFF: u64 = 128;
-// Dump to disk seconds interval
-const DUMP_INTERVAL: u64 = 60;
+// Interval in seconds to check save rules against the filters
+const SAVE_CHECK_INTERVAL: u64 = 1;
 // Interval to check for cold filters
 const DUMP_COLD_INTERVAL: u64 = 5;
+// Interval to sync the append-only log with the `EverySec` policy
+const AOF_SYNC_INTERVAL: u64 = 1;
 // Default timeout to declare a filter cold in seconds
 const COLD_FILTER_TIMEOUT: i64 = 3600;
+// Maximum delay in seconds between two attempts to evict a filter that can't be written
+const EVICTION_MAX_BACKOFF: u64 = 300;
+// Subdirectory of the data directory where unreadable filter files are moved
+const QUARANTINE_DIR: &str = "quarantine";
+// Time appended to the names of the quarantined files
+const QUARANTINE_SUFFIX_FORMAT: &str = "%Y%m%dT%H%M%S%.6fZ";
+// File of the data directory recording where the filters stored elsewhere are
+const LOCATIONS_FILENAME: &str = "locations.yaml";
+// Directory the filters are exported to and imported from when not configured, under the data
+// directory
+pub const DEFAULT_INTEROP_DIR: &str = "interop";
 // Base capacity for each new filter, if not specified
 const DEFAULT_CAPACITY: &str = "50000";
 // Base false positive probability for each new filter, if not specified otherwise
@@ -41,34 +82,47 @@ impl fmt::Display for ParserError {
 }
 
 /// Text protocol declaration, currently supports basic commands such as:
-/// - Create filter-name [capacity] [fpp]
+/// - Create filter-name [capacity] [fpp] [heap|mmap] [dir=volume]
 /// - Set filter-name key
 /// - Check filter-name key
 /// - Info filter-name
-/// - Drop filter-name
+/// - Drop filter-name [keep-data]
 /// - Clear filter-name
 /// - Persist filter-name
+/// - Close filter-name
+/// - Load filter-name
 /// - List
+/// - Stats
+/// - Snapshot path
+/// - Restore path
+/// - LastSave
+/// - Backups filter-name
+/// - Rollback filter-name timestamp
+/// - Export filter-name file-name
+/// - Import filter-name file-name [redisbloom|guava|pybloom]
 #[derive(Debug, PartialEq)]
 enum Request {
     Create {
         name: String,
         capacity: usize,
         fpp: f64,
+        storage: BitmapStorage,
+        dir: Option<PathBuf>,
     },
     Set {
         name: String,
-        key: String,
+        key: Vec<u8>,
     },
     Check {
         name: String,
-        key: String,
+        key: Vec<u8>,
     },
     Info {
         name: String,
     },
     Drop {
         name: String,
+        keep_data: bool,
     },
     Clear {
         name: String,
@@ -76,13 +130,70 @@ enum Request {
     Persist {
         name: String,
     },
+    Close {
+        name: String,
+    },
+    Load {
+        name: String,
+    },
     List,
+    Stats,
+    Snapshot {
+        path: PathBuf,
+    },
+    Restore {
+        path: PathBuf,
+    },
+    LastSave,
+    Backups {
+        name: String,
+    },
+    Rollback {
+        name: String,
+        time: DateTime<Utc>,
+    },
+    Export {
+        name: String,
+        path: PathBuf,
+    },
+    Import {
+        name: String,
+        path: PathBuf,
+        format: ImportFormat,
+    },
+}
+
+/// Library a filter file to import was written by
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ImportFormat {
+    RedisBloom,
+    Guava,
+    Pybloom,
 }
 
 struct FilterProps {
     pub name: String,
-    pub fpp: f64,
-    pub capacity: usize,
+    pub fpp: Option<f64>,
+    pub capacity: Option<usize>,
+    pub size: Option<usize>,
+    pub residency: Residency,
+    pub last_access_time: DateTime<Utc>,
+}
+
+/// Whether a filter is in memory or only on disk
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Residency {
+    Warm,
+    Cold,
+}
+
+impl fmt::Display for Residency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Residency::Warm => write!(f, "warm"),
+            Residency::Cold => write!(f, "cold"),
+        }
+    }
 }
 
 enum Response {
@@ -92,19 +203,42 @@ enum Response {
     Info {
         name: String,
         capacity: usize,
+        fpp: f64,
         size: usize,
         space: String,
+        disk_space: u64,
         filters: u32,
+        scale_factor: ScaleFactor,
         hash_count: u32,
         hits: u64,
         miss: u64,
         creation_time: String,
         last_access_time: String,
+        changes: u64,
+        storage: BitmapStorage,
+        eviction_failures: u32,
+        eviction_error: Option<String>,
+    },
+    Stats {
+        warm: usize,
+        cold: usize,
+        evictions: u64,
+        eviction_failures: u64,
+        failing_evictions: usize,
     },
     Error(String),
     List {
         filters: Vec<FilterProps>,
     },
+    LastSave(Option<DateTime<Utc>>),
+    Backups(Vec<DateTime<Utc>>),
+}
+
+/// Validate a filter name, see `filter::validate_name`
+fn parse_name(name: &str) -> Result<String, ParserError> {
+    filter::validate_name(name)
+        .map(|()| name.to_string())
+        .map_err(|message| ParserError { message })
 }
 
 impl Request {
@@ -117,7 +251,7 @@ impl Request {
                     .ok_or(ParserError {
                         message: "missing name".into(),
                     })
-                    .map(|s| s.to_string())?;
+                    .and_then(parse_name)?;
                 let capacity = token
                     .next()
                     .or(Some(DEFAULT_CAPACITY))
@@ -136,10 +270,27 @@ impl Request {
                         })
                     })
                     .unwrap()?;
+                let (mut storage, mut dir) = (BitmapStorage::Heap, None);
+                for option in token {
+                    match option {
+                        "heap" => storage = BitmapStorage::Heap,
+                        "mmap" => storage = BitmapStorage::Mapped,
+                        o if o.starts_with("dir=") && o.len() > 4 => {
+                            dir = Some(PathBuf::from(&o[4..]))
+                        }
+                        _ => {
+                            return Err(ParserError {
+                                message: "options must be heap, mmap or dir=volume".into(),
+                            })
+                        }
+                    }
+                }
                 Ok(Request::Create {
                     name,
                     capacity,
                     fpp,
+                    storage,
+                    dir,
                 })
             }
             Some(c) if c == "set" => {
@@ -148,13 +299,13 @@ impl Request {
                     .ok_or(ParserError {
                         message: "missing name".into(),
                     })
-                    .map(|s| s.to_string())?;
+                    .and_then(parse_name)?;
                 let key = token
                     .next()
                     .ok_or(ParserError {
                         message: "missing key".into(),
                     })
-                    .map(|s| s.to_string())?;
+                    .map(|s| s.as_bytes().to_vec())?;
                 Ok(Request::Set { name, key })
             }
             Some(c) if c == "check" => {
@@ -163,13 +314,13 @@ impl Request {
                     .ok_or(ParserError {
                         message: "missing name".into(),
                     })
-                    .map(|s| s.to_string())?;
+                    .and_then(parse_name)?;
                 let key = token
                     .next()
                     .ok_or(ParserError {
                         message: "missing key".into(),
                     })
-                    .map(|s| s.to_string())?;
+                    .map(|s| s.as_bytes().to_vec())?;
                 Ok(Request::Check { name, key })
             }
             Some(c) if c == "info" => {
@@ -178,7 +329,7 @@ impl Request {
                     .ok_or(ParserError {
                         message: "missing filter name".into(),
                     })
-                    .map(|s| s.to_string())?;
+                    .and_then(parse_name)?;
                 Ok(Request::Info { name })
             }
             Some(c) if c == "drop" => {
@@ -187,8 +338,17 @@ impl Request {
                     .ok_or(ParserError {
                         message: "missing filter name".into(),
                     })
-                    .map(|s| s.to_string())?;
-                Ok(Request::Drop { name })
+                    .and_then(parse_name)?;
+                let keep_data = match token.next() {
+                    None => false,
+                    Some("keep-data") => true,
+                    Some(_) => {
+                        return Err(ParserError {
+                            message: "unknown drop option, only keep-data is supported".into(),
+                        })
+                    }
+                };
+                Ok(Request::Drop { name, keep_data })
             }
             Some(c) if c == "clear" => {
                 let name = token
@@ -196,7 +356,7 @@ impl Request {
                     .ok_or(ParserError {
                         message: "missing filter name".into(),
                     })
-                    .map(|s| s.to_string())?;
+                    .and_then(parse_name)?;
                 Ok(Request::Clear { name })
             }
             Some(c) if c == "persist" => {
@@ -205,10 +365,104 @@ impl Request {
                     .ok_or(ParserError {
                         message: "missing filter name".into(),
                     })
-                    .map(|s| s.to_string())?;
+                    .and_then(parse_name)?;
                 Ok(Request::Persist { name })
             }
+            Some(c) if c == "close" => {
+                let name = token
+                    .next()
+                    .ok_or(ParserError {
+                        message: "missing filter name".into(),
+                    })
+                    .and_then(parse_name)?;
+                Ok(Request::Close { name })
+            }
+            Some(c) if c == "load" => {
+                let name = token
+                    .next()
+                    .ok_or(ParserError {
+                        message: "missing filter name".into(),
+                    })
+                    .and_then(parse_name)?;
+                Ok(Request::Load { name })
+            }
             Some(c) if c == "list" => Ok(Request::List),
+            Some(c) if c == "stats" => Ok(Request::Stats),
+            Some(c) if c == "snapshot" => {
+                let path = token
+                    .next()
+                    .ok_or(ParserError {
+                        message: "missing snapshot path".into(),
+                    })
+                    .map(PathBuf::from)?;
+                Ok(Request::Snapshot { path })
+            }
+            Some(c) if c == "restore" => {
+                let path = token
+                    .next()
+                    .ok_or(ParserError {
+                        message: "missing snapshot path".into(),
+                    })
+                    .map(PathBuf::from)?;
+                Ok(Request::Restore { path })
+            }
+            Some(c) if c == "lastsave" => Ok(Request::LastSave),
+            Some(c) if c == "backups" => {
+                let name = token
+                    .next()
+                    .ok_or(ParserError {
+                        message: "missing filter name".into(),
+                    })
+                    .and_then(parse_name)?;
+                Ok(Request::Backups { name })
+            }
+            Some(c) if c == "rollback" => {
+                let name = token
+                    .next()
+                    .ok_or(ParserError {
+                        message: "missing filter name".into(),
+                    })
+                    .and_then(parse_name)?;
+                let time = token
+                    .next()
+                    .ok_or(ParserError {
+                        message: "missing backup timestamp".into(),
+                    })
+                    .and_then(|t| {
+                        backup::parse_timestamp(t).ok_or(ParserError {
+                            message: format!("invalid backup timestamp {}", t),
+                        })
+                    })?;
+                Ok(Request::Rollback { name, time })
+            }
+            Some(c) if c == "export" || c == "import" => {
+                let name = token
+                    .next()
+                    .ok_or(ParserError {
+                        message: "missing filter name".into(),
+                    })
+                    .and_then(parse_name)?;
+                let path = token
+                    .next()
+                    .ok_or(ParserError {
+                        message: format!("missing {} path", c),
+                    })
+                    .map(PathBuf::from)?;
+                if c == "export" {
+                    return Ok(Request::Export { name, path });
+                }
+                let format = match token.next() {
+                    None | Some("redisbloom") => ImportFormat::RedisBloom,
+                    Some("guava") => ImportFormat::Guava,
+                    Some("pybloom") => ImportFormat::Pybloom,
+                    Some(f) => {
+                        return Err(ParserError {
+                            message: format!("unknown import format {}", f),
+                        })
+                    }
+                };
+                Ok(Request::Import { name, path, format })
+            }
             Some(_) => Err(ParserError {
                 message: "unknown command".into(),
             }),
@@ -231,18 +485,61 @@ impl Response {
                 capacity,
                 size,
                 space,
+                disk_space,
                 filters,
                 hash_count,
                 hits,
                 miss,
                 creation_time,
                 last_access_time,
+                changes,
+                storage,
+                eviction_failures,
+                eviction_error,
+                ..
+            } => {
+                let mut info = format!(
+                    "name: {}\ncapacity: {}\nsize: {}\nspace: {}\ndisk space: {}\nfilters: {}\nhash functions: {}\nhits: {}\nmiss: {}\ncreation: {}\nlast access: {}\nunsaved changes: {}\nstorage: {}\neviction failures: {}",
+                    name, capacity, size, space, disk_space, filters, hash_count, hits, miss, creation_time, last_access_time, changes, storage, eviction_failures
+                );
+                if let Some(e) = eviction_error {
+                    info.push_str(&format!("\nlast eviction error: {}", e));
+                }
+                info
+            }
+            Response::Stats {
+                warm,
+                cold,
+                evictions,
+                eviction_failures,
+                failing_evictions,
             } => format!(
-                "name: {}\ncapacity: {}\nsize: {}\nspace: {}\nfilters: {}\nhash functions: {}\nhits: {}\nmiss: {}\ncreation: {}\nlast access: {}",
-                name, capacity, size, space, filters, hash_count, hits, miss, creation_time, last_access_time
+                "warm filters: {}\ncold filters: {}\nevictions: {}\neviction failures: {}\nfailing evictions: {}",
+                warm, cold, evictions, eviction_failures, failing_evictions
             ),
+            Response::LastSave(Some(time)) => time.to_rfc3339(),
+            Response::LastSave(None) => "never".into(),
+            Response::Backups(versions) => {
+                let tostr: Vec<String> = versions.iter().map(backup::format_timestamp).collect();
+                tostr.join("\n")
+            }
             Response::List { filters } => {
-                let tostr: Vec<String> = filters.iter().map(|x| format!("{} {} {}", x.name, x.capacity, x.fpp)).collect();
+                // The name, capacity and fpp columns come first as they always did, followed by
+                // the residency and last access. The capacity and fpp of cold filters not loaded
+                // since startup are unknown, reported as 0.
+                let tostr: Vec<String> = filters
+                    .iter()
+                    .map(|x| {
+                        format!(
+                            "{} {} {} {} {}",
+                            x.name,
+                            x.capacity.unwrap_or(0),
+                            x.fpp.unwrap_or(0.),
+                            x.residency,
+                            x.last_access_time.to_rfc3339()
+                        )
+                    })
+                    .collect();
                 tostr.join("\n")
             }
             Response::Error(message) => format!("Error: {}", message),
@@ -252,7 +549,9 @@ impl Response {
 
 #[cfg(test)]
 mod tests {
-    use super::{ParserError, Request};
+    use super::*;
+    use crate::storage::HashAlgorithm;
+    use tokio::net::{TcpListener, TcpStream};
 
     #[test]
     fn test_parse() -> Result<(), ParserError> {
@@ -261,15 +560,41 @@ mod tests {
             Request::Create {
                 name: "foo".into(),
                 capacity: 5,
-                fpp: 0.01
+                fpp: 0.01,
+                storage: BitmapStorage::Heap,
+                dir: None
+            }
+        );
+        assert_eq!(
+            Request::parse("create foo 5 0.01 mmap")?,
+            Request::Create {
+                name: "foo".into(),
+                capacity: 5,
+                fpp: 0.01,
+                storage: BitmapStorage::Mapped,
+                dir: None
+            }
+        );
+        assert_eq!(
+            Request::parse("create foo 5 0.01 dir=/mnt/big mmap")?,
+            Request::Create {
+                name: "foo".into(),
+                capacity: 5,
+                fpp: 0.01,
+                storage: BitmapStorage::Mapped,
+                dir: Some(PathBuf::from("/mnt/big"))
             }
         );
+        assert!(Request::parse("create foo 5 0.01 dir=").is_err());
+        assert!(Request::parse("create foo 5 0.01 disk").is_err());
         assert_eq!(
             Request::parse("create foo")?,
             Request::Create {
                 name: "foo".into(),
                 capacity: 50000,
-                fpp: 0.05
+                fpp: 0.05,
+                storage: BitmapStorage::Heap,
+                dir: None
             }
         );
         assert_eq!(
@@ -288,377 +613,4331 @@ mod tests {
         );
         assert_eq!(
             Request::parse("drop foo")?,
-            Request::Drop { name: "foo".into() }
+            Request::Drop {
+                name: "foo".into(),
+                keep_data: false
+            }
+        );
+        assert_eq!(
+            Request::parse("drop foo keep-data")?,
+            Request::Drop {
+                name: "foo".into(),
+                keep_data: true
+            }
+        );
+        assert_eq!(
+            Request::parse("close foo")?,
+            Request::Close { name: "foo".into() }
+        );
+        assert_eq!(
+            Request::parse("export foo foo.rdb")?,
+            Request::Export {
+                name: "foo".into(),
+                path: "foo.rdb".into()
+            }
+        );
+        assert_eq!(
+            Request::parse("import foo foo.rdb")?,
+            Request::Import {
+                name: "foo".into(),
+                path: "foo.rdb".into(),
+                format: ImportFormat::RedisBloom
+            }
+        );
+        assert_eq!(
+            Request::parse("import foo foo.bin guava")?,
+            Request::Import {
+                name: "foo".into(),
+                path: "foo.bin".into(),
+                format: ImportFormat::Guava
+            }
+        );
+        assert_eq!(
+            Request::parse("load foo")?,
+            Request::Load { name: "foo".into() }
         );
+        assert!(Request::parse("load").is_err());
+        assert!(Request::parse("drop foo keep").is_err());
         let r = Request::parse("create foo bar 0.01").map_err(|e| e);
         assert!(r.is_err());
         Ok(())
     }
-}
-
-struct FilterDatabase {
-    pub filters: HashMap<String, ScalableBloomFilter>,
-    pub cold_filters: HashSet<String>,
-}
 
-/// Shared state between multiple connections, the filter manager to track and
-/// update multiple scalable filters.
-///
-/// Being shared it's wrapped as an atomic counter reference (Arc) guarded by a mutex.
-type FilterDb = Arc<Mutex<FilterDatabase>>;
+    #[test]
+    fn test_parse_malicious_names() {
+        let long = "a".repeat(129);
+        for name in [
+            "../../etc/foo",
+            "/etc/passwd",
+            "foo/bar",
+            "foo\\bar",
+            "..",
+            ".hidden",
+            "foo%2Fbar",
+            "f\u{0}oo",
+            "fïlter",
+            long.as_str(),
+        ]
+        .iter()
+        {
+            for command in ["create", "set", "check", "info", "drop", "clear", "persist"].iter() {
+                let err = Request::parse(&format!("{} {} key", command, name)).unwrap_err();
+                assert!(err.message.starts_with("invalid filter name"));
+            }
+        }
+        assert!(Request::parse("create ").is_err());
+        assert!(Request::parse(&format!("create {}", "a".repeat(128))).is_ok());
+        assert!(Request::parse("create site-hits:2021_v1.2").is_ok());
+    }
 
-/// Server listener state. Created in the `run` call. It includes a `run` method
-/// which performs the TCP listening and initialization of per-connection state.
-struct Server {
-    listener: TcpListener,
-    /// Tcp exponential backoff threshold
-    backoff: u64,
-    /// Filter manager map
-    db: FilterDb,
-}
+    fn test_database(dir: &Path) -> FilterDatabase {
+        FilterDatabase {
+            filters: HashMap::new(),
+            cold_filters: HashMap::new(),
+            aof: None,
+            compression: Compression::None,
+            key: None,
+            data_dir: dir.to_path_buf(),
+            locations: HashMap::new(),
+            volumes: HashMap::new(),
+            eviction_failures: HashMap::new(),
+            stats: Stats::default(),
+            last_snapshot: None,
+            snapshot_dir: dir.join(snapshot::DEFAULT_SNAPSHOT_DIR),
+            interop_dir: dir.join(DEFAULT_INTEROP_DIR),
+            backup_dir: None,
+            unbacked: HashSet::new(),
+        }
+    }
 
-impl Server {
-    /// Init the shared database object by reading the disk at the default path for filters stored
-    /// and put them into memory.
-    ///
-    /// # Errors
-    ///
-    /// Returns `Err` if anything wrong happens while reading from disk and deserializing memory
-    /// maps into memory.
-    pub async fn init(&mut self) -> AsyncResult<()> {
-        let mut db = self.db.lock().await;
-        let mut entries = fs::read_dir(DEFAULT_DATA_DIR).await?;
-        info!("scanning {}/ for persistent filters", DEFAULT_DATA_DIR);
-        while let Some(entry) = entries.next_entry().await? {
-            if let Ok(path) = entry.path().into_os_string().into_string() {
-                let filter = ScalableBloomFilter::from_file(&path).await?;
-                info!("found persistent filter {}", filter);
-                db.filters.insert(filter.name().clone(), filter);
+    #[tokio::test]
+    async fn test_evict_filter_failure() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut db = test_database(dir);
+        // The directory of the filter file doesn't exist, so writing it fails
+        let name = format!("missing-{}/foo", std::process::id());
+        let sbf = ScalableBloomFilter::new(name.clone(), 5, 0.01, ScaleFactor::SmallScaleSize);
+        db.filters.insert(name.clone(), sbf);
+        evict_filter(&mut db, &name, 1000).await;
+        assert!(db.filters.contains_key(&name));
+        assert!(!db.cold_filters.contains_key(&name));
+        let failure = db.eviction_failures.get(&name).unwrap();
+        assert_eq!(failure.attempts, 1);
+        assert_eq!(failure.retry_at, 1000 + DUMP_COLD_INTERVAL as i64);
+        evict_filter(&mut db, &name, 2000).await;
+        let failure = db.eviction_failures.get(&name).unwrap();
+        assert_eq!(failure.attempts, 2);
+        assert_eq!(failure.retry_at, 2000 + 2 * DUMP_COLD_INTERVAL as i64);
+        assert_eq!(db.stats.eviction_failures, 2);
+        assert_eq!(db.stats.evictions, 0);
+        match get_filter_info(&db.filters[&name], db.eviction_failures.get(&name)).await {
+            Response::Info {
+                eviction_failures,
+                eviction_error,
+                ..
+            } => {
+                assert_eq!(eviction_failures, 2);
+                assert!(eviction_error.is_some());
             }
+            _ => panic!("expected info"),
         }
-        Ok(())
+        assert_eq!(eviction_backoff(1), DUMP_COLD_INTERVAL as i64);
+        assert_eq!(eviction_backoff(64), EVICTION_MAX_BACKOFF as i64);
     }
 
-    /// Create a new Server and run.
-    ///
-    /// Listen for inbound connections. For each inbound connection, spawn a
-    /// task to process that connection.
-    ///
-    /// # Errors
-    ///
-    /// Returns `Err` if accepting returns an error. This can happen for a
-    /// number reasons that resolve over time. For example, if the underlying
-    /// operating system has reached an internal limit for max number of
-    /// sockets, accept will fail.
-    pub async fn run(&mut self) -> AsyncResult<()> {
-        // Create a clone reference of the filters database to be used by the dump worker
-        let db = self.db.clone();
-        // Spawn a new task to dump every filter to disk
-        tokio::spawn(async move {
-            if let Err(e) = dump_to_disk(&db, DUMP_INTERVAL).await {
-                error!("Can't spawn `dump_to_disk` worker: {:?}", e);
-            }
-        });
-        let db = self.db.clone();
-        // And another one to keep only warm filters in memory
-        tokio::spawn(async move {
-            if let Err(e) = dump_cold_filters(&db, DUMP_COLD_INTERVAL).await {
-                error!("Can't spawn `dump_cold_filters` worker: {:?}", e);
+    async fn request(db: &FilterDb, line: &str) -> String {
+        handle_request(line, db).await.serialize()
+    }
+
+    // Bring the filter named foo into the given residency
+    async fn set_residency(db: &FilterDb, residency: Residency) {
+        let line = match residency {
+            Residency::Warm => "load foo",
+            Residency::Cold => "close foo",
+        };
+        assert_eq!(request(db, line).await, "Done");
+        let db = db.lock().await;
+        assert_eq!(db.filters.contains_key("foo"), residency == Residency::Warm);
+        assert_eq!(
+            db.cold_filters.contains_key("foo"),
+            residency == Residency::Cold
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commands_warm_and_cold() {
+        for residency in [Residency::Warm, Residency::Cold].iter().copied() {
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = tmp.path();
+            let db = Arc::new(Mutex::new(test_database(dir)));
+            assert_eq!(request(&db, "create foo 50 0.01").await, "Done");
+            assert_eq!(request(&db, "set foo a").await, "Done");
+
+            set_residency(&db, residency).await;
+            assert_eq!(request(&db, "set foo b").await, "Done");
+            assert_eq!(request(&db, "check foo b").await, "True");
+
+            set_residency(&db, residency).await;
+            assert_eq!(request(&db, "check foo a").await, "True");
+            set_residency(&db, residency).await;
+            assert_eq!(request(&db, "check foo z").await, "False");
+
+            set_residency(&db, residency).await;
+            assert!(request(&db, "info foo").await.starts_with("name: foo\n"));
+            // Info doesn't change the residency
+            assert_eq!(
+                db.lock().await.cold_filters.contains_key("foo"),
+                residency == Residency::Cold
+            );
+
+            set_residency(&db, residency).await;
+            let list = request(&db, "list").await;
+            assert!(list.starts_with("foo "));
+            assert!(list.contains(&format!(" 0.01 {} ", residency)));
+
+            set_residency(&db, residency).await;
+            assert_eq!(request(&db, "persist foo").await, "Done");
+            assert!(dir.join("foo.rbl").exists());
+
+            set_residency(&db, residency).await;
+            assert_eq!(request(&db, "clear foo").await, "Done");
+            assert_eq!(request(&db, "check foo a").await, "False");
+            assert_eq!(request(&db, "set foo a").await, "Done");
+
+            // Creating an existing filter leaves it untouched
+            set_residency(&db, residency).await;
+            assert_eq!(request(&db, "create foo 50 0.01").await, "Done");
+            assert_eq!(request(&db, "check foo a").await, "True");
+
+            set_residency(&db, residency).await;
+            assert_eq!(request(&db, "drop foo keep-data").await, "Done");
+            assert!(!db.lock().await.contains("foo"));
+            assert!(dir.join("foo.rbl").exists());
+            assert!(request(&db, "check foo a").await.starts_with("Error"));
+
+            assert_eq!(request(&db, "create foo 50 0.01").await, "Done");
+            set_residency(&db, residency).await;
+            assert_eq!(request(&db, "drop foo").await, "Done");
+            assert!(!db.lock().await.contains("foo"));
+            assert!(!dir.join("foo.rbl").exists());
+            for line in [
+                "set foo a",
+                "check foo a",
+                "info foo",
+                "clear foo",
+                "persist foo",
+            ]
+            .iter()
+            {
+                assert!(request(&db, line)
+                    .await
+                    .starts_with("Error: no scalable filter"));
             }
-        });
-        // Loop forever on new connections, accept them and pass the handling
-        // to a worker.
-        loop {
-            // Accepts a new connection, obtaining a valid socket.
-            let (stream, peer) = self.accept().await?;
-            info!("connection from {}", peer.to_string());
-            // Create a clone reference of the filters database to be used by this connection.
-            let db = self.db.clone();
-            // Spawn a new task to process the connection, moving the ownership of the cloned
-            // db into the async closure.
-            tokio::spawn(async move {
-                // The protocol is line-based, `LinesCodec` is useful to automatically handle
-                // this by converting the stream of bytes into a stream of lines.
-                let mut lines = Framed::new(stream, LinesCodec::new());
-                // Parse each line returned by the codec and by leveraging `LinesCodec` once again
-                // send a response back to the client.
-                while let Some(result) = lines.next().await {
-                    match result {
-                        Ok(line) => {
-                            let response = handle_request(&line, &db).await;
-                            let response = response.serialize();
-                            if let Err(e) = lines.send(response.as_str()).await {
-                                println!("error sending response: {:?}", e);
-                            }
-                        }
-                        Err(e) => {
-                            println!("error on deconding from stream: {:?}", e);
-                        }
-                    }
-                }
-                info!("connection closed by client");
-            });
         }
     }
 
-    /// Accept an inbound connection.
-    ///
-    /// Errors are handled by backing off and retrying. An exponential backoff
-    /// strategy is used. After the first failure, the task waits for 1 second.
-    /// After the second failure, the task waits for 2 seconds. Each subsequent
-    /// failure doubles the wait time. If accepting fails on the 6th try after
-    /// waiting for 64 seconds, then this function returns with an error.
-    async fn accept(&mut self) -> AsyncResult<(TcpStream, SocketAddr)> {
-        let mut backoff = 1;
-
-        // Try to accept a few times
-        loop {
-            // Perform the accept operation. If a socket is successfully
-            // accepted, return it. Otherwise, save the error.
-            match self.listener.accept().await {
-                Ok((socket, peer)) => return Ok((socket, peer)),
-                Err(err) => {
-                    if backoff > self.backoff {
-                        // Accept has failed too many times. Return the error.
-                        return Err(err.into());
-                    }
-                }
-            }
+    #[tokio::test]
+    async fn test_encryption_at_rest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut database = test_database(dir);
+        database.key = Some(Key::from_hex(&"2a".repeat(crate::crypto::KEY_SIZE)).unwrap());
+        let db = Arc::new(Mutex::new(database));
+        assert_eq!(request(&db, "create pii").await, "Done");
+        assert_eq!(request(&db, "set pii alice@example.com").await, "Done");
+        assert_eq!(request(&db, "close pii").await, "Done");
+        let data = fs::read(dir.join("pii.rbl")).await.unwrap();
+        let header = storage::Header::parse(&data).unwrap().unwrap();
+        assert_ne!(header.flags & storage::FLAG_ENCRYPTED, 0);
+        assert_eq!(request(&db, "check pii alice@example.com").await, "True");
+        assert_eq!(
+            request(&db, "create big 1000 0.01 mmap").await,
+            "Error: mmap storage is unavailable with encryption at rest"
+        );
+        // Without the key the filter can't be loaded
+        assert_eq!(request(&db, "close pii").await, "Done");
+        db.lock().await.key = None;
+        assert!(request(&db, "check pii alice@example.com")
+            .await
+            .contains("no encryption key"));
+        // Memory-mapped bitmaps of filters created beforehand aren't restored in plaintext
+        assert_eq!(request(&db, "create big 1000 0.01 mmap").await, "Done");
+        assert_eq!(request(&db, "set big a").await, "Done");
+        assert_eq!(request(&db, "snapshot big.rbs").await, "Done");
+        fs::create_dir_all(dir.join("target")).await.unwrap();
+        let mut database = test_database(&dir.join("target"));
+        database.key = Some(Key::from_hex(&"2a".repeat(crate::crypto::KEY_SIZE)).unwrap());
+        database.snapshot_dir = dir.join(snapshot::DEFAULT_SNAPSHOT_DIR);
+        let db = Arc::new(Mutex::new(database));
+        assert!(request(&db, "restore big.rbs")
+            .await
+            .contains("filter big uses mmap storage"));
+        assert!(!dir.join("target").join("big.rbl").exists());
+    }
 
-            // Pause execution until the back off period elapses.
-            sleep(Duration::from_secs(backoff)).await;
+    #[tokio::test]
+    async fn test_close_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        for line in &["close foo", "load foo"] {
+            assert_eq!(
+                request(&db, line).await,
+                "Error: no scalable filter named foo"
+            );
+        }
+        assert_eq!(request(&db, "create foo 50 0.01").await, "Done");
+        assert_eq!(request(&db, "set foo a").await, "Done");
+        // Closing writes the pending changes before unloading the filter
+        assert_eq!(request(&db, "close foo").await, "Done");
+        assert!(!db.lock().await.filters.contains_key("foo"));
+        let restored = ScalableBloomFilter::from_file(dir.join("foo.rbl").to_str().unwrap(), None)
+            .await
+            .unwrap();
+        let list = request(&db, "list").await;
+        let columns: Vec<&str> = list.split(' ').collect();
+        assert_eq!(
+            columns[..4],
+            ["foo", &restored.capacity().to_string(), "0.01", "cold"]
+        );
+        assert!(DateTime::parse_from_rfc3339(columns[4]).is_ok());
+        // Closing twice or loading a warm filter changes nothing
+        assert_eq!(request(&db, "close foo").await, "Done");
+        assert_eq!(request(&db, "load foo").await, "Done");
+        assert_eq!(request(&db, "load foo").await, "Done");
+        assert!(db.lock().await.filters.contains_key("foo"));
+        assert!(request(&db, "list").await.contains(" 0.01 warm "));
+        assert_eq!(request(&db, "check foo a").await, "True");
+    }
 
-            // Double the back off
-            backoff *= 2;
+    #[tokio::test]
+    async fn test_lazy_loading() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        for name in &["hot", "lazy"] {
+            assert_eq!(
+                request(&db, &format!("create {} 50 0.01", name)).await,
+                "Done"
+            );
+            assert_eq!(request(&db, &format!("set {} a", name)).await, "Done");
+            assert_eq!(request(&db, &format!("close {}", name)).await, "Done");
+        }
+        // A restart only indexes the filters, apart from the preloaded ones
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        let mut server = Server {
+            listener: TcpListener::bind("127.0.0.1:0").await.unwrap().into(),
+            unix_listener: None,
+            resp_listener: None,
+            bloomd_listener: None,
+            http_listener: None,
+            grpc_listener: None,
+            backoff: BACKOFF,
+            db: db.clone(),
+            config: Config {
+                data_dir: dir.to_path_buf(),
+                preload: vec!["hot".into(), "missing".into()],
+                ..Config::default()
+            },
+        };
+        server.init().await.unwrap();
+        {
+            let db = db.lock().await;
+            assert!(db.filters.contains_key("hot"));
+            assert!(!db.cold_filters.contains_key("hot"));
+            assert!(!db.filters.contains_key("lazy"));
+            let cold = &db.cold_filters["lazy"];
+            assert_eq!((cold.capacity, cold.fpp), (None, None));
         }
+        // Cold filters are listed without being loaded
+        let list = request(&db, "list").await;
+        let lines: Vec<&str> = list.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("hot ") && lines[0].contains(" 0.01 warm "));
+        assert!(lines[1].starts_with("lazy 0 0 cold "));
+        assert!(!db.lock().await.filters.contains_key("lazy"));
+        // And loaded on first access
+        assert_eq!(request(&db, "check lazy a").await, "True");
+        assert!(db.lock().await.filters.contains_key("lazy"));
+        let list = request(&db, "list").await;
+        assert!(list.lines().nth(1).unwrap().contains(" 0.01 warm "));
     }
-}
 
-/// Write to disk every scalable filter in the database every `interval` seconds, meant to run
-/// as a tokio task
-async fn dump_to_disk(db: &FilterDb, interval: u64) -> AsyncResult<()> {
-    loop {
-        // Sleep for a defined timeout
-        sleep(Duration::from_secs(interval)).await;
-        let db = db.lock().await;
-        for (_, v) in db.filters.iter() {
-            match v.to_file().await {
-                Ok(()) => info!("{} filter dumped to disk", v),
-                Err(e) => error!("{} filter dump error: {:?}", v, e),
+    #[tokio::test]
+    async fn test_compact_append_only_log() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut database = test_database(dir);
+        let path = dir.join(AOF_FILENAME);
+        let (aof, _) = AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .unwrap();
+        database.aof = Some(aof);
+        let db = Arc::new(Mutex::new(database));
+        for line in &["create saved", "set saved a", "create kept", "set kept a"] {
+            assert_eq!(request(&db, line).await, "Done");
+        }
+        let mut db = db.lock().await;
+        let saved = db.filters.get_mut("saved").unwrap();
+        saved.to_file(Compression::None, None).await.unwrap();
+        saved.mark_saved();
+        // Dropped filters, only the drop whose files couldn't be deleted is left in the log
+        fs::write(dir.join("stuck.rbl"), b"").await.unwrap();
+        let aof = db.aof.as_mut().unwrap();
+        for name in &["gone", "stuck"] {
+            let operations = vec![
+                Operation::Create {
+                    name: name.to_string(),
+                    capacity: 5,
+                    fpp: 0.01,
+                },
+                Operation::Set {
+                    name: name.to_string(),
+                    key: b"a".to_vec(),
+                },
+                Operation::Drop {
+                    name: name.to_string(),
+                },
+            ];
+            for op in operations.iter() {
+                aof.append(op).await.unwrap();
             }
         }
-        drop(db);
+        compact_append_only_log(&mut db).await.unwrap();
+        db.aof = None;
+        let (_, replayed) = AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .unwrap();
+        let names: Vec<&str> = replayed.iter().map(|op| op.name()).collect();
+        assert_eq!(names, vec!["kept", "kept", "stuck"]);
+        assert_eq!(
+            replayed[2],
+            Operation::Drop {
+                name: "stuck".into()
+            }
+        );
     }
-}
 
-/// Write to disk every scalable filter in the database that is considered cold. Cold filters are
-/// those that are not accessed since a given time.
-async fn dump_cold_filters(db: &FilterDb, interval: u64) -> AsyncResult<()> {
-    loop {
-        let mut db_ref = db.lock().await;
-        let dbr = db_ref.deref_mut();
-        let now = Utc::now().timestamp();
-        for (k, v) in dbr.filters.iter() {
-            if now - &v.last_access_time().timestamp() > COLD_FILTER_TIMEOUT {
-                match v.to_file().await {
-                    Ok(()) => {
-                        dbr.cold_filters.insert(k.clone());
-                        info!(
-                            "{} filter dumped to disk as deemed cold - last access time {}",
-                            v,
-                            v.last_access_time()
-                        )
-                    }
-                    Err(e) => error!("{} filter dump error: {:?}", v, e),
-                }
-            }
+    #[tokio::test]
+    async fn test_save_on_shutdown() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut database = test_database(dir);
+        let path = dir.join(AOF_FILENAME);
+        let (aof, _) = AppendOnlyLog::open(&path, FsyncPolicy::EverySec, None)
+            .await
+            .unwrap();
+        database.aof = Some(aof);
+        let db = Arc::new(Mutex::new(database));
+        for line in &["create foo", "set foo a"] {
+            assert_eq!(request(&db, line).await, "Done");
         }
-        db_ref
-            .filters
-            .retain(|_, v| now - v.last_access_time().timestamp() < COLD_FILTER_TIMEOUT);
-        drop(db_ref);
-        // Sleep for a defined timeout
-        sleep(Duration::from_secs(interval)).await;
+        save_on_shutdown(&db).await;
+        // The filter is written whatever the save rules, its records are dropped from the log
+        assert!(dir.join("foo.rbl").exists());
+        assert!(!db.lock().await.filters["foo"].is_dirty());
+        db.lock().await.aof = None;
+        let (_, replayed) = AppendOnlyLog::open(&path, FsyncPolicy::Never, None)
+            .await
+            .unwrap();
+        assert!(replayed.is_empty());
     }
-}
 
-/// Parse a line into a `Request` and return a `Response` based on the outcome of the
-/// operation requested.
-async fn handle_request(line: &str, db: &FilterDb) -> Response {
-    let request = match Request::parse(&line) {
-        Ok(req) => req,
-        Err(e) => return Response::Error(e.message),
-    };
-    let mut db = db.lock().await;
-    let db_ref = db.deref_mut();
-    match request {
-        Request::Create {
-            name,
-            capacity,
-            fpp,
-        } => {
-            db.filters
-                .entry(name.clone())
-                .or_insert(ScalableBloomFilter::new(
-                    name,
-                    capacity,
-                    fpp,
-                    ScaleFactor::SmallScaleSize,
-                ));
-            Response::Done
+    #[tokio::test]
+    async fn test_filter_location() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let volume = dir.join("volume");
+        let mut database = test_database(dir);
+        database.volumes.insert("big".into(), volume.clone());
+        let db = Arc::new(Mutex::new(database));
+        // Only configured volumes are accepted, by name or by path
+        for other in &[
+            dir.join("elsewhere"),
+            volume.join(".."),
+            PathBuf::from("/etc"),
+        ] {
+            let create = format!("create foo 50 0.01 dir={}", other.display());
+            assert_eq!(
+                request(&db, &create).await,
+                format!("Error: {} isn't a configured volume", other.display())
+            );
         }
-        Request::Set { name, key } => match db_ref.filters.get_mut(&name) {
-            // First we check that a warm filter matching the name exists and in case, try to set
-            // the value
+        assert!(!db.lock().await.contains("foo"));
+        assert!(!dir.join("elsewhere").exists());
+        assert_eq!(request(&db, "create foo 50 0.01 dir=big").await, "Done");
+        let create = format!("create bar 50 0.01 dir={}", volume.display());
+        assert_eq!(request(&db, &create).await, "Done");
+        assert_eq!(request(&db, "set foo a").await, "Done");
+        assert_eq!(request(&db, "close foo").await, "Done");
+        assert_eq!(request(&db, "close bar").await, "Done");
+        assert!(volume.join("foo.rbl").exists());
+        assert!(volume.join("bar.rbl").exists());
+        assert!(!dir.join("foo.rbl").exists());
+        // A restart finds the filter where it was created
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        let report = index_filters(&mut *db.lock().await).await.unwrap();
+        assert_eq!(report.filters, 2);
+        assert_eq!(request(&db, "check foo a").await, "True");
+        assert_eq!(request(&db, "drop foo").await, "Done");
+        assert_eq!(request(&db, "drop bar").await, "Done");
+        assert!(!volume.join("foo.rbl").exists());
+        assert!(load_locations(dir).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_migrate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let volume = dir.join("volume");
+        fs::create_dir_all(&volume).await.unwrap();
+        // Legacy files are plain bincode dumps of the filters
+        let mut locations = HashMap::new();
+        locations.insert("far".to_string(), volume.clone());
+        fs::write(
+            dir.join(LOCATIONS_FILENAME),
+            serde_yaml::to_string(&locations).unwrap(),
+        )
+        .await
+        .unwrap();
+        for path in &[dir.join("near.rbl"), volume.join("far.rbl")] {
+            let sbf = ScalableBloomFilter::new("f".into(), 5, 0.01, ScaleFactor::SmallScaleSize);
+            fs::write(path, bincode::serialize(&sbf).unwrap())
+                .await
+                .unwrap();
+        }
+        let config = Config {
+            data_dir: dir.to_path_buf(),
+            ..Config::default()
+        };
+        migrate(&config).await.unwrap();
+        for path in &[dir.join("near.rbl"), volume.join("far.rbl")] {
+            assert!(fs::read(path).await.unwrap().starts_with(storage::MAGIC));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let snapshot_dir = dir.join("snapshots");
+        let volume = dir.join("volume");
+        fs::create_dir_all(dir.join("source")).await.unwrap();
+        let mut database = test_database(&dir.join("source"));
+        database.snapshot_dir = snapshot_dir.clone();
+        database.volumes.insert("big".into(), volume.clone());
+        let db = Arc::new(Mutex::new(database));
+        assert_eq!(request(&db, "lastsave").await, "never");
+        for line in [
+            "create warm",
+            "set warm a",
+            "create cold",
+            "set cold b",
+            "close cold",
+            "create mapped 50 0.01 mmap",
+            "set mapped c",
+            "create far 50 0.01 dir=big",
+            "set far d",
+        ]
+        .iter()
+        {
+            assert_eq!(request(&db, line).await, "Done");
+        }
+        // Archives are confined to the snapshot directory
+        for path in &[
+            "../backup.rbs",
+            "/tmp/backup.rbs",
+            "snapshots/backup.rbs",
+            "..",
+        ] {
+            assert_eq!(
+                request(&db, &format!("snapshot {}", path)).await,
+                format!("Error: {} isn't a file name, paths aren't accepted", path)
+            );
+        }
+        assert_eq!(request(&db, "snapshot backup.rbs").await, "Done");
+        assert!(snapshot_dir.join("backup.rbs").exists());
+        assert_ne!(request(&db, "lastsave").await, "never");
+        // Changes after the snapshot aren't part of it
+        assert_eq!(request(&db, "set warm z").await, "Done");
+        assert!(request(&db, "snapshot backup.rbs")
+            .await
+            .contains("already exists"));
+
+        // Locations which aren't configured volumes are ignored
+        fs::create_dir_all(dir.join("target")).await.unwrap();
+        let mut database = test_database(&dir.join("target"));
+        database.snapshot_dir = snapshot_dir.clone();
+        let db = Arc::new(Mutex::new(database));
+        assert!(request(&db, "restore /etc/passwd")
+            .await
+            .contains("isn't a file name"));
+        assert_eq!(request(&db, "restore backup.rbs").await, "Done");
+        assert_eq!(request(&db, "check warm a").await, "True");
+        assert_eq!(request(&db, "check warm z").await, "False");
+        assert_eq!(request(&db, "check cold b").await, "True");
+        assert_eq!(request(&db, "check mapped c").await, "True");
+        assert!(request(&db, "info mapped").await.contains("storage: mmap"));
+        assert_eq!(request(&db, "check far d").await, "True");
+        assert!(dir.join("target").join("far.rbl").exists());
+        // Only an empty server can be restored
+        assert!(request(&db, "restore backup.rbs")
+            .await
+            .contains("must be empty"));
+
+        fs::create_dir_all(dir.join("volumes")).await.unwrap();
+        let mut database = test_database(&dir.join("volumes"));
+        database.snapshot_dir = snapshot_dir.clone();
+        database.volumes.insert("big".into(), volume.clone());
+        let db = Arc::new(Mutex::new(database));
+        fs::remove_dir_all(&volume).await.unwrap();
+        assert_eq!(request(&db, "restore backup.rbs").await, "Done");
+        assert_eq!(request(&db, "check far d").await, "True");
+        assert!(volume.join("far.rbl").exists());
+    }
+
+    #[tokio::test]
+    async fn test_backup_rollback() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut database = test_database(dir);
+        database.backup_dir = Some(dir.join(backup::DEFAULT_BACKUP_DIR));
+        let db = Arc::new(Mutex::new(database));
+        assert_eq!(request(&db, "create foo").await, "Done");
+        assert_eq!(request(&db, "set foo a").await, "Done");
+        assert_eq!(backup_changed_filters(&db, 3).await, 1);
+        // Unchanged filters aren't backed up again
+        assert_eq!(backup_changed_filters(&db, 3).await, 0);
+        let versions = request(&db, "backups foo").await;
+        assert_eq!(versions.lines().count(), 1);
+        assert_eq!(request(&db, "set foo b").await, "Done");
+        assert_eq!(request(&db, "clear foo").await, "Done");
+        let rollback = format!("rollback foo {}", versions);
+        assert_eq!(request(&db, &rollback).await, "Done");
+        assert_eq!(request(&db, "check foo a").await, "True");
+        assert_eq!(request(&db, "check foo b").await, "False");
+        // A dropped filter can be brought back too
+        assert_eq!(request(&db, "drop foo").await, "Done");
+        assert_eq!(request(&db, &rollback).await, "Done");
+        assert_eq!(request(&db, "check foo a").await, "True");
+        assert_eq!(
+            request(&db, "rollback foo 20000101T000000Z").await,
+            "Error: rollback failed: no backup of filter foo taken at 20000101T000000Z"
+        );
+        assert_eq!(
+            request(&db, "rollback foo yesterday").await,
+            "Error: invalid backup timestamp yesterday"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_redisbloom_export_import() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        // An empty RedisBloom filter, as created by BF.RESERVE with an error rate of 0.01
+        let layer = crate::filter::Layer {
+            bits: 960,
+            hash_count: 7,
+            size: 0,
+            bitmap: vec![0; 120],
+        };
+        let sbf = ScalableBloomFilter::from_layers(
+            "source".into(),
+            100,
+            0.01,
+            ScaleFactor::SmallScaleSize,
+            HashAlgorithm::Murmur64A,
+            &[layer],
+        );
+        let interop_dir = dir.join(DEFAULT_INTEROP_DIR);
+        fs::create_dir_all(&interop_dir).await.unwrap();
+        redisbloom::export(&sbf, &interop_dir.join("source.rdb"))
+            .await
+            .unwrap();
+        assert_eq!(request(&db, "import foo source.rdb").await, "Done");
+        assert!(request(&db, "import foo source.rdb")
+            .await
+            .contains("already exists"));
+        // Files are only read from and written to the interop directory
+        let outside = format!("import qux {}", interop_dir.join("source.rdb").display());
+        assert!(request(&db, &outside)
+            .await
+            .contains("paths aren't accepted"));
+        assert!(request(&db, "export foo ../foo.rdb")
+            .await
+            .contains("paths aren't accepted"));
+        assert_eq!(request(&db, "set foo a").await, "Done");
+        // The hashing is kept along with the filter on disk
+        set_residency(&db, Residency::Cold).await;
+        assert_eq!(request(&db, "check foo a").await, "True");
+        assert_eq!(request(&db, "export foo target.rdb").await, "Done");
+        let mut exported = redisbloom::import("bar".into(), &interop_dir.join("target.rdb"))
+            .await
+            .unwrap();
+        assert!(exported.check(b"a"));
+        // Native filters hash keys differently than RedisBloom does
+        assert_eq!(request(&db, "create bar").await, "Done");
+        assert!(request(&db, "export bar bar.rdb")
+            .await
+            .contains("only filters imported"));
+        assert_eq!(
+            request(&db, "import baz").await,
+            "Error: missing import path"
+        );
+        // Filters built by other libraries are served too, though not exported
+        for (format, file) in [
+            ("guava", "guava-mitz64.bin"),
+            ("pybloom", "pybloom-md5.bin"),
+        ]
+        .iter()
+        {
+            let fixture = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(file);
+            fs::copy(fixture, interop_dir.join(file)).await.unwrap();
+            let import = format!("import {} {} {}", format, file, format);
+            assert_eq!(request(&db, &import).await, "Done");
+            assert_eq!(
+                request(&db, &format!("check {} apple", format)).await,
+                "True"
+            );
+            assert_eq!(
+                request(&db, &format!("check {} fig", format)).await,
+                "False"
+            );
+        }
+        assert_eq!(
+            request(&db, "import baz path csv").await,
+            "Error: unknown import format csv"
+        );
+    }
+
+    async fn resp(db: &FilterDb, codec: &mut RespCodec, args: &[&[u8]]) -> Frame {
+        let args: Vec<Vec<u8>> = args.iter().map(|arg| arg.to_vec()).collect();
+        handle_resp_command(&args, db, codec).await
+    }
+
+    #[tokio::test]
+    async fn test_resp_commands() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        let mut codec = RespCodec::new();
+        let ok = Frame::Simple("OK".into());
+        let error = |message: &str| Frame::Error(format!("ERR {}", message));
+        let ints =
+            |values: &[i64]| Frame::Array(values.iter().copied().map(Frame::Integer).collect());
+        let reserve: &[&[u8]] = &[b"BF.RESERVE", b"foo", b"0.01", b"1000"];
+        assert_eq!(resp(&db, &mut codec, reserve).await, ok);
+        assert_eq!(resp(&db, &mut codec, reserve).await, error("item exists"));
+        // Keys are binary-safe
+        let key: &[u8] = b"a b\r\n\xff";
+        assert_eq!(
+            resp(&db, &mut codec, &[b"bf.add", b"foo", key]).await,
+            Frame::Integer(1)
+        );
+        assert_eq!(
+            resp(&db, &mut codec, &[b"bf.add", b"foo", key]).await,
+            Frame::Integer(0)
+        );
+        assert_eq!(
+            resp(&db, &mut codec, &[b"BF.EXISTS", b"foo", key]).await,
+            Frame::Integer(1)
+        );
+        assert_eq!(
+            resp(&db, &mut codec, &[b"BF.EXISTS", b"foo", b"a"]).await,
+            Frame::Integer(0)
+        );
+        let madd: &[&[u8]] = &[b"BF.MADD", b"foo", b"a", key];
+        assert_eq!(resp(&db, &mut codec, madd).await, ints(&[1, 0]));
+        let mexists: &[&[u8]] = &[b"BF.MEXISTS", b"foo", b"a", b"b"];
+        assert_eq!(resp(&db, &mut codec, mexists).await, ints(&[1, 0]));
+        assert_eq!(request(&db, "check foo a").await, "True");
+        // Missing filters hold no keys, adding one creates the filter
+        assert_eq!(
+            resp(&db, &mut codec, &[b"BF.EXISTS", b"bar", b"a"]).await,
+            Frame::Integer(0)
+        );
+        assert_eq!(
+            resp(&db, &mut codec, &[b"BF.ADD", b"bar", b"a"]).await,
+            Frame::Integer(1)
+        );
+        assert_eq!(request(&db, "check bar a").await, "True");
+        assert_eq!(
+            resp(&db, &mut codec, &[b"BF.INFO", b"foo", b"items"]).await,
+            ints(&[2])
+        );
+        match resp(&db, &mut codec, &[b"BF.INFO", b"foo"]).await {
+            Frame::Map(pairs) => {
+                assert_eq!(pairs.len(), BF_INFO_FIELDS.len());
+                let items = (
+                    Frame::Simple("Number of items inserted".into()),
+                    Frame::Integer(2),
+                );
+                assert_eq!(pairs[3], items);
+                let expansion = (Frame::Simple("Expansion rate".into()), Frame::Integer(2));
+                assert_eq!(pairs[4], expansion);
+            }
+            frame => panic!("expected a map, got {:?}", frame),
+        }
+        assert_eq!(
+            resp(&db, &mut codec, &[b"BF.INFO", b"foo", b"hits"]).await,
+            error("Invalid information value")
+        );
+        assert_eq!(
+            resp(&db, &mut codec, &[b"BF.INFO", b"baz"]).await,
+            error("not found")
+        );
+        for (options, message) in [
+            (&[&b"0"[..], b"10"][..], "bad error rate"),
+            (&[b"0.01", b"0"], "bad capacity"),
+            (
+                &[b"0.01", b"10", b"NONSCALING"],
+                "NONSCALING filters are not supported",
+            ),
+            (
+                &[b"0.01", b"10", b"EXPANSION", b"4"],
+                "only an EXPANSION of 2 is supported",
+            ),
+            (
+                &[b"0.01", b"10", b"EXPANSION", b"2", b"CAS"],
+                "syntax error",
+            ),
+        ]
+        .iter()
+        {
+            let mut args: Vec<&[u8]> = vec![b"BF.RESERVE", b"baz"];
+            args.extend_from_slice(options);
+            assert_eq!(resp(&db, &mut codec, &args).await, error(message));
+        }
+        assert!(matches!(
+            resp(&db, &mut codec, &[b"BF.ADD", b".foo", b"a"]).await,
+            Frame::Error(e) if e.starts_with("ERR ")
+        ));
+        let del: &[&[u8]] = &[b"DEL", b"foo", b"bar", b"baz", b".foo"];
+        assert_eq!(resp(&db, &mut codec, del).await, Frame::Integer(2));
+        assert!(!db.lock().await.contains_any());
+        assert_eq!(
+            resp(&db, &mut codec, &[b"BF.ADD", b"foo"]).await,
+            error("wrong number of arguments for 'bf.add' command")
+        );
+        assert_eq!(
+            resp(&db, &mut codec, &[b"GET", b"foo"]).await,
+            error("unknown command 'get'")
+        );
+        assert_eq!(
+            resp(&db, &mut codec, &[b"PING"]).await,
+            Frame::Simple("PONG".into())
+        );
+        assert_eq!(
+            resp(&db, &mut codec, &[b"HELLO", b"4"]).await,
+            Frame::Error("NOPROTO unsupported protocol version".into())
+        );
+        assert_eq!(codec.protocol(), 2);
+        assert!(matches!(
+            resp(&db, &mut codec, &[b"HELLO", b"3"]).await,
+            Frame::Map(_)
+        ));
+        assert_eq!(codec.protocol(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_bloomd_commands() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        let bloomd = |line: &'static str| {
+            let db = db.clone();
+            async move { handle_bloomd_command(line, &db).await }
+        };
+        assert_eq!(bloomd("create foo capacity=1000 prob=0.01").await, "Done");
+        assert_eq!(bloomd("create foo").await, "Exists");
+        assert_eq!(
+            bloomd("create bar prob=2").await,
+            "Client Error: Bad arguments"
+        );
+        assert_eq!(
+            bloomd("create bar capacity=0").await,
+            "Client Error: Bad arguments"
+        );
+        assert_eq!(bloomd("create .bar").await, "Client Error: Bad filter name");
+        assert_eq!(bloomd("set foo a").await, "Yes");
+        assert_eq!(bloomd("s foo a").await, "No");
+        assert_eq!(bloomd("bulk foo a b c").await, "No Yes Yes");
+        assert_eq!(bloomd("check foo b").await, "Yes");
+        assert_eq!(bloomd("multi foo a d c").await, "Yes No Yes");
+        assert_eq!(bloomd("check foo").await, "Client Error: Bad arguments");
+        assert_eq!(bloomd("check bar a").await, "Filter does not exist");
+        assert_eq!(
+            bloomd("set").await,
+            "Client Error: Must provide filter name"
+        );
+        assert_eq!(
+            bloomd("get foo").await,
+            "Client Error: Command not supported"
+        );
+        let info = bloomd("info foo").await;
+        let fields: Vec<&str> = info
+            .lines()
+            .map(|line| line.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "START",
+                "capacity",
+                "checks",
+                "check_hits",
+                "check_misses",
+                "in_memory",
+                "page_ins",
+                "page_outs",
+                "probability",
+                "sets",
+                "set_hits",
+                "set_misses",
+                "size",
+                "storage",
+                "END"
+            ]
+        );
+        assert!(info.contains("\nin_memory 1\n"));
+        assert!(info.contains("\nprobability 0.01\n"));
+        assert!(info.contains("\nsize 3\n"));
+        assert_eq!(bloomd("create food").await, "Done");
+        assert_eq!(bloomd("flush").await, "Done");
+        assert_eq!(bloomd("close foo").await, "Done");
+        let list = bloomd("list foo").await;
+        let lines: Vec<&str> = list.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!((lines[0], lines[3]), ("START", "END"));
+        assert!(lines[1].starts_with("foo 0.01 "));
+        assert!(lines[1].ends_with(" 3"));
+        assert!(lines[2].starts_with("food "));
+        assert_eq!(bloomd("list bar").await, "START\nEND");
+        // Cleared filters are only forgotten once closed, dropped ones are deleted
+        assert_eq!(
+            bloomd("clear food").await,
+            "Filter is not proxied. Close it first."
+        );
+        assert_eq!(bloomd("clear foo").await, "Done");
+        assert_eq!(bloomd("check foo a").await, "Filter does not exist");
+        assert!(dir.join("foo.rbl").exists());
+        assert_eq!(bloomd("drop food").await, "Done");
+        assert_eq!(bloomd("list").await, "START\nEND");
+    }
+
+    async fn http(db: &FilterDb, method: &str, uri: &str, body: &str) -> (u16, serde_json::Value) {
+        let req = hyper::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let res = handle_http_request(req, db).await;
+        let status = res.status().as_u16();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_http_api() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        let done = json!({ "done": true });
+        let options = r#"{"capacity": 1000, "fpp": 0.01}"#;
+        assert_eq!(
+            http(&db, "PUT", "/filters/foo", options).await,
+            (200, done.clone())
+        );
+        assert_eq!(
+            http(&db, "PUT", "/filters/bar", "").await,
+            (200, done.clone())
+        );
+        let (status, body) = http(&db, "PUT", "/filters/baz", r#"{"size": 1}"#).await;
+        assert_eq!(status, 400);
+        assert!(body["error"].as_str().unwrap().contains("unknown field"));
+        for options in [r#"{"capacity": 0}"#, r#"{"fpp": 1.5}"#].iter() {
+            let (status, body) = http(&db, "PUT", "/filters/baz", options).await;
+            assert_eq!(status, 400);
+            assert!(body["error"].as_str().unwrap().contains(" must be "));
+        }
+        let keys = r#"{"keys": ["a", "b c", "/"]}"#;
+        assert_eq!(
+            http(&db, "POST", "/filters/foo/keys", keys).await,
+            (200, done.clone())
+        );
+        assert_eq!(request(&db, "check foo a").await, "True");
+        let present = |present: bool| json!({ "present": present });
+        assert_eq!(
+            http(&db, "GET", "/filters/foo/keys/b%20c", "").await,
+            (200, present(true))
+        );
+        assert_eq!(
+            http(&db, "GET", "/filters/foo/keys/%2F", "").await,
+            (200, present(true))
+        );
+        assert_eq!(
+            http(&db, "GET", "/filters/foo/keys/d", "").await,
+            (200, present(false))
+        );
+        let check = r#"{"keys": ["a", "d"]}"#;
+        let batch = json!({ "present": [true, false] });
+        assert_eq!(
+            http(&db, "POST", "/filters/foo/check", check).await,
+            (200, batch)
+        );
+        let (status, info) = http(&db, "GET", "/filters/foo", "").await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            (info["name"].clone(), info["size"].clone()),
+            (json!("foo"), json!(3))
+        );
+        assert_eq!(info["storage"], json!("heap"));
+        let (status, list) = http(&db, "GET", "/filters", "").await;
+        assert_eq!(status, 200);
+        let names: Vec<&str> = list
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["bar", "foo"]);
+        // Errors are the ones of the text protocol
+        let missing = json!({ "error": "no scalable filter named baz" });
+        assert_eq!(
+            http(&db, "GET", "/filters/baz", "").await,
+            (404, missing.clone())
+        );
+        assert_eq!(
+            http(&db, "POST", "/filters/baz/check", check).await,
+            (404, missing)
+        );
+        assert_eq!(http(&db, "GET", "/filters/.foo", "").await.0, 400);
+        assert_eq!(http(&db, "POST", "/filters/foo/keys", "[]").await.0, 400);
+        assert_eq!(http(&db, "GET", "/filters/foo/keys/%zz", "").await.0, 400);
+        assert_eq!(http(&db, "POST", "/filters/foo", "").await.0, 405);
+        assert_eq!(http(&db, "GET", "/stats", "").await.0, 404);
+        assert_eq!(
+            http(&db, "DELETE", "/filters/bar?keep-data", "").await,
+            (200, done.clone())
+        );
+        assert!(dir.join("bar.rbl").exists());
+        assert_eq!(http(&db, "DELETE", "/filters/foo", "").await, (200, done));
+        assert_eq!(http(&db, "GET", "/filters", "").await, (200, json!([])));
+    }
+
+    #[tokio::test]
+    async fn test_resp_connection() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_resp(listener.into(), db.clone(), BACKOFF));
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        // As sent by a client library, then inline as typed into telnet
+        stream
+            .write_all(b"*3\r\n$6\r\nBF.ADD\r\n$3\r\nfoo\r\n$3\r\na\r\n\r\n")
+            .await
+            .unwrap();
+        stream
+            .write_all(b"HELLO 3\r\nBF.INFO foo ITEMS\r\nQUIT\r\n")
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.starts_with(b":1\r\n%6\r\n$6\r\nserver\r\n$5\r\nrublo\r\n"));
+        assert!(reply.ends_with(b"*1\r\n:1\r\n+OK\r\n"));
+        // The key added holds the line break
+        assert_eq!(request(&db, "check foo a").await, "False");
+    }
+
+    #[tokio::test]
+    async fn test_grpc_service() {
+        use grpc::rublo_client::RubloClient;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_grpc(listener.into(), db.clone()));
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{}", address))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = RubloClient::new(channel);
+        let create = |name: &str| grpc::CreateRequest {
+            name: name.into(),
+            ..Default::default()
+        };
+        client.create(create("foo")).await.unwrap();
+        let key = |key: &[u8]| grpc::KeyRequest {
+            name: "foo".into(),
+            key: key.to_vec(),
+        };
+        client.set(key(b"a\n\xff")).await.unwrap();
+        let check = client.check(key(b"a\n\xff")).await.unwrap();
+        assert!(check.into_inner().present);
+        let keys = grpc::KeysRequest {
+            name: "foo".into(),
+            keys: vec![b"b".to_vec(), b"c".to_vec()],
+        };
+        client.set_batch(keys).await.unwrap();
+        let keys = grpc::KeysRequest {
+            name: "foo".into(),
+            keys: vec![b"b".to_vec(), b"d".to_vec()],
+        };
+        let batch = client.check_batch(keys).await.unwrap().into_inner();
+        assert_eq!(batch.present, [true, false]);
+        let stream = tokio_stream::iter(vec![key(b"e"), key(b"f")]);
+        client.set_stream(stream).await.unwrap();
+        let stream = tokio_stream::iter(vec![key(b"e"), key(b"g"), key(b"f")]);
+        let replies: Vec<bool> = client
+            .check_stream(stream)
+            .await
+            .unwrap()
+            .into_inner()
+            .map(|reply| reply.unwrap().present)
+            .collect()
+            .await;
+        assert_eq!(replies, [true, false, true]);
+        // Shared with the text protocol
+        assert_eq!(request(&db, "check foo c").await, "True");
+        let filter = grpc::FilterRequest { name: "foo".into() };
+        let info = client.info(filter.clone()).await.unwrap().into_inner();
+        assert_eq!((info.name.as_str(), info.size), ("foo", 5));
+        assert_eq!(info.storage, grpc::Storage::Heap as i32);
+        let list = client
+            .list(grpc::ListRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(list.filters.len(), 1);
+        assert_eq!(list.filters[0].size, Some(5));
+        // Errors
+        let missing = grpc::FilterRequest { name: "bar".into() };
+        let status = client.info(missing.clone()).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert_eq!(status.message(), "no scalable filter named bar");
+        let mmap = grpc::CreateRequest {
+            storage: 2,
+            ..create("bar")
+        };
+        let status = client.create(mmap).await.unwrap_err();
+        assert_eq!(status.message(), "storage must be heap or mmap");
+        let status = client.create(create(".foo")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let fpp = grpc::CreateRequest {
+            fpp: 1.5,
+            ..create("bar")
+        };
+        let status = client.create(fpp).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains("false-positive probability"));
+        // Ending the stream of replies
+        let stream = tokio_stream::iter(vec![grpc::KeyRequest::default(), key(b"e")]);
+        let mut replies = client.check_stream(stream).await.unwrap().into_inner();
+        let status = replies.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let drop = grpc::DropRequest {
+            name: "foo".into(),
+            keep_data: false,
+        };
+        client.drop(drop).await.unwrap();
+        let status = client.info(filter).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_binary_protocol() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_db = db.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(handle_connection(stream, server_db.clone()));
+            }
+        });
+        let frame = |id: u8, opcode: u8, body: &[u8]| {
+            let mut frame = ((body.len() + 5) as u32).to_be_bytes().to_vec();
+            frame.extend_from_slice(&[0, 0, 0, id, opcode]);
+            frame.extend_from_slice(body);
+            frame
+        };
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let mut requests = vec![BINARY_HANDSHAKE];
+        let mut create = b"\x03foo".to_vec();
+        create.extend_from_slice(&[0; 17]);
+        create.extend_from_slice(&[0; 4]);
+        requests.extend(frame(1, 0x01, &create));
+        requests.extend(frame(
+            2,
+            0x04,
+            b"\x03foo\0\0\0\x02\0\0\0\x01a\0\0\0\x02\r\n",
+        ));
+        requests.extend(frame(3, 0x03, b"\x03foo\0\0\0\x02\r\n"));
+        requests.extend(frame(4, 0x05, b"\x03foo\0\0\0\x02\0\0\0\x01a\0\0\0\x01b"));
+        requests.extend(frame(5, 0x02, b"\x03bar\0\0\0\x01a"));
+        requests.extend(frame(6, 0x42, b""));
+        let mut create = b"\x03baz\0\0\0\0\0\0\0\x01".to_vec();
+        create.extend_from_slice(&1.5f64.to_be_bytes());
+        create.extend_from_slice(&[0; 5]);
+        requests.extend(frame(7, 0x01, &create));
+        requests.extend(frame(8, 0x00, b"stats"));
+        // Batches of missing filters fail even without keys
+        requests.extend(frame(9, 0x05, b"\x03bar\0\0\0\0"));
+        stream.write_all(&requests).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut replies = Vec::new();
+        stream.read_to_end(&mut replies).await.unwrap();
+        let mut expected = vec![BINARY_HANDSHAKE];
+        expected.extend(b"\0\0\0\x05\0\0\0\x01\x00");
+        expected.extend(b"\0\0\0\x05\0\0\0\x02\x00");
+        expected.extend(b"\0\0\0\x06\0\0\0\x03\x01\x01");
+        expected.extend(b"\0\0\0\x0b\0\0\0\x04\x02\0\0\0\x02\x01\x00");
+        let missing = b"no scalable filter named bar";
+        expected.extend(&((missing.len() + 5) as u32).to_be_bytes());
+        expected.extend(b"\0\0\0\x05\xff");
+        expected.extend(missing);
+        expected.extend(b"\0\0\0\x18\0\0\0\x06\xffunknown opcode 0x42");
+        let invalid = b"false-positive probability must be between 0 and 1, got 1.5";
+        expected.extend(&((invalid.len() + 5) as u32).to_be_bytes());
+        expected.extend(b"\0\0\0\x07\xff");
+        expected.extend(invalid);
+        let stats = request(&db, "stats").await;
+        expected.extend(&((stats.len() + 5) as u32).to_be_bytes());
+        expected.extend(b"\0\0\0\x08\x03");
+        expected.extend(stats.as_bytes());
+        expected.extend(&((missing.len() + 5) as u32).to_be_bytes());
+        expected.extend(b"\0\0\0\x09\xff");
+        expected.extend(missing);
+        assert_eq!(replies, expected);
+        // Shared with the text protocol, still served on the same port
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"check foo a\n").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(reply, "True\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use tokio::net::UnixStream;
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        let listener = Listener::bind_unix(&dir.join("rublo.sock"), Some(0o660))
+            .await
+            .unwrap();
+        let server_db = db.clone();
+        tokio::spawn(async move { serve_clients(&listener, server_db, BACKOFF).await });
+        let http_address = format!("unix:{}", dir.join("http.sock").display());
+        let listener = Listener::bind(&http_address, None).await.unwrap();
+        tokio::spawn(serve_http(listener, db.clone()));
+        let converse = |path: PathBuf, request: &'static [u8]| async move {
+            let mut stream = UnixStream::connect(path).await.unwrap();
+            stream.write_all(request).await.unwrap();
+            stream.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await.unwrap();
+            reply
+        };
+        let reply = converse(dir.join("rublo.sock"), b"create foo\nset foo a\n").await;
+        assert_eq!(reply, b"Done\nDone\n");
+        // The binary protocol too
+        let check = b"\xb1\0\0\0\x0e\0\0\0\x01\x03\x03foo\0\0\0\x01a";
+        let reply = converse(dir.join("rublo.sock"), check).await;
+        assert_eq!(reply, b"\xb1\0\0\0\x06\0\0\0\x01\x01\x01");
+        // And the HTTP API, without half-closing the connection which hyper would drop
+        let request =
+            b"GET /filters/foo/keys/a HTTP/1.1\r\nHost: rublo\r\nConnection: close\r\n\r\n";
+        let mut stream = UnixStream::connect(dir.join("http.sock")).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(reply.ends_with(br#"{"present":true}"#));
+    }
+
+    #[tokio::test]
+    async fn test_index_filters() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let data =
+            storage::encode(HashAlgorithm::Gxhash32, Compression::None, 0, b"", None).unwrap();
+        fs::write(dir.join("good.rbl"), &data).await.unwrap();
+        fs::write(dir.join("legacy.rbl"), b"legacy").await.unwrap();
+        fs::write(dir.join("bad.rbl"), &data[..10]).await.unwrap();
+        fs::write(dir.join("good.rbl.tmp"), b"").await.unwrap();
+        fs::write(dir.join(".DS_Store"), b"").await.unwrap();
+        fs::write(dir.join(".good.rbl.swp"), b"").await.unwrap();
+        let mut db = test_database(dir);
+        let report = index_filters(&mut db).await.unwrap();
+        assert_eq!(
+            report,
+            ScanReport {
+                filters: 2,
+                skipped: 2,
+                quarantined: 1,
+                renamed: 0
+            }
+        );
+        assert!(db.cold_filters.contains_key("good"));
+        assert!(db.cold_filters.contains_key("legacy"));
+        assert!(!dir.join("bad.rbl").exists());
+        assert_eq!(quarantined_files(dir).await, vec!["bad.rbl"]);
+        assert!(!dir.join("good.rbl.tmp").exists());
+        // The quarantine directory itself is ignored on the next startup
+        let report = index_filters(&mut db).await.unwrap();
+        assert_eq!(report.quarantined, 0);
+        // A file quarantined again is kept apart from the earlier copy
+        fs::write(dir.join("bad.rbl"), &data[..10]).await.unwrap();
+        let report = index_filters(&mut db).await.unwrap();
+        assert_eq!(report.quarantined, 1);
+        assert_eq!(quarantined_files(dir).await, vec!["bad.rbl", "bad.rbl"]);
+    }
+
+    #[tokio::test]
+    async fn test_rename_legacy_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let volume = dir.join("volume");
+        fs::create_dir_all(&volume).await.unwrap();
+        let data =
+            storage::encode(HashAlgorithm::Gxhash32, Compression::None, 0, b"", None).unwrap();
+        // Written under the raw filter names, before names were encoded
+        fs::write(dir.join("site:hits.rbl"), &data).await.unwrap();
+        fs::write(dir.join("site:hits.0.rbm"), b"").await.unwrap();
+        fs::write(volume.join("far:away.rbl"), &data).await.unwrap();
+        let mut locations = HashMap::new();
+        locations.insert("far:away".to_string(), volume.clone());
+        fs::write(
+            dir.join(LOCATIONS_FILENAME),
+            serde_yaml::to_string(&locations).unwrap(),
+        )
+        .await
+        .unwrap();
+        // Clashing with a file already encoded, left alone
+        fs::write(dir.join("a:b.rbl"), &data).await.unwrap();
+        fs::write(dir.join("a%3Ab.rbl"), &data).await.unwrap();
+        // Not a valid filter name, can't be renamed
+        fs::write(dir.join("my filter.rbl"), &data).await.unwrap();
+        let mut db = test_database(dir);
+        let report = index_filters(&mut db).await.unwrap();
+        assert_eq!(
+            report,
+            ScanReport {
+                filters: 3,
+                skipped: 2,
+                quarantined: 0,
+                renamed: 2
+            }
+        );
+        assert!(db.cold_filters.contains_key("site:hits"));
+        assert!(db.cold_filters.contains_key("far:away"));
+        assert!(db.cold_filters.contains_key("a:b"));
+        assert!(dir.join("site%3Ahits.rbl").exists());
+        assert!(dir.join("site%3Ahits.0.rbm").exists());
+        assert!(!dir.join("site:hits.rbl").exists());
+        assert!(volume.join("far%3Aaway.rbl").exists());
+        assert!(dir.join("a:b.rbl").exists());
+    }
+
+    // Names of the files in the quarantine directory of `dir`, without their time suffix
+    async fn quarantined_files(dir: &Path) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut entries = fs::read_dir(dir.join(QUARANTINE_DIR)).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // The suffix holds a dot too, ahead of the microseconds
+            let (name, suffix) = name.split_at(name.len() - "20211231T235959.999999Z".len());
+            let name = name.strip_suffix('.').unwrap();
+            assert!(NaiveDateTime::parse_from_str(suffix, QUARANTINE_SUFFIX_FORMAT).is_ok());
+            names.push(name.to_string());
+        }
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_quarantine_corrupt_payload() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let db = Arc::new(Mutex::new(test_database(dir)));
+        assert_eq!(request(&db, "create big 50 0.01 mmap").await, "Done");
+        assert_eq!(request(&db, "set big a").await, "Done");
+        assert_eq!(request(&db, "close big").await, "Done");
+        // The header stays valid, only loading the filter finds out
+        let path = dir.join("big.rbl");
+        let mut data = fs::read(&path).await.unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).await.unwrap();
+        assert!(request(&db, "check big a")
+            .await
+            .contains("checksum mismatch"));
+        assert!(!db.lock().await.contains("big"));
+        assert!(!path.exists());
+        assert_eq!(quarantined_files(dir).await, vec!["big.0.rbm", "big.rbl"]);
+    }
+}
+
+/// Save rule, a filter is written to disk once at least `seconds` passed since it was last saved
+/// and it has been changed at least `changes` times in the meanwhile.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SaveRule {
+    seconds: u64,
+    changes: u64,
+}
+
+impl SaveRule {
+    /// By default any changed filter is saved once a minute
+    pub fn defaults() -> Vec<SaveRule> {
+        vec![SaveRule {
+            seconds: 60,
+            changes: 1,
+        }]
+    }
+
+    fn matches(&self, filter: &ScalableBloomFilter, now: DateTime<Utc>) -> bool {
+        filter.is_dirty()
+            && filter.changes() >= self.changes
+            && (now - filter.last_save_time()).num_seconds() >= self.seconds as i64
+    }
+}
+
+struct FilterDatabase {
+    pub filters: HashMap<String, ScalableBloomFilter>,
+    pub cold_filters: HashMap<String, ColdFilter>,
+    /// Log of the writes since the last snapshot, if enabled
+    pub aof: Option<AppendOnlyLog>,
+    /// Compression applied to the filters written to disk
+    pub compression: Compression,
+    /// Key encrypting the files written to disk, if encryption at rest is enabled
+    pub key: Option<Key>,
+    /// Directory the filters are stored in
+    pub data_dir: PathBuf,
+    /// Directories of the filters stored out of `data_dir`, by name
+    pub locations: HashMap<String, PathBuf>,
+    /// Directories filters can be created in out of `data_dir`, by volume name
+    pub volumes: HashMap<String, PathBuf>,
+    /// Cold filters that couldn't be written to disk and are kept in memory until a retry succeeds
+    pub eviction_failures: HashMap<String, EvictionFailure>,
+    pub stats: Stats,
+    /// Completion time of the last snapshot archive
+    pub last_snapshot: Option<DateTime<Utc>>,
+    /// Directory the snapshot archives are written to and restored from
+    pub snapshot_dir: PathBuf,
+    /// Directory the filters are exported to and imported from
+    pub interop_dir: PathBuf,
+    /// Directory the filters are backed up into, if backups are enabled
+    pub backup_dir: Option<PathBuf>,
+    /// Filters changed since their last backup
+    pub unbacked: HashSet<String>,
+}
+
+impl FilterDatabase {
+    /// Whether a filter named `name` exists, warm or cold
+    fn contains(&self, name: &str) -> bool {
+        self.filters.contains_key(name) || self.cold_filters.contains_key(name)
+    }
+
+    /// Whether there's any filter, warm or cold
+    fn contains_any(&self) -> bool {
+        !self.filters.is_empty() || !self.cold_filters.is_empty()
+    }
+
+    /// Path of the file storing the filter named `name`, in the data directory unless a
+    /// different location has been given at creation
+    fn filter_path(&self, name: &str) -> PathBuf {
+        let dir = self.locations.get(name).unwrap_or(&self.data_dir);
+        ScalableBloomFilter::file_path(dir, name)
+    }
+
+    /// Directory to restore the filter `name` into from a snapshot or a backup recording its
+    /// `location`, `None` for the data directory. A location which isn't a configured volume is
+    /// ignored, archives can't write anywhere else.
+    fn restored_location(&self, name: &str, location: Option<&PathBuf>) -> Option<PathBuf> {
+        let location = location?;
+        if self.volumes.values().any(|path| path == location) {
+            return Some(location.clone());
+        }
+        error!(
+            "restoring filter {} into the data directory, {} isn't a configured volume",
+            name,
+            location.display()
+        );
+        None
+    }
+
+    /// Directory of the configured volume `dir` refers to, either by name or by path
+    ///
+    /// # Errors
+    ///
+    /// Returns a message if `dir` isn't a configured volume, clients can't store filters anywhere
+    /// else.
+    fn volume(&self, dir: &Path) -> Result<PathBuf, String> {
+        if let Some(path) = dir.to_str().and_then(|name| self.volumes.get(name)) {
+            return Ok(path.clone());
+        }
+        if self.volumes.values().any(|path| path == dir) {
+            return Ok(dir.to_path_buf());
+        }
+        Err(format!("{} isn't a configured volume", dir.display()))
+    }
+
+    /// Create a new empty filter named `name` unless one already exists, warm or cold, it must
+    /// not be shadowed by an empty one
+    fn create(&mut self, name: String, capacity: usize, fpp: f64, storage: BitmapStorage) {
+        if !self.contains(&name) {
+            let path = self.filter_path(&name);
+            let sbf = ScalableBloomFilter::new(name, capacity, fpp, ScaleFactor::SmallScaleSize)
+                .with_storage(storage)
+                .with_path(path);
+            self.filters.insert(sbf.name().clone(), sbf);
+        }
+    }
+}
+
+/// What is known of a cold filter without reading its file: everything once it has been in
+/// memory, only the time of its last write when found on disk at startup
+#[derive(Debug, Clone, PartialEq)]
+struct ColdFilter {
+    last_access_time: DateTime<Utc>,
+    capacity: Option<usize>,
+    fpp: Option<f64>,
+    size: Option<usize>,
+}
+
+impl ColdFilter {
+    fn from_filter(sbf: &ScalableBloomFilter) -> Self {
+        ColdFilter {
+            last_access_time: sbf.last_access_time(),
+            capacity: Some(sbf.capacity()),
+            fpp: Some(sbf.fpp()),
+            size: Some(sbf.size()),
+        }
+    }
+}
+
+/// Failed attempts to write a cold filter to disk before evicting it from memory
+#[derive(Debug, Clone)]
+struct EvictionFailure {
+    attempts: u32,
+    /// Timestamp of the next attempt
+    retry_at: i64,
+    error: String,
+}
+
+/// Counters of the cold filters eviction since startup
+#[derive(Debug, Default)]
+struct Stats {
+    evictions: u64,
+    eviction_failures: u64,
+}
+
+/// Shared state between multiple connections, the filter manager to track and
+/// update multiple scalable filters.
+///
+/// Being shared it's wrapped as an atomic counter reference (Arc) guarded by a mutex.
+type FilterDb = Arc<Mutex<FilterDatabase>>;
+
+/// Server listener state. Created in the `run` call. It includes a `run` method
+/// which performs the TCP listening and initialization of per-connection state.
+struct Server {
+    listener: Listener,
+    /// Unix domain socket serving the text and binary protocols too, if enabled
+    unix_listener: Option<Listener>,
+    /// Listener of the RESP clients, if enabled
+    resp_listener: Option<Listener>,
+    /// Listener of the bloomd clients, if enabled
+    bloomd_listener: Option<Listener>,
+    /// Listener of the HTTP API, if enabled
+    http_listener: Option<Listener>,
+    /// Listener of the gRPC service, if enabled
+    grpc_listener: Option<Listener>,
+    /// Tcp exponential backoff threshold
+    backoff: u64,
+    /// Filter manager map
+    db: FilterDb,
+    config: Config,
+}
+
+impl Server {
+    /// Init the shared database object by indexing the filters stored at the default path. Only
+    /// the header of each file is read, filters are registered as cold and loaded on first access,
+    /// apart from the ones listed to be preloaded. If the append-only log is enabled, the
+    /// operations it records are replayed on top of the filters on disk, loading those they touch.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if anything wrong happens while reading from disk and deserializing memory
+    /// maps into memory.
+    pub async fn init(&mut self) -> AsyncResult<()> {
+        let mut db = self.db.lock().await;
+        info!("scanning {}/ for persistent filters", db.data_dir.display());
+        let mut report = index_filters(&mut db).await?;
+        for name in self.config.preload() {
+            match load_cold_filter(&mut db, name).await {
+                Ok(Some(filter)) => {
+                    filter.touch();
+                    info!("preloaded filter {}", filter);
+                }
+                Ok(None) => error!("can't preload unknown filter {}", name),
+                Err(e) => {
+                    error!("can't preload filter {}: {}", name, e);
+                    quarantine_filter(&mut db, name).await?;
+                    report.quarantined += 1;
+                }
+            }
+        }
+        if let Some(policy) = self.config.appendonly() {
+            let path = db.data_dir.join(AOF_FILENAME);
+            let (aof, operations) = AppendOnlyLog::open(&path, policy, db.key.clone()).await?;
+            info!(
+                "replaying {} operations from {}",
+                operations.len(),
+                AOF_FILENAME
+            );
+            for operation in operations {
+                let name = operation.name().to_string();
+                if let Err(e) = replay(&mut db, operation).await {
+                    error!("can't replay operation on filter {}: {}", name, e);
+                    quarantine_filter(&mut db, &name).await?;
+                    report.quarantined += 1;
+                }
+            }
+            db.aof = Some(aof);
+        }
+        if let Some(dir) = db.backup_dir.clone() {
+            find_unbacked_filters(&mut db, &dir).await?;
+        }
+        info!(
+            "{} filters found, {} renamed, {} files skipped, {} quarantined",
+            report.filters, report.renamed, report.skipped, report.quarantined
+        );
+        Ok(())
+    }
+
+    /// Create a new Server and run.
+    ///
+    /// Listen for inbound connections. For each inbound connection, spawn a
+    /// task to process that connection. Returns once asked to shut down with
+    /// Ctrl-C or SIGTERM, after saving the filters changed since their last
+    /// save and syncing the append-only log, the sockets of the Unix listeners
+    /// being removed as they're dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err` if accepting returns an error. This can happen for a
+    /// number reasons that resolve over time. For example, if the underlying
+    /// operating system has reached an internal limit for max number of
+    /// sockets, accept will fail.
+    pub async fn run(&mut self) -> AsyncResult<()> {
+        // Create a clone reference of the filters database to be used by the dump worker
+        let db = self.db.clone();
+        let rules = self.config.save_rules().to_vec();
+        // Spawn a new task to dump changed filters to disk according to the save rules
+        tokio::spawn(async move {
+            if let Err(e) = dump_to_disk(&db, &rules, SAVE_CHECK_INTERVAL).await {
+                error!("Can't spawn `dump_to_disk` worker: {:?}", e);
+            }
+        });
+        let db = self.db.clone();
+        // And another one to keep only warm filters in memory
+        tokio::spawn(async move {
+            if let Err(e) = dump_cold_filters(&db, DUMP_COLD_INTERVAL).await {
+                error!("Can't spawn `dump_cold_filters` worker: {:?}", e);
+            }
+        });
+        if let Some(config) = self.config.backup().cloned() {
+            let db = self.db.clone();
+            // Another one to back up changed filters on schedule
+            tokio::spawn(async move {
+                if let Err(e) = backup_filters(&db, &config).await {
+                    error!("Can't spawn `backup_filters` worker: {:?}", e);
+                }
+            });
+        }
+        if self.config.appendonly() == Some(FsyncPolicy::EverySec) {
+            let db = self.db.clone();
+            // And a last one to sync the append-only log to disk every second
+            tokio::spawn(async move {
+                if let Err(e) = sync_append_only_log(&db, AOF_SYNC_INTERVAL).await {
+                    error!("Can't spawn `sync_append_only_log` worker: {:?}", e);
+                }
+            });
+        }
+        if let Some(listener) = self.resp_listener.take() {
+            let (db, backoff) = (self.db.clone(), self.backoff);
+            // RESP clients are served by their own task too
+            tokio::spawn(async move {
+                if let Err(e) = serve_resp(listener, db, backoff).await {
+                    error!("Can't spawn `serve_resp` worker: {:?}", e);
+                }
+            });
+        }
+        if let Some(listener) = self.bloomd_listener.take() {
+            let (db, backoff) = (self.db.clone(), self.backoff);
+            // And so are bloomd clients
+            tokio::spawn(async move {
+                if let Err(e) = serve_bloomd(listener, db, backoff).await {
+                    error!("Can't spawn `serve_bloomd` worker: {:?}", e);
+                }
+            });
+        }
+        if let Some(listener) = self.http_listener.take() {
+            let db = self.db.clone();
+            // The HTTP API is served by hyper in its own task
+            tokio::spawn(async move {
+                if let Err(e) = serve_http(listener, db).await {
+                    error!("Can't spawn `serve_http` worker: {:?}", e);
+                }
+            });
+        }
+        if let Some(listener) = self.grpc_listener.take() {
+            let db = self.db.clone();
+            // And the gRPC service by tonic
+            tokio::spawn(async move {
+                if let Err(e) = serve_grpc(listener, db).await {
+                    error!("Can't spawn `serve_grpc` worker: {:?}", e);
+                }
+            });
+        }
+        if let Some(listener) = self.unix_listener.take() {
+            let (db, backoff) = (self.db.clone(), self.backoff);
+            // Clients of the Unix domain socket are served like the ones of the main listener
+            tokio::spawn(async move {
+                if let Err(e) = serve_clients(&listener, db, backoff).await {
+                    error!("Can't spawn `serve_clients` worker: {:?}", e);
+                }
+            });
+        }
+        tokio::select! {
+            result = serve_clients(&self.listener, self.db.clone(), self.backoff) => result,
+            result = shutdown_signal() => {
+                info!("shutting down");
+                save_on_shutdown(&self.db).await;
+                result
+            }
+        }
+    }
+}
+
+/// Wait for the server to be asked to shut down, with Ctrl-C or SIGTERM on Unix
+async fn shutdown_signal() -> AsyncResult<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Loop forever on new connections of the text and binary protocols, accept them and pass the
+/// handling to a worker.
+async fn serve_clients(listener: &Listener, db: FilterDb, backoff: u64) -> AsyncResult<()> {
+    loop {
+        // Accepts a new connection, obtaining a valid socket.
+        let (stream, peer) = accept(listener, backoff).await?;
+        info!("connection from {}", peer);
+        // Create a clone reference of the filters database to be used by this connection.
+        let db = db.clone();
+        // Spawn a new task to process the connection, moving the ownership of the cloned
+        // db into the async closure.
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, db).await {
+                error!("error on connection: {}", e);
+            }
+            info!("connection closed by client");
+        });
+    }
+}
+
+/// Serve a client of the text protocol, or of the binary one if it starts with its handshake
+async fn handle_connection<S>(mut stream: S, db: FilterDb) -> AsyncResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let first = match stream.read_u8().await {
+        Ok(first) => first,
+        // Closed without a word
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if first == BINARY_HANDSHAKE {
+        stream.write_u8(BINARY_HANDSHAKE).await?;
+        return serve_binary(Framed::new(stream, BinaryCodec::new()), &db).await;
+    }
+    // The protocol is line-based, `LinesCodec` is useful to automatically handle
+    // this by converting the stream of bytes into a stream of lines.
+    let mut lines = Framed::new(stream, LinesCodec::new());
+    lines.read_buffer_mut().put_u8(first);
+    // Parse each line returned by the codec and by leveraging `LinesCodec` once again
+    // send a response back to the client.
+    while let Some(result) = lines.next().await {
+        match result {
+            Ok(line) => {
+                let response = handle_request(&line, &db).await;
+                let response = response.serialize();
+                if let Err(e) = lines.send(response.as_str()).await {
+                    error!("error sending response: {:?}", e);
+                }
+            }
+            Err(e) => {
+                error!("error on deconding from stream: {:?}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Answer the requests of a binary client. Replies are buffered while pipelined requests are
+/// waiting to be read, and flushed once they have all been answered.
+async fn serve_binary<S>(mut frames: Framed<S, BinaryCodec>, db: &FilterDb) -> AsyncResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Some(request) = frames.next().await {
+        // Without a frame there's no telling where the next one starts, nor which request failed
+        let request = request?;
+        let reply = match request.operation {
+            Ok(operation) => handle_binary_operation(operation, db).await,
+            Err(message) => Reply::Error(message),
+        };
+        frames
+            .feed(BinaryResponse {
+                id: request.id,
+                reply,
+            })
+            .await?;
+        if frames.read_buffer().is_empty() {
+            frames.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Map an operation of a binary client onto the `Request`s of the text protocol
+async fn handle_binary_operation(operation: BinaryOperation, db: &FilterDb) -> Reply {
+    let request = match operation {
+        BinaryOperation::Text(line) => {
+            return Reply::Text(handle_request(&line, db).await.serialize());
+        }
+        BinaryOperation::SetBatch { name, keys } => {
+            return binary_batch(name, keys, true, db).await
+        }
+        BinaryOperation::CheckBatch { name, keys } => {
+            return binary_batch(name, keys, false, db).await
+        }
+        BinaryOperation::Create {
+            name,
+            capacity,
+            fpp,
+            mmap,
+            dir,
+        } => Request::Create {
+            name,
+            capacity: match capacity {
+                0 => DEFAULT_CAPACITY.parse().unwrap(),
+                capacity => capacity as usize,
+            },
+            fpp: if fpp == 0. {
+                DEFAULT_FPP.parse().unwrap()
+            } else {
+                fpp
+            },
+            storage: if mmap {
+                BitmapStorage::Mapped
+            } else {
+                BitmapStorage::Heap
+            },
+            dir: dir.map(PathBuf::from),
+        },
+        BinaryOperation::Set { name, key } => Request::Set { name, key },
+        BinaryOperation::Check { name, key } => Request::Check { name, key },
+        BinaryOperation::Drop { name, keep_data } => Request::Drop { name, keep_data },
+        BinaryOperation::Clear { name } => Request::Clear { name },
+    };
+    if let Err(e) = request_name(&request).map(parse_name).transpose() {
+        return Reply::Error(e.message);
+    }
+    binary_reply(execute(request, db).await)
+}
+
+// Name of the filter a request of the binary protocol is about, validated like the text protocol
+fn request_name(request: &Request) -> Option<&str> {
+    match request {
+        Request::Create { name, .. }
+        | Request::Set { name, .. }
+        | Request::Check { name, .. }
+        | Request::Drop { name, .. }
+        | Request::Clear { name } => Some(name),
+        _ => None,
+    }
+}
+
+// Set or check every key of a batch, holding the lock all along and stopping at the first error.
+// Sets are answered with `Done`, checks with whether each key is present.
+async fn binary_batch(name: String, keys: Vec<Vec<u8>>, set: bool, db: &FilterDb) -> Reply {
+    if let Err(e) = parse_name(&name) {
+        return Reply::Error(e.message);
+    }
+    let mut db = db.lock().await;
+    if !db.contains(&name) {
+        return Reply::Error(format!("no scalable filter named {}", name));
+    }
+    let mut present = Vec::with_capacity(keys.len());
+    for key in keys {
+        let name = name.clone();
+        let request = if set {
+            Request::Set { name, key }
+        } else {
+            Request::Check { name, key }
+        };
+        match binary_reply(apply(request, &mut db).await) {
+            Reply::Present(p) => present.push(p),
+            Reply::Done => (),
+            reply => return reply,
+        }
+    }
+    if set {
+        Reply::Done
+    } else {
+        Reply::Batch(present)
+    }
+}
+
+fn binary_reply(response: Response) -> Reply {
+    match response {
+        Response::True => Reply::Present(true),
+        Response::False => Reply::Present(false),
+        Response::Error(message) => Reply::Error(message),
+        // Only the text passthrough gets anything else, serialized
+        _ => Reply::Done,
+    }
+}
+
+/// Accept an inbound connection.
+///
+/// Errors are handled by backing off and retrying. An exponential backoff
+/// strategy is used. After the first failure, the task waits for 1 second.
+/// After the second failure, the task waits for 2 seconds. Each subsequent
+/// failure doubles the wait time. If accepting fails on the 6th try after
+/// waiting for 64 seconds, then this function returns with an error.
+async fn accept(listener: &Listener, max_backoff: u64) -> AsyncResult<(Connection, String)> {
+    let mut backoff = 1;
+
+    // Try to accept a few times
+    loop {
+        // Perform the accept operation. If a socket is successfully
+        // accepted, return it. Otherwise, save the error.
+        match listener.accept().await {
+            Ok((socket, peer)) => return Ok((socket, peer)),
+            Err(err) => {
+                if backoff > max_backoff {
+                    // Accept has failed too many times. Return the error.
+                    return Err(err.into());
+                }
+            }
+        }
+
+        // Pause execution until the back off period elapses.
+        sleep(Duration::from_secs(backoff)).await;
+
+        // Double the back off
+        backoff *= 2;
+    }
+}
+
+/// Write to disk the scalable filters matching any of the save `rules`, checked every `interval`
+/// seconds, meant to run as a tokio task. Only filters changed since their last save are written.
+/// Once a filter is safely on disk its operations are dropped from the append-only log, see
+/// `compact_append_only_log`.
+async fn dump_to_disk(db: &FilterDb, rules: &[SaveRule], interval: u64) -> AsyncResult<()> {
+    loop {
+        // Sleep for a defined timeout
+        sleep(Duration::from_secs(interval)).await;
+        let mut db = db.lock().await;
+        let now = Utc::now();
+        save_filters(&mut db, |v| rules.iter().any(|r| r.matches(v, now))).await;
+        drop(db);
+    }
+}
+
+/// Write to disk the scalable filters matching `save`, then rewrite the append-only log if any
+/// was saved, see `compact_append_only_log`
+async fn save_filters<F>(db: &mut FilterDatabase, save: F)
+where
+    F: Fn(&ScalableBloomFilter) -> bool,
+{
+    let mut saved = 0;
+    for (_, v) in db.filters.iter_mut() {
+        if !save(v) {
+            continue;
+        }
+        match v.to_file(db.compression, db.key.as_ref()).await {
+            Ok(()) => {
+                info!("{} filter dumped to disk, {} changes", v, v.changes());
+                v.mark_saved();
+                saved += 1;
+            }
+            Err(e) => error!("{} filter dump error: {:?}", v, e),
+        }
+    }
+    if saved > 0 {
+        if let Err(e) = compact_append_only_log(db).await {
+            error!("append-only log rewrite error: {:?}", e);
+        }
+    }
+}
+
+/// Save the filters changed since their last save and sync the append-only log when the server
+/// shuts down, so that no acknowledged write is left only in memory or in the page cache
+async fn save_on_shutdown(db: &FilterDb) {
+    let mut db = db.lock().await;
+    save_filters(&mut db, |v| v.is_dirty()).await;
+    if let Some(aof) = db.aof.as_mut() {
+        if let Err(e) = aof.sync().await {
+            error!("append-only log sync error: {:?}", e);
+        }
+    }
+}
+
+/// Rewrite the append-only log once filters were saved, keeping the records of the filters still
+/// dirty. Of the filters neither in memory nor cold, only the drops whose files couldn't be
+/// deleted are kept, to be completed on replay.
+async fn compact_append_only_log(db: &mut FilterDatabase) -> AsyncResult<()> {
+    let mut aof = match db.aof.take() {
+        Some(aof) => aof,
+        None => return Ok(()),
+    };
+    let result = aof
+        .retain(|op| match db.filters.get(op.name()) {
+            Some(f) => f.is_dirty(),
+            None if db.cold_filters.contains_key(op.name()) => false,
+            None => {
+                let path = db.filter_path(op.name());
+                matches!(op, Operation::Drop { .. })
+                    && (path.exists() || ScalableBloomFilter::layer_path(&path, 0).exists())
+            }
+        })
+        .await;
+    db.aof = Some(aof);
+    result
+}
+
+/// Sync the append-only log to disk every `interval` seconds, meant to run as a tokio task when
+/// the `EverySec` fsync policy is configured
+async fn sync_append_only_log(db: &FilterDb, interval: u64) -> AsyncResult<()> {
+    loop {
+        sleep(Duration::from_secs(interval)).await;
+        let mut db = db.lock().await;
+        if let Some(aof) = db.aof.as_mut() {
+            if let Err(e) = aof.sync().await {
+                error!("append-only log sync error: {:?}", e);
+            }
+        }
+        drop(db);
+    }
+}
+
+/// Outcome of the scan of the data directory on startup
+#[derive(Debug, Default, PartialEq)]
+struct ScanReport {
+    /// Filter files found and registered as cold
+    filters: usize,
+    /// Unrelated files ignored
+    skipped: usize,
+    /// Filter files that can't be read, moved to the quarantine directory
+    quarantined: usize,
+    /// Filter files written under the raw name of their filter, renamed to its encoded name
+    renamed: usize,
+}
+
+/// Register every filter file found in the data directory as a cold filter, reading only its
+/// header, as well as the filters recorded to be stored elsewhere. Files whose header is invalid
+/// are moved into the quarantine directory, files that aren't filters are skipped. Files written
+/// before filter names were encoded into file names are renamed.
+///
+/// # Errors
+///
+/// Returns `Err` if the directory can't be read or a file can't be moved into quarantine.
+async fn index_filters(db: &mut FilterDatabase) -> AsyncResult<ScanReport> {
+    let mut report = ScanReport::default();
+    let dir = db.data_dir.clone();
+    db.locations = load_locations(&dir).await?;
+    let mut entries = fs::read_dir(&dir).await?;
+    let mut legacy = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        // Leftovers of a write interrupted by a crash, the previous snapshot is still intact
+        if path.extension() == Some(OsStr::new(TMP_EXTENSION)) {
+            info!("removing incomplete write {}", path.display());
+            fs::remove_file(&path).await?;
+            continue;
+        }
+        // Bitmaps of memory-mapped filters are mapped when their filter is read
+        if entry.file_name() == AOF_FILENAME
+            || entry.file_name() == LOCATIONS_FILENAME
+            || path.extension() == Some(OsStr::new(BITMAP_EXTENSION))
+            || entry.file_type().await?.is_dir()
+        {
+            continue;
+        }
+        let stem = path.file_stem().and_then(|n| n.to_str());
+        match stem.map(|stem| (stem, filter::decode_name(stem))) {
+            Some((_, Some(name))) if path.extension() == Some(OsStr::new(FILTER_EXTENSION)) => {
+                index_filter(db, &mut report, &name, &path).await?
+            }
+            // Renamed once the directory has been read, to be found only once
+            Some((stem, None))
+                if path.extension() == Some(OsStr::new(FILTER_EXTENSION))
+                    && filter::validate_name(stem).is_ok() =>
+            {
+                legacy.push(stem.to_string())
+            }
+            _ if path.extension() == Some(OsStr::new(FILTER_EXTENSION)) => {
+                error!(
+                    "skipping {}, not a valid filter name, rename the file to serve it",
+                    path.display()
+                );
+                report.skipped += 1;
+            }
+            _ => {
+                info!("skipping {}, not a filter file", path.display());
+                report.skipped += 1;
+            }
+        }
+    }
+    for name in legacy {
+        match rename_legacy_files(&dir, &name).await {
+            Ok(_) => {
+                report.renamed += 1;
+                let path = db.filter_path(&name);
+                index_filter(db, &mut report, &name, &path).await?
+            }
+            Err(e) => {
+                error!("{}", e);
+                report.skipped += 1;
+            }
+        }
+    }
+    let locations: Vec<(String, PathBuf)> = db
+        .locations
+        .iter()
+        .map(|(name, dir)| (name.clone(), dir.clone()))
+        .collect();
+    for (name, dir) in locations {
+        match rename_legacy_files(&dir, &name).await {
+            Ok(true) => report.renamed += 1,
+            Ok(false) => {}
+            Err(e) => error!("{}", e),
+        }
+        let path = db.filter_path(&name);
+        if fs::metadata(&path).await.is_ok() {
+            index_filter(db, &mut report, &name, &path).await?;
+        } else {
+            error!("filter {} not found at {}", name, path.display());
+        }
+    }
+    Ok(report)
+}
+
+/// Key encrypting the files written to disk, if encryption at rest is configured
+async fn encryption_key(config: &Config) -> AsyncResult<Option<Key>> {
+    match config.encryption_key_file() {
+        Some(path) => Ok(Some(Key::from_file(path).await?)),
+        None => Ok(None),
+    }
+}
+
+// Memory-mapped bitmaps are written as is, with a key configured they're the only plaintext left
+fn warn_plaintext_bitmaps(path: &Path, header: Option<&storage::Header>, key: Option<&Key>) {
+    if let (Some(header), Some(_)) = (header, key) {
+        if header.flags & storage::FLAG_MAPPED != 0 {
+            error!(
+                "{} keeps its memory-mapped bitmaps in plaintext, they aren't encrypted at rest",
+                path.display()
+            );
+        }
+    }
+}
+
+// Refuse to restore a filter with memory-mapped bitmaps while encryption at rest is enabled, they
+// would be written in plaintext
+fn check_encryptable(db: &FilterDatabase, entry: &snapshot::Entry) -> AsyncResult<()> {
+    if db.key.is_some() && !entry.files.bitmaps.is_empty() {
+        return Err(format!(
+            "filter {} uses mmap storage, unavailable with encryption at rest",
+            entry.name
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Rename the files of the filter `name` stored in `dir` under its raw name, as they were before
+/// filter names were encoded into file names, to the encoded name. Returns whether there were
+/// such files.
+///
+/// # Errors
+///
+/// Returns `Err` if the files can't be renamed, or if a file already has the encoded name.
+async fn rename_legacy_files(dir: &Path, name: &str) -> AsyncResult<bool> {
+    let legacy = dir.join(format!("{}.{}", name, FILTER_EXTENSION));
+    let path = ScalableBloomFilter::file_path(dir, name);
+    if legacy == path || fs::metadata(&legacy).await.is_err() {
+        return Ok(false);
+    }
+    if fs::metadata(&path).await.is_ok() {
+        return Err(format!(
+            "can't rename legacy filter file {}, {} already exists",
+            legacy.display(),
+            path.display()
+        )
+        .into());
+    }
+    // The bitmaps first, so that the filter file is never found without them
+    for layer in 0.. {
+        let bitmap = ScalableBloomFilter::layer_path(&legacy, layer);
+        match fs::rename(&bitmap, ScalableBloomFilter::layer_path(&path, layer)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    fs::rename(&legacy, &path).await?;
+    info!(
+        "renamed legacy filter file {} to {}",
+        legacy.display(),
+        path.display()
+    );
+    Ok(true)
+}
+
+/// Register the filter file at `path` as the cold filter `name` if its header is valid, quarantine
+/// it otherwise
+async fn index_filter(
+    db: &mut FilterDatabase,
+    report: &mut ScanReport,
+    name: &str,
+    path: &Path,
+) -> AsyncResult<()> {
+    match storage::read_header(path).await {
+        Ok(header) => {
+            let version = header.as_ref().map(|h| h.version).unwrap_or(0);
+            info!("found persistent filter {} (format v{})", name, version);
+            warn_plaintext_bitmaps(path, header.as_ref(), db.key.as_ref());
+            let last_write = fs::metadata(path).await?.modified()?;
+            db.cold_filters.insert(
+                name.to_string(),
+                ColdFilter {
+                    last_access_time: DateTime::from(last_write),
+                    capacity: None,
+                    fpp: None,
+                    size: None,
+                },
+            );
+            report.filters += 1;
+        }
+        Err(e) => {
+            error!("can't read filter file {}", e);
+            quarantine(path).await?;
+            report.quarantined += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Read the directories of the filters stored out of the data directory `dir`, by name
+async fn load_locations(dir: &Path) -> AsyncResult<HashMap<String, PathBuf>> {
+    match fs::read(dir.join(LOCATIONS_FILENAME)).await {
+        Ok(data) => Ok(serde_yaml::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record the directories of the filters stored out of the data directory
+async fn save_locations(db: &FilterDatabase) -> AsyncResult<()> {
+    let data = serde_yaml::to_string(&db.locations)?;
+    storage::write_atomic(&db.data_dir.join(LOCATIONS_FILENAME), data.as_bytes()).await
+}
+
+/// Move the unreadable file at `path` and its memory-mapped bitmaps if any into the quarantine
+/// subdirectory next to it, out of the way of the next startups but still available for
+/// inspection. The moved files are suffixed with the time of the move, so that a file quarantined
+/// twice never replaces the earlier copy.
+async fn quarantine(path: &Path) -> AsyncResult<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let quarantine_dir = dir.join(QUARANTINE_DIR);
+    fs::create_dir_all(&quarantine_dir).await?;
+    let suffix = Utc::now().format(QUARANTINE_SUFFIX_FORMAT).to_string();
+    // Layers are numbered from 0 without gaps
+    let bitmaps = (0..).map(|layer| ScalableBloomFilter::layer_path(path, layer));
+    for (i, source) in std::iter::once(path.to_path_buf())
+        .chain(bitmaps)
+        .enumerate()
+    {
+        let file_name = match source.file_name() {
+            Some(file_name) => file_name.to_string_lossy(),
+            None => break,
+        };
+        let target = quarantine_dir.join(format!("{}.{}", file_name, suffix));
+        match fs::rename(&source, &target).await {
+            Ok(()) => error!("{} moved to {}", source.display(), target.display()),
+            Err(e) if i > 0 && e.kind() == ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Quarantine the file of the cold filter named `name` which failed to load, forgetting the filter
+async fn quarantine_filter(db: &mut FilterDatabase, name: &str) -> AsyncResult<()> {
+    if db.cold_filters.remove(name).is_some() {
+        quarantine(&db.filter_path(name)).await?;
+    }
+    Ok(())
+}
+
+/// Read the file of the cold filter named `name`, quarantining the filter if the file is corrupt
+async fn read_cold_filter(db: &mut FilterDatabase, name: &str) -> AsyncResult<ScalableBloomFilter> {
+    let path = db.filter_path(name);
+    let outcome = ScalableBloomFilter::from_file(path.to_str().unwrap(), db.key.as_ref()).await;
+    if let Err(e) = &outcome {
+        if e.is::<filter::CorruptFileError>() {
+            error!("can't read filter {}: {}", name, e);
+            quarantine_filter(db, name).await?;
+        }
+    }
+    outcome
+}
+
+/// Filter looked up by `resolve_filter`, either in memory or read from disk for a one-off access
+enum Resolved<'a> {
+    Memory(&'a mut ScalableBloomFilter),
+    Disk(ScalableBloomFilter),
+}
+
+impl Deref for Resolved<'_> {
+    type Target = ScalableBloomFilter;
+
+    fn deref(&self) -> &ScalableBloomFilter {
+        match self {
+            Resolved::Memory(sbf) => sbf,
+            Resolved::Disk(sbf) => sbf,
+        }
+    }
+}
+
+impl DerefMut for Resolved<'_> {
+    fn deref_mut(&mut self) -> &mut ScalableBloomFilter {
+        match self {
+            Resolved::Memory(sbf) => sbf,
+            Resolved::Disk(sbf) => sbf,
+        }
+    }
+}
+
+/// Look up the filter named `name` whatever its residency, every command goes through it. A cold
+/// filter is pulled back into memory if `load` is set, otherwise it's read from disk and left
+/// cold. A cold filter whose file turns out to be corrupt is quarantined and forgotten.
+///
+/// # Errors
+///
+/// Returns the `Response::Error` to send back if there's no such filter or it can't be read.
+async fn resolve_filter<'a>(
+    db: &'a mut FilterDatabase,
+    name: &str,
+    load: bool,
+) -> Result<Resolved<'a>, Response> {
+    let outcome = if !load && db.cold_filters.contains_key(name) {
+        read_cold_filter(db, name)
+            .await
+            .map(|sbf| Some(Resolved::Disk(sbf)))
+    } else {
+        load_cold_filter(db, name)
+            .await
+            .map(|sbf| sbf.map(Resolved::Memory))
+    };
+    match outcome {
+        Ok(Some(sbf)) => Ok(sbf),
+        Ok(None) => Err(Response::Error(format!(
+            "no scalable filter named {}",
+            name
+        ))),
+        Err(e) => Err(Response::Error(format!(
+            "error recovering cold filter named {}: {:?}",
+            name, e
+        ))),
+    }
+}
+
+/// Pull the cold filter named `name` back into memory, marking it as warm again. Returns the
+/// filter if it's in memory, either already or after loading it, `None` if there's no such filter.
+///
+/// # Errors
+///
+/// Returns `Err` if the filter file can't be read, in which case the filter is left cold, or
+/// quarantined if the file is corrupt.
+async fn load_cold_filter<'a>(
+    db: &'a mut FilterDatabase,
+    name: &str,
+) -> AsyncResult<Option<&'a mut ScalableBloomFilter>> {
+    if db.cold_filters.contains_key(name) {
+        let filter = read_cold_filter(db, name).await?;
+        info!("pulling cold filter {} back to memory", name);
+        db.filters.insert(name.to_string(), filter);
+        db.cold_filters.remove(name);
+    }
+    Ok(db.filters.get_mut(name))
+}
+
+/// Apply an operation read back from the append-only log to the database, loading the filter it
+/// applies to if cold
+async fn replay(db: &mut FilterDatabase, operation: Operation) -> AsyncResult<()> {
+    mark_changed(db, &operation);
+    if let Operation::Drop { name } = operation {
+        // The drop was logged but its files may not have been deleted before a crash
+        db.filters.remove(&name);
+        db.cold_filters.remove(&name);
+        ScalableBloomFilter::remove_files(&db.filter_path(&name)).await?;
+        return Ok(());
+    }
+    load_cold_filter(db, operation.name()).await?;
+    match operation {
+        Operation::Create {
+            name,
+            capacity,
+            fpp,
+        } => db.create(name, capacity, fpp, BitmapStorage::Heap),
+        Operation::CreateMapped {
+            name,
+            capacity,
+            fpp,
+        } => db.create(name, capacity, fpp, BitmapStorage::Mapped),
+        Operation::Set { name, key } => match db.filters.get_mut(&name) {
             Some(sbf) => {
-                if let Err(e) = sbf.set(key.as_bytes()) {
-                    Response::Error(format!(
-                        "set \"{}\" into \"{}\" filter failed: {:?}",
-                        key, name, e
-                    ))
+                if let Err(e) = sbf.set(&key) {
+                    error!("replaying set into \"{}\" filter failed: {:?}", name, e);
+                }
+            }
+            None => error!("replaying set into unknown filter {}", name),
+        },
+        Operation::Clear { name } => {
+            if let Some(sbf) = db.filters.get_mut(&name) {
+                sbf.clear();
+            }
+        }
+        Operation::Drop { .. } => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Keep track of the filters changed since their last backup, a dropped filter has nothing left
+/// to back up
+fn mark_changed(db: &mut FilterDatabase, operation: &Operation) {
+    match operation {
+        Operation::Drop { name } => db.unbacked.remove(name),
+        _ => db.unbacked.insert(operation.name().to_string()),
+    };
+}
+
+/// Record a write operation into the append-only log, if enabled, before acknowledging it. A
+/// failure to write the log is reported back to the client, as the operation wouldn't survive
+/// a crash. The filter is also marked as changed since its last backup.
+async fn log_operation(
+    db: &mut FilterDatabase,
+    operation: Operation,
+    response: Response,
+) -> Response {
+    mark_changed(db, &operation);
+    match db.aof.as_mut() {
+        Some(aof) => match aof.append(&operation).await {
+            Ok(()) => response,
+            Err(e) => Response::Error(format!("append-only log write failed: {}", e)),
+        },
+        None => response,
+    }
+}
+
+/// Remove the filter named `name` from memory and from the cold filters, leaving its data on disk
+/// where it's found again on the next startup. Changes not yet saved are written first.
+async fn unload_filter(db: &mut FilterDatabase, name: &str) -> Response {
+    if let Some(sbf) = db.filters.get(name) {
+        if sbf.is_dirty() {
+            if let Err(e) = sbf.to_file(db.compression, db.key.as_ref()).await {
+                return Response::Error(format!("persist failed {}", e));
+            }
+        }
+        db.filters.remove(name);
+    }
+    db.cold_filters.remove(name);
+    db.eviction_failures.remove(name);
+    Response::Done
+}
+
+/// Drop the filter named `name` for good, warm or cold: it's removed from memory, its files are
+/// deleted and its records are purged from the append-only log. The drop is logged first, so that
+/// a crash before the files are deleted completes it on replay.
+async fn drop_filter(db: &mut FilterDatabase, name: String) -> Response {
+    let response = log_operation(db, Operation::Drop { name: name.clone() }, Response::Done).await;
+    if let Response::Error(_) = response {
+        return response;
+    }
+    db.filters.remove(&name);
+    db.cold_filters.remove(&name);
+    db.eviction_failures.remove(&name);
+    let path = db.filter_path(&name);
+    if let Err(e) = ScalableBloomFilter::remove_files(&path).await {
+        return Response::Error(format!("deleting {} failed: {}", path.display(), e));
+    }
+    if db.locations.remove(&name).is_some() {
+        if let Err(e) = save_locations(db).await {
+            error!("can't record filter locations: {:?}", e);
+        }
+    }
+    if let Some(aof) = db.aof.as_mut() {
+        if let Err(e) = aof.retain(|op| op.name() != name).await {
+            error!("append-only log rewrite error: {:?}", e);
+        }
+    }
+    Response::Done
+}
+
+/// Write to disk every scalable filter in the database that is considered cold and evict it from
+/// memory. Cold filters are those that are not accessed since a given time. A filter that can't
+/// be written stays in memory and is retried with an exponential backoff.
+async fn dump_cold_filters(db: &FilterDb, interval: u64) -> AsyncResult<()> {
+    loop {
+        let mut db_ref = db.lock().await;
+        let dbr = db_ref.deref_mut();
+        let now = Utc::now().timestamp();
+        let cold: Vec<String> = dbr
+            .filters
+            .iter()
+            .filter(|(_, v)| now - v.last_access_time().timestamp() > COLD_FILTER_TIMEOUT)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for name in cold {
+            match dbr.eviction_failures.get(&name) {
+                Some(failure) if failure.retry_at > now => continue,
+                _ => evict_filter(dbr, &name, now).await,
+            }
+        }
+        drop(db_ref);
+        // Sleep for a defined timeout
+        sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+/// Write the warm filter named `name` to disk and move it to the cold filters, freeing its
+/// memory. The filter is only removed from memory once the write succeeded.
+async fn close_filter(db: &mut FilterDatabase, name: &str) -> AsyncResult<()> {
+    if let Some(sbf) = db.filters.get(name) {
+        sbf.to_file(db.compression, db.key.as_ref()).await?;
+        db.cold_filters
+            .insert(name.to_string(), ColdFilter::from_filter(sbf));
+        db.filters.remove(name);
+        db.eviction_failures.remove(name);
+    }
+    Ok(())
+}
+
+/// Close the filter named `name` as deemed cold, a failure to write it is recorded to be retried
+/// later.
+async fn evict_filter(db: &mut FilterDatabase, name: &str, now: i64) {
+    match close_filter(db, name).await {
+        Ok(()) => {
+            if let Some(cold) = db.cold_filters.get(name) {
+                info!(
+                    "{} filter dumped to disk as deemed cold - last access time {}",
+                    name, cold.last_access_time
+                );
+            }
+            db.stats.evictions += 1;
+        }
+        Err(e) => {
+            let failure = db
+                .eviction_failures
+                .entry(name.to_string())
+                .or_insert(EvictionFailure {
+                    attempts: 0,
+                    retry_at: now,
+                    error: String::new(),
+                });
+            failure.attempts += 1;
+            failure.retry_at = now + eviction_backoff(failure.attempts);
+            failure.error = e.to_string();
+            db.stats.eviction_failures += 1;
+            error!(
+                "{} filter dump error, attempt {}, retrying at {}: {:?}",
+                name, failure.attempts, failure.retry_at, e
+            );
+        }
+    }
+}
+
+/// Seconds to wait before retrying an eviction that failed `attempts` times, doubling from
+/// `DUMP_COLD_INTERVAL` up to `EVICTION_MAX_BACKOFF`
+fn eviction_backoff(attempts: u32) -> i64 {
+    let backoff = DUMP_COLD_INTERVAL << attempts.saturating_sub(1).min(16);
+    backoff.min(EVICTION_MAX_BACKOFF) as i64
+}
+
+/// Parse a line into a `Request` and return a `Response` based on the outcome of the
+/// operation requested.
+async fn handle_request(line: &str, db: &FilterDb) -> Response {
+    match Request::parse(line) {
+        Ok(request) => execute(request, db).await,
+        Err(e) => Response::Error(e.message),
+    }
+}
+
+/// Execute a `Request`, whatever the protocol it was received through
+async fn execute(request: Request, db: &FilterDb) -> Response {
+    // Snapshots are written without holding the lock on the database
+    if let Request::Snapshot { path } = &request {
+        return snapshot(db, path).await;
+    }
+    let mut db = db.lock().await;
+    apply(request, &mut db).await
+}
+
+/// Execute a `Request` other than a snapshot on the locked database
+async fn apply(request: Request, db: &mut FilterDatabase) -> Response {
+    match request {
+        Request::Create {
+            name,
+            capacity,
+            fpp,
+            storage,
+            dir,
+        } => {
+            // Checked before reaching the append-only log, whatever the protocol
+            if let Err(message) = filter::validate_parameters(capacity, fpp) {
+                return Response::Error(message);
+            }
+            // Memory-mapped bitmaps are written as is, they can't be encrypted
+            if storage == BitmapStorage::Mapped && db.key.is_some() {
+                return Response::Error(
+                    "mmap storage is unavailable with encryption at rest".into(),
+                );
+            }
+            let dir = match dir.map(|dir| db.volume(&dir)).transpose() {
+                Ok(dir) => dir,
+                Err(message) => return Response::Error(message),
+            };
+            if let (Some(dir), false) = (dir, db.contains(&name)) {
+                if let Err(e) = fs::create_dir_all(&dir).await {
+                    return Response::Error(format!("can't create {}: {}", dir.display(), e));
+                }
+                db.locations.insert(name.clone(), dir);
+                if let Err(e) = save_locations(db).await {
+                    db.locations.remove(&name);
+                    return Response::Error(format!("can't record filter location: {}", e));
+                }
+            }
+            db.create(name.clone(), capacity, fpp, storage);
+            let operation = match storage {
+                BitmapStorage::Heap => Operation::Create {
+                    name,
+                    capacity,
+                    fpp,
+                },
+                BitmapStorage::Mapped => Operation::CreateMapped {
+                    name,
+                    capacity,
+                    fpp,
+                },
+            };
+            log_operation(db, operation, Response::Done).await
+        }
+        Request::Set { name, key } => {
+            let response = match resolve_filter(db, &name, true).await {
+                Ok(mut sbf) => {
+                    if let Err(e) = sbf.set(&key) {
+                        Response::Error(format!(
+                            "set \"{}\" into \"{}\" filter failed: {:?}",
+                            String::from_utf8_lossy(&key),
+                            name,
+                            e
+                        ))
+                    } else {
+                        Response::Done
+                    }
+                }
+                Err(response) => response,
+            };
+            if let Response::Done = response {
+                log_operation(db, Operation::Set { name, key }, response).await
+            } else {
+                response
+            }
+        }
+        Request::Check { name, key } => match resolve_filter(db, &name, true).await {
+            Ok(mut sbf) => {
+                if sbf.check(&key) {
+                    Response::True
                 } else {
+                    Response::False
+                }
+            }
+            Err(response) => response,
+        },
+        Request::Info { name } => {
+            let failure = db.eviction_failures.get(&name).cloned();
+            // We don't count info call as actually active operation for a filter, a cold filter
+            // is read from disk without making it warm again
+            match resolve_filter(db, &name, false).await {
+                Ok(sbf) => get_filter_info(&sbf, failure.as_ref()).await,
+                Err(response) => response,
+            }
+        }
+        Request::Drop { name, keep_data } => {
+            if !db.contains(&name) {
+                return Response::Error(format!("no scalable filter named {}", name));
+            }
+            if keep_data {
+                unload_filter(db, &name).await
+            } else {
+                drop_filter(db, name).await
+            }
+        }
+        Request::Clear { name } => match resolve_filter(db, &name, true).await {
+            Ok(mut sbf) => {
+                sbf.clear();
+                log_operation(db, Operation::Clear { name }, Response::Done).await
+            }
+            Err(response) => response,
+        },
+        Request::Persist { name } => {
+            let (compression, key) = (db.compression, db.key.clone());
+            // A cold filter is already persisted, it's rewritten as is without loading it
+            match resolve_filter(db, &name, false).await {
+                Ok(mut sbf) => match sbf.to_file(compression, key.as_ref()).await {
+                    Ok(()) => {
+                        sbf.mark_saved();
+                        Response::Done
+                    }
+                    Err(e) => Response::Error(format!("persist failed {}", e)),
+                },
+                Err(response) => response,
+            }
+        }
+        Request::Close { name } => {
+            if !db.contains(&name) {
+                return Response::Error(format!("no scalable filter named {}", name));
+            }
+            match close_filter(db, &name).await {
+                Ok(()) => Response::Done,
+                Err(e) => Response::Error(format!("persist failed {}", e)),
+            }
+        }
+        Request::Load { name } => match resolve_filter(db, &name, true).await {
+            Ok(mut sbf) => {
+                sbf.touch();
+                Response::Done
+            }
+            Err(response) => response,
+        },
+        Request::List => {
+            let warm = db.filters.values().map(|v| FilterProps {
+                name: v.name().clone(),
+                fpp: Some(v.fpp()),
+                capacity: Some(v.capacity()),
+                size: Some(v.size()),
+                residency: Residency::Warm,
+                last_access_time: v.last_access_time(),
+            });
+            let cold = db.cold_filters.iter().map(|(k, v)| FilterProps {
+                name: k.clone(),
+                fpp: v.fpp,
+                capacity: v.capacity,
+                size: v.size,
+                residency: Residency::Cold,
+                last_access_time: v.last_access_time,
+            });
+            let mut filters: Vec<FilterProps> = warm.chain(cold).collect();
+            filters.sort_by(|a, b| a.name.cmp(&b.name));
+            Response::List { filters }
+        }
+        Request::Stats => Response::Stats {
+            warm: db.filters.len(),
+            cold: db.cold_filters.len(),
+            evictions: db.stats.evictions,
+            eviction_failures: db.stats.eviction_failures,
+            failing_evictions: db.eviction_failures.len(),
+        },
+        Request::Snapshot { .. } => unreachable!("snapshots are handled before locking"),
+        Request::Restore { path } => match restore(db, &path).await {
+            Ok(count) => {
+                info!("restored {} filters from {}", count, path.display());
+                Response::Done
+            }
+            Err(e) => Response::Error(format!("restore failed: {}", e)),
+        },
+        Request::LastSave => Response::LastSave(db.last_snapshot),
+        Request::Backups { name } => match &db.backup_dir {
+            Some(dir) => match backup::list(dir, &name).await {
+                Ok(versions) => Response::Backups(versions),
+                Err(e) => Response::Error(format!("can't list backups: {}", e)),
+            },
+            None => Response::Error("backups are disabled".into()),
+        },
+        Request::Rollback { name, time } => match rollback(db, &name, &time).await {
+            Ok(()) => {
+                info!(
+                    "{} filter rolled back to {}",
+                    name,
+                    backup::format_timestamp(&time)
+                );
+                Response::Done
+            }
+            Err(e) => Response::Error(format!("rollback failed: {}", e)),
+        },
+        Request::Export { name, path } => {
+            let path = match confined_path(&db.interop_dir, &path) {
+                Ok(path) => path,
+                Err(message) => return Response::Error(message),
+            };
+            if let Err(e) = fs::create_dir_all(&db.interop_dir).await {
+                return Response::Error(format!("export failed: {}", e));
+            }
+            match resolve_filter(db, &name, false).await {
+                Ok(sbf) => match redisbloom::export(&sbf, &path).await {
+                    Ok(()) => Response::Done,
+                    Err(e) => Response::Error(format!("export failed: {}", e)),
+                },
+                Err(response) => response,
+            }
+        }
+        Request::Import { name, path, format } => {
+            let path = match confined_path(&db.interop_dir, &path) {
+                Ok(path) => path,
+                Err(message) => return Response::Error(message),
+            };
+            match import(db, &name, &path, format).await {
+                Ok(()) => {
+                    info!("{} filter imported from {}", name, path.display());
                     Response::Done
                 }
+                Err(e) => Response::Error(format!("import failed: {}", e)),
+            }
+        }
+    }
+}
+
+// Commands of RESP clients, those not listed are unknown
+const RESP_COMMANDS: [&str; 14] = [
+    "ping",
+    "echo",
+    "select",
+    "client",
+    "command",
+    "hello",
+    "quit",
+    "bf.reserve",
+    "bf.add",
+    "bf.madd",
+    "bf.exists",
+    "bf.mexists",
+    "bf.info",
+    "del",
+];
+// Fields of `BF.INFO` selecting a single value, with the key of that value in the full reply
+const BF_INFO_FIELDS: [(&str, &str); 5] = [
+    ("capacity", "Capacity"),
+    ("size", "Size"),
+    ("filters", "Number of filters"),
+    ("items", "Number of items inserted"),
+    ("expansion", "Expansion rate"),
+];
+
+/// Answer a command of a RESP client. `BF.*` commands and `DEL` are mapped onto the `Request`s
+/// of the text protocol, while the connection commands Redis clients issue, such as `PING`,
+/// `HELLO` or `CLIENT SETNAME`, are answered directly. Like RedisBloom does, `BF.ADD` and
+/// `BF.MADD` create missing filters, with the defaults of `create`, and `BF.EXISTS` and
+/// `BF.MEXISTS` find no keys in them.
+async fn handle_resp_command(args: &[Vec<u8>], db: &FilterDb, codec: &mut RespCodec) -> Frame {
+    let command = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let argv = &args[1..];
+    let outcome = match (command.as_str(), argv.len()) {
+        ("ping", 0) => Ok(Frame::Simple("PONG".into())),
+        ("ping", 1) | ("echo", 1) => Ok(Frame::Bulk(argv[0].clone())),
+        ("select", 1) if argv[0] == b"0" => Ok(Frame::Simple("OK".into())),
+        ("select", 1) => Err("DB index is out of range".into()),
+        ("client", n) if n > 0 => match argv[0].to_ascii_lowercase().as_slice() {
+            // Clients name themselves on connection, names aren't kept
+            b"setname" | b"setinfo" => Ok(Frame::Simple("OK".into())),
+            sub => Err(format!(
+                "unknown subcommand '{}'",
+                String::from_utf8_lossy(sub)
+            )),
+        },
+        ("command", _) => Ok(Frame::Array(Vec::new())),
+        ("hello", _) => hello(argv, codec),
+        ("quit", _) => Ok(Frame::Simple("OK".into())),
+        ("bf.reserve", n) if n >= 3 => bf_reserve(argv, db).await,
+        ("bf.add", 2) => bf_add(argv, db).await.map(|mut added| added.remove(0)),
+        ("bf.madd", n) if n >= 2 => bf_add(argv, db).await.map(Frame::Array),
+        ("bf.exists", 2) => bf_exists(argv, db).await.map(|mut found| found.remove(0)),
+        ("bf.mexists", n) if n >= 2 => bf_exists(argv, db).await.map(Frame::Array),
+        ("bf.info", 1) | ("bf.info", 2) => bf_info(argv, db).await,
+        ("del", n) if n > 0 => del(argv, db).await,
+        (c, _) if RESP_COMMANDS.contains(&c) => Err(format!(
+            "wrong number of arguments for '{}' command",
+            command
+        )),
+        _ => Err(format!("unknown command '{}'", command)),
+    };
+    outcome.unwrap_or_else(|message| Frame::Error(format!("ERR {}", message)))
+}
+
+// Filter name given to a RESP command, valid like the ones of the text protocol
+fn resp_name(arg: &[u8]) -> Result<String, String> {
+    parse_name(&String::from_utf8_lossy(arg)).map_err(|e| e.message)
+}
+
+fn resp_arg<T: FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+// Reply to a RESP command out of the `Response` to the `Request` it was mapped onto
+fn resp_reply(response: Response) -> Result<Frame, String> {
+    match response {
+        Response::Done => Ok(Frame::Simple("OK".into())),
+        Response::True => Ok(Frame::Integer(1)),
+        Response::False => Ok(Frame::Integer(0)),
+        Response::Error(message) => Err(message),
+        response => Ok(Frame::Bulk(response.serialize().into_bytes())),
+    }
+}
+
+// `HELLO [protover]`, switching the connection to RESP3 or back to RESP2. There are neither
+// users nor client names, the `AUTH` and `SETNAME` options are ignored.
+fn hello(argv: &[Vec<u8>], codec: &mut RespCodec) -> Result<Frame, String> {
+    if let Some(version) = argv.first() {
+        match resp_arg::<i64>(version) {
+            Some(protocol) if protocol == 2 || protocol == 3 => codec.set_protocol(protocol as u8),
+            Some(_) => return Ok(Frame::Error("NOPROTO unsupported protocol version".into())),
+            None => return Err("Protocol version is not an integer or out of range".into()),
+        }
+    }
+    let field = |key: &str, value| (Frame::Bulk(key.as_bytes().to_vec()), value);
+    Ok(Frame::Map(vec![
+        field("server", Frame::Bulk(b"rublo".to_vec())),
+        field("version", Frame::Bulk(env!("CARGO_PKG_VERSION").into())),
+        field("proto", Frame::Integer(codec.protocol() as i64)),
+        field("mode", Frame::Bulk(b"standalone".to_vec())),
+        field("role", Frame::Bulk(b"master".to_vec())),
+        field("modules", Frame::Array(Vec::new())),
+    ]))
+}
+
+// `BF.RESERVE key error_rate capacity [EXPANSION expansion] [NONSCALING]`, mapped onto
+// `Request::Create`. Filters scale by 2, no other expansion is supported.
+async fn bf_reserve(argv: &[Vec<u8>], db: &FilterDb) -> Result<Frame, String> {
+    let name = resp_name(&argv[0])?;
+    let fpp = resp_arg::<f64>(&argv[1])
+        .filter(|fpp| *fpp > 0. && *fpp < 1.)
+        .ok_or("bad error rate")?;
+    let capacity = resp_arg::<usize>(&argv[2])
+        .filter(|capacity| *capacity > 0)
+        .ok_or("bad capacity")?;
+    let mut options = argv[3..].iter();
+    while let Some(option) = options.next() {
+        match option.to_ascii_lowercase().as_slice() {
+            b"expansion" => match options.next().and_then(|e| resp_arg::<u32>(e)) {
+                Some(2) => {}
+                Some(_) => return Err("only an EXPANSION of 2 is supported".into()),
+                None => return Err("bad expansion".into()),
+            },
+            b"nonscaling" => return Err("NONSCALING filters are not supported".into()),
+            _ => return Err("syntax error".into()),
+        }
+    }
+    let mut db = db.lock().await;
+    if db.contains(&name) {
+        return Err("item exists".into());
+    }
+    let request = Request::Create {
+        name,
+        capacity,
+        fpp,
+        storage: BitmapStorage::Heap,
+        dir: None,
+    };
+    resp_reply(apply(request, &mut db).await)
+}
+
+// `BF.ADD key item` and `BF.MADD key item...`, mapped onto `Request::Check` and, for the items
+// not found, `Request::Set`, 1 for each item added and 0 for those which may have been already
+async fn bf_add(argv: &[Vec<u8>], db: &FilterDb) -> Result<Vec<Frame>, String> {
+    let name = resp_name(&argv[0])?;
+    let mut db = db.lock().await;
+    if !db.contains(&name) {
+        let request = Request::Create {
+            name: name.clone(),
+            capacity: DEFAULT_CAPACITY.parse().unwrap(),
+            fpp: DEFAULT_FPP.parse().unwrap(),
+            storage: BitmapStorage::Heap,
+            dir: None,
+        };
+        resp_reply(apply(request, &mut db).await)?;
+    }
+    let mut added = Vec::with_capacity(argv.len() - 1);
+    for key in &argv[1..] {
+        let check = Request::Check {
+            name: name.clone(),
+            key: key.clone(),
+        };
+        added.push(match apply(check, &mut db).await {
+            Response::True => Frame::Integer(0),
+            Response::False => {
+                let set = Request::Set {
+                    name: name.clone(),
+                    key: key.clone(),
+                };
+                resp_reply(apply(set, &mut db).await)?;
+                Frame::Integer(1)
+            }
+            response => resp_reply(response)?,
+        });
+    }
+    Ok(added)
+}
+
+// `BF.EXISTS key item` and `BF.MEXISTS key item...`, mapped onto `Request::Check`
+async fn bf_exists(argv: &[Vec<u8>], db: &FilterDb) -> Result<Vec<Frame>, String> {
+    let name = resp_name(&argv[0])?;
+    let mut db = db.lock().await;
+    if !db.contains(&name) {
+        return Ok(vec![Frame::Integer(0); argv.len() - 1]);
+    }
+    let mut found = Vec::with_capacity(argv.len() - 1);
+    for key in &argv[1..] {
+        let check = Request::Check {
+            name: name.clone(),
+            key: key.clone(),
+        };
+        found.push(resp_reply(apply(check, &mut db).await)?);
+    }
+    Ok(found)
+}
+
+// `BF.INFO key [CAPACITY|SIZE|FILTERS|ITEMS|EXPANSION]`, mapped onto `Request::Info`. The
+// capacity is the one `info` reports, in bits, and the size is the space of the bitmaps.
+async fn bf_info(argv: &[Vec<u8>], db: &FilterDb) -> Result<Frame, String> {
+    let name = resp_name(&argv[0])?;
+    let field = match argv.get(1) {
+        Some(field) => Some(
+            BF_INFO_FIELDS
+                .iter()
+                .position(|(f, _)| field.eq_ignore_ascii_case(f.as_bytes()))
+                .ok_or("Invalid information value")?,
+        ),
+        None => None,
+    };
+    let mut db = db.lock().await;
+    if !db.contains(&name) {
+        return Err("not found".into());
+    }
+    match apply(Request::Info { name }, &mut db).await {
+        Response::Info {
+            capacity,
+            size,
+            space,
+            filters,
+            scale_factor,
+            ..
+        } => {
+            let values = [
+                capacity as i64,
+                space.parse().unwrap_or(0),
+                filters as i64,
+                size as i64,
+                scale_factor as i64,
+            ];
+            Ok(match field {
+                Some(i) => Frame::Array(vec![Frame::Integer(values[i])]),
+                None => Frame::Map(
+                    BF_INFO_FIELDS
+                        .iter()
+                        .zip(values.iter())
+                        .map(|((_, key), value)| {
+                            (Frame::Simple(key.to_string()), Frame::Integer(*value))
+                        })
+                        .collect(),
+                ),
+            })
+        }
+        response => resp_reply(response),
+    }
+}
+
+// `DEL key...`, mapped onto `Request::Drop`, counting the filters deleted
+async fn del(argv: &[Vec<u8>], db: &FilterDb) -> Result<Frame, String> {
+    let mut db = db.lock().await;
+    let mut deleted = 0;
+    for arg in argv {
+        // Keys which aren't valid filter names hold no filter
+        let name = match resp_name(arg) {
+            Ok(name) if db.contains(&name) => name,
+            _ => continue,
+        };
+        let request = Request::Drop {
+            name,
+            keep_data: false,
+        };
+        resp_reply(apply(request, &mut db).await)?;
+        deleted += 1;
+    }
+    Ok(Frame::Integer(deleted))
+}
+
+/// Accept connections of RESP clients on `listener`, meant to run as a tokio task
+async fn serve_resp(listener: Listener, db: FilterDb, backoff: u64) -> AsyncResult<()> {
+    loop {
+        let (stream, peer) = accept(&listener, backoff).await?;
+        info!("RESP connection from {}", peer);
+        let db = db.clone();
+        tokio::spawn(async move {
+            let mut frames = Framed::new(stream, RespCodec::new());
+            while let Some(result) = frames.next().await {
+                match result {
+                    Ok(args) => {
+                        let quit = args[0].eq_ignore_ascii_case(b"quit");
+                        let reply = handle_resp_command(&args, &db, frames.codec_mut()).await;
+                        if let Err(e) = frames.send(reply).await {
+                            error!("error sending RESP reply: {}", e);
+                            break;
+                        }
+                        if quit {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        // Like Redis, the connection is closed on protocol errors, there's no
+                        // telling where the next command starts
+                        let _ = frames.send(Frame::Error(format!("ERR {}", e))).await;
+                        break;
+                    }
+                }
+            }
+            info!("RESP connection closed");
+        });
+    }
+}
+
+// Commands of bloomd clients with their aliases, those not listed are unsupported
+const BLOOMD_COMMANDS: [&str; 15] = [
+    "create", "list", "drop", "close", "clear", "check", "c", "multi", "m", "set", "s", "bulk",
+    "b", "info", "flush",
+];
+
+/// Answer a line of a bloomd client, its commands being mapped onto the `Request`s of the text
+/// protocol. Replies are bloomd's ones: `Yes` or `No` for each key, `Done`, `Exists` or
+/// `Filter does not exist` for filters, and `START`/`END` framed lines for `list` and `info`.
+async fn handle_bloomd_command(line: &str, db: &FilterDb) -> String {
+    let mut token = line.split_whitespace();
+    let command = token.next().unwrap_or("");
+    let args: Vec<&str> = token.collect();
+    if !BLOOMD_COMMANDS.contains(&command) {
+        return "Client Error: Command not supported".into();
+    }
+    let mut db = db.lock().await;
+    let (name, args) = match (command, args.split_first()) {
+        ("list", _) if args.len() <= 1 => return bloomd_list(args.first().copied(), &mut db).await,
+        ("flush", None) => return bloomd_flush_all(&mut db).await,
+        (_, Some((name, args))) => match parse_name(name) {
+            Ok(name) => (name, args),
+            Err(_) => return "Client Error: Bad filter name".into(),
+        },
+        _ => return "Client Error: Must provide filter name".into(),
+    };
+    if command == "create" {
+        return bloomd_create(name, args, &mut db).await;
+    }
+    if !db.contains(&name) {
+        return "Filter does not exist".into();
+    }
+    match (command, args.len()) {
+        ("check", 1) | ("c", 1) => bloomd_keys(name, args, false, &mut db).await,
+        ("multi", n) | ("m", n) if n > 0 => bloomd_keys(name, args, false, &mut db).await,
+        ("set", 1) | ("s", 1) => bloomd_keys(name, args, true, &mut db).await,
+        ("bulk", n) | ("b", n) if n > 0 => bloomd_keys(name, args, true, &mut db).await,
+        ("info", 0) => bloomd_info(name, &mut db).await,
+        ("drop", 0) => {
+            let request = Request::Drop {
+                name,
+                keep_data: false,
+            };
+            bloomd_reply(apply(request, &mut db).await)
+        }
+        ("close", 0) => bloomd_reply(apply(Request::Close { name }, &mut db).await),
+        // bloomd's clear forgets a closed filter without deleting its data, filters in memory
+        // have to be closed first
+        ("clear", 0) if db.filters.contains_key(&name) => {
+            "Filter is not proxied. Close it first.".into()
+        }
+        ("clear", 0) => {
+            let request = Request::Drop {
+                name,
+                keep_data: true,
+            };
+            bloomd_reply(apply(request, &mut db).await)
+        }
+        ("flush", 0) => bloomd_reply(apply(Request::Persist { name }, &mut db).await),
+        _ => "Client Error: Bad arguments".into(),
+    }
+}
+
+// Reply to a bloomd command out of the `Response` to the `Request` it was mapped onto
+fn bloomd_reply(response: Response) -> String {
+    match response {
+        Response::Error(message) => format!("Internal Error: {}", message),
+        response => response.serialize(),
+    }
+}
+
+// `create name [capacity=N] [prob=P] [in_memory=0|1]`, mapped onto `Request::Create` with the
+// defaults of `create`. Filters are always persisted, whatever `in_memory` says.
+async fn bloomd_create(name: String, options: &[&str], db: &mut FilterDatabase) -> String {
+    let mut capacity = DEFAULT_CAPACITY.parse().unwrap();
+    let mut fpp = DEFAULT_FPP.parse().unwrap();
+    for option in options {
+        let valid = match option.split_once('=') {
+            Some(("capacity", value)) => value.parse().map(|c| capacity = c).is_ok(),
+            Some(("prob", value)) => value.parse().map(|p| fpp = p).is_ok(),
+            Some(("in_memory", "0")) | Some(("in_memory", "1")) => true,
+            _ => false,
+        };
+        if !valid {
+            return "Client Error: Bad arguments".into();
+        }
+    }
+    if filter::validate_parameters(capacity, fpp).is_err() {
+        return "Client Error: Bad arguments".into();
+    }
+    if db.contains(&name) {
+        return "Exists".into();
+    }
+    let request = Request::Create {
+        name,
+        capacity,
+        fpp,
+        storage: BitmapStorage::Heap,
+        dir: None,
+    };
+    bloomd_reply(apply(request, db).await)
+}
+
+// `check`, `multi`, `set` and `bulk`, mapped onto `Request::Check` and, when setting keys not
+// found, `Request::Set`. Set keys are `Yes` when added and `No` when they may have been already.
+async fn bloomd_keys(name: String, keys: &[&str], set: bool, db: &mut FilterDatabase) -> String {
+    let mut replies = Vec::with_capacity(keys.len());
+    for key in keys {
+        let check = Request::Check {
+            name: name.clone(),
+            key: key.as_bytes().to_vec(),
+        };
+        let found = match apply(check, db).await {
+            Response::True => true,
+            Response::False => false,
+            response => return bloomd_reply(response),
+        };
+        if set && !found {
+            let request = Request::Set {
+                name: name.clone(),
+                key: key.as_bytes().to_vec(),
+            };
+            if let Response::Error(message) = apply(request, db).await {
+                return bloomd_reply(Response::Error(message));
+            }
+        }
+        replies.push(if found != set { "Yes" } else { "No" });
+    }
+    replies.join(" ")
+}
+
+// `info name`, mapped onto `Request::Info`, with bloomd's fields in its order. The capacity is
+// the one `info` reports, in bits. Sets and page ins and outs aren't counted, they're always 0,
+// and checks count the ones made by `set` and `bulk` too.
+async fn bloomd_info(name: String, db: &mut FilterDatabase) -> String {
+    let in_memory = db.filters.contains_key(&name);
+    match apply(Request::Info { name }, db).await {
+        Response::Info {
+            capacity,
+            fpp,
+            size,
+            space,
+            hits,
+            miss,
+            ..
+        } => format!(
+            "START\ncapacity {}\nchecks {}\ncheck_hits {}\ncheck_misses {}\nin_memory {}\npage_ins 0\npage_outs 0\nprobability {}\nsets 0\nset_hits 0\nset_misses 0\nsize {}\nstorage {}\nEND",
+            capacity,
+            hits + miss,
+            hits,
+            miss,
+            in_memory as u8,
+            fpp,
+            size,
+            space
+        ),
+        response => bloomd_reply(response),
+    }
+}
+
+// `list [prefix]`, mapped onto `Request::List`. Each filter is listed with its false positive
+// probability, its space in bytes, its capacity and its size, 0 when unknown for a cold filter
+// not loaded since startup.
+async fn bloomd_list(prefix: Option<&str>, db: &mut FilterDatabase) -> String {
+    match apply(Request::List, db).await {
+        Response::List { filters } => {
+            let mut lines = vec!["START".to_string()];
+            for f in filters {
+                if prefix.is_none_or(|prefix| f.name.starts_with(prefix)) {
+                    let capacity = f.capacity.unwrap_or(0);
+                    lines.push(format!(
+                        "{} {} {} {} {}",
+                        f.name,
+                        f.fpp.unwrap_or(0.),
+                        capacity / 8,
+                        capacity,
+                        f.size.unwrap_or(0)
+                    ));
+                }
+            }
+            lines.push("END".into());
+            lines.join("\n")
+        }
+        response => bloomd_reply(response),
+    }
+}
+
+// `flush` without a filter name, mapped onto a `Request::Persist` of every filter in memory,
+// cold filters being already on disk
+async fn bloomd_flush_all(db: &mut FilterDatabase) -> String {
+    let names: Vec<String> = db.filters.keys().cloned().collect();
+    for name in names {
+        if let Response::Error(message) = apply(Request::Persist { name }, db).await {
+            return bloomd_reply(Response::Error(message));
+        }
+    }
+    "Done".into()
+}
+
+/// Accept connections of bloomd clients on `listener`, meant to run as a tokio task
+async fn serve_bloomd(listener: Listener, db: FilterDb, backoff: u64) -> AsyncResult<()> {
+    loop {
+        let (stream, peer) = accept(&listener, backoff).await?;
+        info!("bloomd connection from {}", peer);
+        let db = db.clone();
+        tokio::spawn(async move {
+            let mut lines = Framed::new(stream, LinesCodec::new());
+            while let Some(result) = lines.next().await {
+                match result {
+                    Ok(line) => {
+                        let reply = handle_bloomd_command(&line, &db).await;
+                        if let Err(e) = lines.send(reply.as_str()).await {
+                            error!("error sending bloomd reply: {}", e);
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!("error decoding bloomd command: {}", e);
+                        break;
+                    }
+                }
+            }
+            info!("bloomd connection closed");
+        });
+    }
+}
+
+// Largest body accepted in an HTTP request
+const MAX_HTTP_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Options of a filter created with `PUT /filters/{name}`, the defaults of `create` when missing
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HttpFilter {
+    capacity: Option<usize>,
+    fpp: Option<f64>,
+    storage: BitmapStorage,
+    dir: Option<PathBuf>,
+}
+
+/// Keys set or checked in a batch, `{"keys": [...]}`
+#[derive(Debug, Deserialize)]
+struct HttpKeys {
+    keys: Vec<String>,
+}
+
+type HttpResult = Result<serde_json::Value, (StatusCode, String)>;
+
+/// Answer a request of the HTTP API with a JSON body, its routes being mapped onto the `Request`s
+/// of the text protocol:
+/// - `GET /filters` lists the filters
+/// - `PUT /filters/{name}` creates a filter, with an optional body of `HttpFilter` options
+/// - `GET /filters/{name}` returns the info of a filter
+/// - `DELETE /filters/{name}[?keep-data]` drops a filter
+/// - `POST /filters/{name}/keys` sets the `HttpKeys` of the body, in order and not atomically:
+///   the keys set before one failing stay set
+/// - `GET /filters/{name}/keys/{key}` checks a key, percent-encoded if need be
+/// - `POST /filters/{name}/check` checks the `HttpKeys` of the body
+///
+/// Failures are answered with `{"error": message}`, the message `handle_request` would give, and
+/// 404 for unknown filters or routes, 400 for any other error.
+async fn handle_http_request(req: hyper::Request<Body>, db: &FilterDb) -> hyper::Response<Body> {
+    let (status, body) = match route_http_request(req, db).await {
+        Ok(body) => (StatusCode::OK, body),
+        Err((status, message)) => (status, json!({ "error": message })),
+    };
+    hyper::Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+async fn route_http_request(req: hyper::Request<Body>, db: &FilterDb) -> HttpResult {
+    let (parts, body) = req.into_parts();
+    let segments: Vec<&str> = parts.uri.path().trim_matches('/').split('/').collect();
+    let (method, query) = (&parts.method, parts.uri.query());
+    if segments == ["filters"] {
+        return match *method {
+            Method::GET => Ok(http_reply(execute(Request::List, db).await)?),
+            _ => Err(http_method_not_allowed()),
+        };
+    }
+    let (name, route) = match segments.split_first() {
+        Some((&"filters", [name, route @ ..])) => (http_name(name)?, route),
+        _ => return Err((StatusCode::NOT_FOUND, "no such route".into())),
+    };
+    match (method, route) {
+        (&Method::PUT, []) => {
+            let body = read_http_body(body).await?;
+            let options: HttpFilter = if body.is_empty() {
+                HttpFilter::default()
+            } else {
+                serde_json::from_slice(&body).map_err(http_bad_request)?
+            };
+            let request = Request::Create {
+                name,
+                capacity: options
+                    .capacity
+                    .unwrap_or_else(|| DEFAULT_CAPACITY.parse().unwrap()),
+                fpp: options.fpp.unwrap_or_else(|| DEFAULT_FPP.parse().unwrap()),
+                storage: options.storage,
+                dir: options.dir,
+            };
+            http_reply(execute(request, db).await)
+        }
+        (&Method::GET, []) => http_apply(Request::Info { name: name.clone() }, &name, db).await,
+        (&Method::DELETE, []) => {
+            // `keep-data`, like the option of `drop`, or `keep_data=true`
+            let keep_data = query.is_some_and(|query| {
+                query
+                    .split('&')
+                    .any(|option| option == "keep-data" || option == "keep_data=true")
+            });
+            let request = Request::Drop {
+                name: name.clone(),
+                keep_data,
+            };
+            http_apply(request, &name, db).await
+        }
+        (&Method::POST, ["keys"]) | (&Method::POST, ["check"]) => {
+            let body = read_http_body(body).await?;
+            let keys: HttpKeys = serde_json::from_slice(&body).map_err(http_bad_request)?;
+            let set = route == ["keys"];
+            let mut db = db.lock().await;
+            if !db.contains(&name) {
+                return Err(http_no_filter(&name));
             }
-            // No warm filter found matching the name given, let's check for any cold fitler stored
-            // on disk, if present, pull it back to memory for faster access, marking it as warm
-            // again, then try to set the value
-            None => match db_ref.cold_filters.get(&name) {
-                Some(fname) => {
-                    let path = Path::new(DEFAULT_DATA_DIR).join(format!("{}.rbl", fname));
-                    let filter = ScalableBloomFilter::from_file(&path.to_str().unwrap()).await;
-                    info!("pulling cold filter {} back to memory", fname);
-                    match filter {
-                        Ok(mut f) => {
-                            let outcome = if let Err(e) = f.set(key.as_bytes()) {
-                                Response::Error(format!(
-                                    "set \"{}\" into \"{}\" filter failed: {:?}",
-                                    key, name, e
-                                ))
-                            } else {
-                                Response::Done
-                            };
-                            // We want to re-insert the filter into the shared database and remove
-                            // it from the cold filters atomically
-                            db_ref.filters.insert(f.name().clone(), f);
-                            db_ref.cold_filters.remove(&name);
-                            outcome
-                        }
-                        Err(e) => Response::Error(format!(
-                            "error recovering cold filter named {}: {:?}",
-                            name, e
-                        )),
-                    }
-                }
-                None => Response::Error(format!("no scalable filter named {}", name)),
-            },
-        },
-        Request::Check { name, key } => match db_ref.filters.get_mut(&name) {
-            // For check operation, the process is analogous to the Set command, we check that a
-            // warm filter matching the name exists and in case, try to check the value
-            Some(sbf) => {
-                if sbf.check(key.as_bytes()) {
-                    Response::True
+            let mut present = Vec::with_capacity(keys.keys.len());
+            for key in keys.keys {
+                let (name, key) = (name.clone(), key.into_bytes());
+                let request = if set {
+                    Request::Set { name, key }
                 } else {
-                    Response::False
-                }
-            }
-            // No warm filter found matching the name given, let's check for any cold fitler stored
-            // on disk, if present, pull it back to memory for faster access, marking it as warm
-            // again, then try to check the value
-            None => match db_ref.cold_filters.get(&name) {
-                Some(fname) => {
-                    let path = Path::new(DEFAULT_DATA_DIR).join(format!("{}.rbl", fname));
-                    let filter = ScalableBloomFilter::from_file(&path.to_str().unwrap()).await;
-                    info!("pulling cold filter {} back to memory", fname);
-                    match filter {
-                        Ok(mut f) => {
-                            let outcome = if f.check(key.as_bytes()) {
-                                Response::True
-                            } else {
-                                Response::False
-                            };
-                            // We want to re-insert the filter into the shared database and remove
-                            // it from the cold filters atomically
-                            db_ref.filters.insert(f.name().clone(), f);
-                            db_ref.cold_filters.remove(&name);
-                            outcome
-                        }
-                        Err(e) => Response::Error(format!(
-                            "error recovering cold filter named {}: {:?}",
-                            name, e
-                        )),
+                    Request::Check { name, key }
+                };
+                match apply(request, &mut db).await {
+                    Response::True => present.push(true),
+                    Response::False => present.push(false),
+                    response => {
+                        http_reply(response)?;
                     }
                 }
-                None => Response::Error(format!("no scalable filter named {}", name)),
+            }
+            if set {
+                Ok(json!({ "done": true }))
+            } else {
+                Ok(json!({ "present": present }))
+            }
+        }
+        (&Method::GET, ["keys", key]) => {
+            let key = percent_decode(key)
+                .ok_or_else(|| http_bad_request("invalid percent-encoding of the key"))?;
+            http_apply(
+                Request::Check {
+                    name: name.clone(),
+                    key,
+                },
+                &name,
+                db,
+            )
+            .await
+        }
+        (_, []) | (_, ["keys"]) | (_, ["check"]) | (_, ["keys", _]) => {
+            Err(http_method_not_allowed())
+        }
+        _ => Err((StatusCode::NOT_FOUND, "no such route".into())),
+    }
+}
+
+// Apply `request` on the filter named `name`, which must exist
+async fn http_apply(request: Request, name: &str, db: &FilterDb) -> HttpResult {
+    let mut db = db.lock().await;
+    if !db.contains(name) {
+        return Err(http_no_filter(name));
+    }
+    http_reply(apply(request, &mut db).await)
+}
+
+// JSON body answering the `Request` a route was mapped onto, out of its `Response`
+fn http_reply(response: Response) -> HttpResult {
+    match response {
+        Response::Done => Ok(json!({ "done": true })),
+        Response::True => Ok(json!({ "present": true })),
+        Response::False => Ok(json!({ "present": false })),
+        Response::Info {
+            name,
+            capacity,
+            size,
+            space,
+            disk_space,
+            filters,
+            scale_factor,
+            hash_count,
+            hits,
+            miss,
+            creation_time,
+            last_access_time,
+            changes,
+            storage,
+            eviction_failures,
+            eviction_error,
+            ..
+        } => Ok(json!({
+            "name": name,
+            "capacity": capacity,
+            "size": size,
+            "space": space.parse::<u64>().unwrap_or(0),
+            "disk_space": disk_space,
+            "filters": filters,
+            "scale_factor": scale_factor as u32,
+            "hash_count": hash_count,
+            "hits": hits,
+            "miss": miss,
+            "creation_time": creation_time,
+            "last_access_time": last_access_time,
+            "changes": changes,
+            "storage": storage,
+            "eviction_failures": eviction_failures,
+            "eviction_error": eviction_error,
+        })),
+        Response::List { filters } => Ok(filters
+            .iter()
+            .map(|f| {
+                json!({
+                    "name": f.name,
+                    "capacity": f.capacity,
+                    "fpp": f.fpp,
+                    "size": f.size,
+                    "residency": f.residency.to_string(),
+                    "last_access_time": f.last_access_time.to_rfc3339(),
+                })
+            })
+            .collect()),
+        Response::Error(message) => Err((StatusCode::BAD_REQUEST, message)),
+        response => Ok(json!({ "result": response.serialize() })),
+    }
+}
+
+// Filter name of a route, valid like the ones of the text protocol
+fn http_name(segment: &str) -> Result<String, (StatusCode, String)> {
+    let name = percent_decode(segment)
+        .and_then(|name| String::from_utf8(name).ok())
+        .ok_or_else(|| http_bad_request("invalid percent-encoding of the filter name"))?;
+    parse_name(&name).map_err(|e| http_bad_request(e.message))
+}
+
+fn http_bad_request<E: fmt::Display>(e: E) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, e.to_string())
+}
+
+fn http_no_filter(name: &str) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        format!("no scalable filter named {}", name),
+    )
+}
+
+fn http_method_not_allowed() -> (StatusCode, String) {
+    (StatusCode::METHOD_NOT_ALLOWED, "method not allowed".into())
+}
+
+// Body of an HTTP request, up to `MAX_HTTP_BODY_SIZE` bytes
+async fn read_http_body(mut body: Body) -> Result<Vec<u8>, (StatusCode, String)> {
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(http_bad_request)?;
+        if data.len() + chunk.len() > MAX_HTTP_BODY_SIZE {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("body larger than {} bytes", MAX_HTTP_BODY_SIZE),
+            ));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+// Bytes of a percent-encoded path segment, `None` if an escape is invalid
+fn percent_decode(segment: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(segment.len());
+    let mut iter = segment.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    Some(bytes)
+}
+
+/// Serve the HTTP API on `listener`, meant to run as a tokio task
+async fn serve_http(listener: Listener, db: FilterDb) -> AsyncResult<()> {
+    let make_service = make_service_fn(move |_| {
+        let db = db.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let db = db.clone();
+                async move { Ok::<_, Infallible>(handle_http_request(req, &db).await) }
+            }))
+        }
+    });
+    hyper::Server::builder(listener).serve(make_service).await?;
+    Ok(())
+}
+
+/// gRPC service of proto/rublo.proto, its RPCs being mapped onto the `Request`s of the text
+/// protocol
+struct GrpcService {
+    db: FilterDb,
+}
+
+type GrpcResult<T> = Result<tonic::Response<T>, Status>;
+type CheckReplies = Pin<Box<dyn Stream<Item = Result<grpc::CheckReply, Status>> + Send>>;
+
+impl GrpcService {
+    // Apply `request` on the filter named `name`, which must exist
+    async fn apply(&self, name: &str, request: Request) -> Result<Response, Status> {
+        let mut db = self.db.lock().await;
+        if !db.contains(name) {
+            return Err(grpc_no_filter(name));
+        }
+        grpc_checked(apply(request, &mut db).await)
+    }
+
+    // Apply `request` on the filter named `name`, replying `Done`
+    async fn done(&self, name: &str, request: Request) -> GrpcResult<grpc::Done> {
+        self.apply(name, request).await?;
+        Ok(tonic::Response::new(grpc::Done {}))
+    }
+
+    // Execute `request`, which doesn't need a filter to exist, replying `Done`
+    async fn execute(&self, request: Request) -> GrpcResult<grpc::Done> {
+        grpc_checked(execute(request, &self.db).await)?;
+        Ok(tonic::Response::new(grpc::Done {}))
+    }
+
+    async fn check_key(&self, key: grpc::KeyRequest) -> Result<grpc::CheckReply, Status> {
+        let name = grpc_name(&key.name)?;
+        let request = Request::Check {
+            name: name.clone(),
+            key: key.key,
+        };
+        let present = matches!(self.apply(&name, request).await?, Response::True);
+        Ok(grpc::CheckReply { present })
+    }
+
+    // Set or check every key of a batch, holding the lock all along
+    async fn batch(&self, keys: grpc::KeysRequest, set: bool) -> Result<Vec<bool>, Status> {
+        let name = grpc_name(&keys.name)?;
+        let mut db = self.db.lock().await;
+        if !db.contains(&name) {
+            return Err(grpc_no_filter(&name));
+        }
+        let mut present = Vec::with_capacity(keys.keys.len());
+        for key in keys.keys {
+            let name = name.clone();
+            let request = if set {
+                Request::Set { name, key }
+            } else {
+                Request::Check { name, key }
+            };
+            present.push(matches!(
+                grpc_checked(apply(request, &mut db).await)?,
+                Response::True
+            ));
+        }
+        Ok(present)
+    }
+}
+
+// `Err` with the message of the text protocol if the request failed. The helpers return the
+// `Status` that tonic handlers reply with, however large it is.
+#[allow(clippy::result_large_err)]
+fn grpc_checked(response: Response) -> Result<Response, Status> {
+    match response {
+        Response::Error(message) => Err(Status::invalid_argument(message)),
+        response => Ok(response),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn grpc_name(name: &str) -> Result<String, Status> {
+    parse_name(name).map_err(|e| Status::invalid_argument(e.message))
+}
+
+#[allow(clippy::result_large_err)]
+fn grpc_path(path: String, message: &str) -> Result<PathBuf, Status> {
+    if path.is_empty() {
+        Err(Status::invalid_argument(message))
+    } else {
+        Ok(PathBuf::from(path))
+    }
+}
+
+fn grpc_no_filter(name: &str) -> Status {
+    Status::not_found(format!("no scalable filter named {}", name))
+}
+
+#[tonic::async_trait]
+impl grpc::rublo_server::Rublo for GrpcService {
+    type CheckStreamStream = CheckReplies;
+
+    async fn create(&self, request: tonic::Request<grpc::CreateRequest>) -> GrpcResult<grpc::Done> {
+        let request = request.into_inner();
+        let storage = match grpc::Storage::try_from(request.storage) {
+            Ok(grpc::Storage::Heap) => BitmapStorage::Heap,
+            Ok(grpc::Storage::Mmap) => BitmapStorage::Mapped,
+            Err(_) => return Err(Status::invalid_argument("storage must be heap or mmap")),
+        };
+        let request = Request::Create {
+            name: grpc_name(&request.name)?,
+            capacity: match request.capacity {
+                0 => DEFAULT_CAPACITY.parse().unwrap(),
+                capacity => capacity as usize,
             },
-        },
-        Request::Info { name } => match db.filters.get(&name) {
-            // Same for info operation, the process is analogous to the Set command, we check that
-            // a warm filter matching the name exists and in case, try to retrieve info from the
-            // filter
-            Some(sbf) => get_filter_info(&sbf),
-            // No warm filter found matching the name given, let's check for any cold fitler stored
-            // on disk, if present, pull it back to memory for faster access, but without making it
-            // warm again, we don't count info call as actually active operation for a filter
-            None => match db.cold_filters.get(&name) {
-                Some(fname) => {
-                    let path = Path::new(DEFAULT_DATA_DIR).join(format!("{}.rbl", fname));
-                    let filter = ScalableBloomFilter::from_file(&path.to_str().unwrap()).await;
-                    match filter {
-                        Ok(f) => get_filter_info(&f),
-                        Err(e) => Response::Error(format!(
-                            "error recovering cold scalable filter named {}: {:?}",
-                            name, e
-                        )),
-                    }
-                }
-                None => Response::Error(format!("no scalable filter named {}", name)),
+            fpp: if request.fpp == 0. {
+                DEFAULT_FPP.parse().unwrap()
+            } else {
+                request.fpp
             },
-        },
-        Request::Drop { name } => match db.filters.remove(&name) {
-            Some(_) => Response::Done,
-            None => Response::Error(format!("no scalable filter named {}", name)),
-        },
-        Request::Clear { name } => match db.filters.get_mut(&name) {
-            Some(sbf) => {
-                sbf.clear();
-                Response::Done
+            storage,
+            dir: Some(request.dir)
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from),
+        };
+        self.execute(request).await
+    }
+
+    async fn set(&self, request: tonic::Request<grpc::KeyRequest>) -> GrpcResult<grpc::Done> {
+        let request = request.into_inner();
+        let name = grpc_name(&request.name)?;
+        let key = request.key;
+        self.done(
+            &name,
+            Request::Set {
+                name: name.clone(),
+                key,
+            },
+        )
+        .await
+    }
+
+    async fn check(
+        &self,
+        request: tonic::Request<grpc::KeyRequest>,
+    ) -> GrpcResult<grpc::CheckReply> {
+        Ok(tonic::Response::new(
+            self.check_key(request.into_inner()).await?,
+        ))
+    }
+
+    async fn set_batch(
+        &self,
+        request: tonic::Request<grpc::KeysRequest>,
+    ) -> GrpcResult<grpc::Done> {
+        self.batch(request.into_inner(), true).await?;
+        Ok(tonic::Response::new(grpc::Done {}))
+    }
+
+    async fn check_batch(
+        &self,
+        request: tonic::Request<grpc::KeysRequest>,
+    ) -> GrpcResult<grpc::CheckBatchReply> {
+        let present = self.batch(request.into_inner(), false).await?;
+        Ok(tonic::Response::new(grpc::CheckBatchReply { present }))
+    }
+
+    async fn set_stream(
+        &self,
+        request: tonic::Request<Streaming<grpc::KeyRequest>>,
+    ) -> GrpcResult<grpc::Done> {
+        let mut stream = request.into_inner();
+        while let Some(key) = stream.message().await? {
+            let name = grpc_name(&key.name)?;
+            let request = Request::Set {
+                name: name.clone(),
+                key: key.key,
+            };
+            self.apply(&name, request).await?;
+        }
+        Ok(tonic::Response::new(grpc::Done {}))
+    }
+
+    async fn check_stream(
+        &self,
+        request: tonic::Request<Streaming<grpc::KeyRequest>>,
+    ) -> GrpcResult<CheckReplies> {
+        let db = self.db.clone();
+        let replies = futures::StreamExt::then(request.into_inner(), move |key| {
+            let service = GrpcService { db: db.clone() };
+            async move { service.check_key(key?).await }
+        });
+        Ok(tonic::Response::new(Box::pin(replies)))
+    }
+
+    async fn info(
+        &self,
+        request: tonic::Request<grpc::FilterRequest>,
+    ) -> GrpcResult<grpc::InfoReply> {
+        let name = grpc_name(&request.into_inner().name)?;
+        match self
+            .apply(&name, Request::Info { name: name.clone() })
+            .await?
+        {
+            Response::Info {
+                name,
+                capacity,
+                size,
+                space,
+                disk_space,
+                filters,
+                scale_factor,
+                hash_count,
+                hits,
+                miss,
+                creation_time,
+                last_access_time,
+                changes,
+                storage,
+                eviction_failures,
+                eviction_error,
+                ..
+            } => Ok(tonic::Response::new(grpc::InfoReply {
+                name,
+                capacity: capacity as u64,
+                size: size as u64,
+                space: space.parse().unwrap_or(0),
+                disk_space,
+                filters,
+                scale_factor: scale_factor as u32,
+                hash_count,
+                hits,
+                miss,
+                creation_time,
+                last_access_time,
+                changes,
+                storage: match storage {
+                    BitmapStorage::Heap => grpc::Storage::Heap,
+                    BitmapStorage::Mapped => grpc::Storage::Mmap,
+                } as i32,
+                eviction_failures,
+                eviction_error,
+            })),
+            _ => Err(Status::internal("unexpected reply to info")),
+        }
+    }
+
+    async fn drop(&self, request: tonic::Request<grpc::DropRequest>) -> GrpcResult<grpc::Done> {
+        let request = request.into_inner();
+        let name = grpc_name(&request.name)?;
+        let keep_data = request.keep_data;
+        self.done(
+            &name,
+            Request::Drop {
+                name: name.clone(),
+                keep_data,
+            },
+        )
+        .await
+    }
+
+    async fn clear(&self, request: tonic::Request<grpc::FilterRequest>) -> GrpcResult<grpc::Done> {
+        let name = grpc_name(&request.into_inner().name)?;
+        self.done(&name, Request::Clear { name: name.clone() })
+            .await
+    }
+
+    async fn persist(
+        &self,
+        request: tonic::Request<grpc::FilterRequest>,
+    ) -> GrpcResult<grpc::Done> {
+        let name = grpc_name(&request.into_inner().name)?;
+        self.done(&name, Request::Persist { name: name.clone() })
+            .await
+    }
+
+    async fn close(&self, request: tonic::Request<grpc::FilterRequest>) -> GrpcResult<grpc::Done> {
+        let name = grpc_name(&request.into_inner().name)?;
+        self.done(&name, Request::Close { name: name.clone() })
+            .await
+    }
+
+    async fn load(&self, request: tonic::Request<grpc::FilterRequest>) -> GrpcResult<grpc::Done> {
+        let name = grpc_name(&request.into_inner().name)?;
+        self.done(&name, Request::Load { name: name.clone() }).await
+    }
+
+    async fn list(&self, _: tonic::Request<grpc::ListRequest>) -> GrpcResult<grpc::ListReply> {
+        match execute(Request::List, &self.db).await {
+            Response::List { filters } => Ok(tonic::Response::new(grpc::ListReply {
+                filters: filters
+                    .into_iter()
+                    .map(|f| grpc::FilterSummary {
+                        name: f.name,
+                        capacity: f.capacity.map(|c| c as u64),
+                        fpp: f.fpp,
+                        size: f.size.map(|s| s as u64),
+                        residency: match f.residency {
+                            Residency::Warm => grpc::Residency::Warm,
+                            Residency::Cold => grpc::Residency::Cold,
+                        } as i32,
+                        last_access_time: f.last_access_time.to_rfc3339(),
+                    })
+                    .collect(),
+            })),
+            response => Err(grpc_checked(response)
+                .err()
+                .unwrap_or_else(|| Status::internal("unexpected reply to list"))),
+        }
+    }
+
+    async fn stats(&self, _: tonic::Request<grpc::StatsRequest>) -> GrpcResult<grpc::StatsReply> {
+        match execute(Request::Stats, &self.db).await {
+            Response::Stats {
+                warm,
+                cold,
+                evictions,
+                eviction_failures,
+                failing_evictions,
+            } => Ok(tonic::Response::new(grpc::StatsReply {
+                warm: warm as u64,
+                cold: cold as u64,
+                evictions,
+                eviction_failures,
+                failing_evictions: failing_evictions as u64,
+            })),
+            _ => Err(Status::internal("unexpected reply to stats")),
+        }
+    }
+
+    async fn snapshot(&self, request: tonic::Request<grpc::PathRequest>) -> GrpcResult<grpc::Done> {
+        let path = grpc_path(request.into_inner().path, "missing snapshot path")?;
+        self.execute(Request::Snapshot { path }).await
+    }
+
+    async fn restore(&self, request: tonic::Request<grpc::PathRequest>) -> GrpcResult<grpc::Done> {
+        let path = grpc_path(request.into_inner().path, "missing snapshot path")?;
+        self.execute(Request::Restore { path }).await
+    }
+
+    async fn last_save(
+        &self,
+        _: tonic::Request<grpc::LastSaveRequest>,
+    ) -> GrpcResult<grpc::LastSaveReply> {
+        match execute(Request::LastSave, &self.db).await {
+            Response::LastSave(time) => Ok(tonic::Response::new(grpc::LastSaveReply {
+                time: time.map(|t| t.to_rfc3339()),
+            })),
+            _ => Err(Status::internal("unexpected reply to lastsave")),
+        }
+    }
+
+    async fn backups(
+        &self,
+        request: tonic::Request<grpc::FilterRequest>,
+    ) -> GrpcResult<grpc::BackupsReply> {
+        let name = grpc_name(&request.into_inner().name)?;
+        // Backups of dropped filters are listed too
+        match grpc_checked(execute(Request::Backups { name }, &self.db).await)? {
+            Response::Backups(versions) => Ok(tonic::Response::new(grpc::BackupsReply {
+                timestamps: versions.iter().map(backup::format_timestamp).collect(),
+            })),
+            _ => Err(Status::internal("unexpected reply to backups")),
+        }
+    }
+
+    async fn rollback(
+        &self,
+        request: tonic::Request<grpc::RollbackRequest>,
+    ) -> GrpcResult<grpc::Done> {
+        let request = request.into_inner();
+        let time = backup::parse_timestamp(&request.timestamp).ok_or_else(|| {
+            Status::invalid_argument(format!("invalid backup timestamp {}", request.timestamp))
+        })?;
+        let name = grpc_name(&request.name)?;
+        self.execute(Request::Rollback { name, time }).await
+    }
+
+    async fn export(&self, request: tonic::Request<grpc::ExportRequest>) -> GrpcResult<grpc::Done> {
+        let request = request.into_inner();
+        let name = grpc_name(&request.name)?;
+        let path = grpc_path(request.path, "missing export path")?;
+        self.done(
+            &name,
+            Request::Export {
+                name: name.clone(),
+                path,
+            },
+        )
+        .await
+    }
+
+    async fn import(&self, request: tonic::Request<grpc::ImportRequest>) -> GrpcResult<grpc::Done> {
+        let request = request.into_inner();
+        let format = match grpc::ImportFormat::try_from(request.format) {
+            Ok(grpc::ImportFormat::Redisbloom) => ImportFormat::RedisBloom,
+            Ok(grpc::ImportFormat::Guava) => ImportFormat::Guava,
+            Ok(grpc::ImportFormat::Pybloom) => ImportFormat::Pybloom,
+            Err(_) => {
+                return Err(Status::invalid_argument(format!(
+                    "unknown import format {}",
+                    request.format
+                )))
             }
-            None => Response::Error(format!("no scalable filter named {}", name)),
-        },
-        Request::Persist { name } => match db.filters.get(&name) {
-            Some(sbf) => match sbf.to_file().await {
-                Ok(()) => Response::Done,
-                Err(e) => Response::Error(format!("persist failed {}", e)),
+        };
+        let request = Request::Import {
+            name: grpc_name(&request.name)?,
+            path: grpc_path(request.path, "missing import path")?,
+            format,
+        };
+        self.execute(request).await
+    }
+}
+
+/// Serve the gRPC service on `listener`, meant to run as a tokio task
+async fn serve_grpc(listener: Listener, db: FilterDb) -> AsyncResult<()> {
+    let incoming = futures::stream::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(connection, _)| connection)))
+    });
+    tonic::transport::Server::builder()
+        .add_service(RubloServer::new(GrpcService { db }))
+        .serve_with_incoming(incoming)
+        .await?;
+    Ok(())
+}
+
+/// Write every filter, warm and cold, into a single archive named `file_name` in the snapshot
+/// directory. Every filter is collected at one consistent point while holding the lock, the cold
+/// ones being read from their files, then the archive is written without it so that other
+/// requests aren't blocked meanwhile.
+async fn snapshot(db: &FilterDb, file_name: &Path) -> Response {
+    let (path, entries, compression, key) = {
+        let db = db.lock().await;
+        let path = match confined_path(&db.snapshot_dir, file_name) {
+            Ok(path) => path,
+            Err(message) => return Response::Error(message),
+        };
+        let mut entries = Vec::with_capacity(db.filters.len() + db.cold_filters.len());
+        for name in db.filters.keys().chain(db.cold_filters.keys()) {
+            match snapshot_entry(&db, name).await {
+                Ok(entry) => entries.push(entry),
+                Err(e) => return Response::Error(format!("snapshot failed: {}", e)),
+            }
+        }
+        (path, entries, db.compression, db.key.clone())
+    };
+    if let Err(e) = fs::create_dir_all(path.parent().unwrap_or(&path)).await {
+        return Response::Error(format!("snapshot failed: {}", e));
+    }
+    if let Err(e) = snapshot::write(&path, &entries, compression, key.as_ref()).await {
+        return Response::Error(format!("snapshot failed: {}", e));
+    }
+    info!("{} filters saved to {}", entries.len(), path.display());
+    db.lock().await.last_snapshot = Some(Utc::now());
+    Response::Done
+}
+
+/// Path of the file named `file_name` in `dir`, for the commands reading or writing files named
+/// by clients, which are confined to a configured directory
+///
+/// # Errors
+///
+/// Returns a message if `file_name` isn't a plain file name, such as a path into another
+/// directory.
+fn confined_path(dir: &Path, file_name: &Path) -> Result<PathBuf, String> {
+    let mut components = file_name.components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(file_name)), None) => Ok(dir.join(file_name)),
+        _ => Err(format!(
+            "{} isn't a file name, paths aren't accepted",
+            file_name.display()
+        )),
+    }
+}
+
+/// Content of the filter named `name`, read from memory if warm and from disk if cold
+async fn snapshot_entry(db: &FilterDatabase, name: &str) -> AsyncResult<snapshot::Entry> {
+    let files = match db.filters.get(name) {
+        Some(sbf) => sbf.to_files(db.compression, db.key.as_ref())?,
+        None if db.cold_filters.contains_key(name) => {
+            FilterFiles::read(&db.filter_path(name)).await?
+        }
+        None => return Err(format!("no scalable filter named {}", name).into()),
+    };
+    Ok(snapshot::Entry {
+        name: name.to_string(),
+        location: db.locations.get(name).cloned(),
+        files,
+    })
+}
+
+/// Load the filters of the snapshot archive named `file_name` in the snapshot directory into an
+/// empty database, returning their count. They're written to their files and registered as cold,
+/// to be loaded on first access.
+async fn restore(db: &mut FilterDatabase, file_name: &Path) -> AsyncResult<usize> {
+    if db.contains_any() {
+        return Err("the server must be empty, drop every filter first".into());
+    }
+    let path = confined_path(&db.snapshot_dir, file_name)?;
+    let entries = snapshot::read(&path, db.key.as_ref()).await?;
+    for entry in entries.iter() {
+        check_encryptable(db, entry)?;
+    }
+    for entry in entries.iter() {
+        if let Some(location) = db.restored_location(&entry.name, entry.location.as_ref()) {
+            fs::create_dir_all(&location).await?;
+            db.locations.insert(entry.name.clone(), location);
+        }
+        entry.files.write(&db.filter_path(&entry.name)).await?;
+        db.unbacked.insert(entry.name.clone());
+        db.cold_filters.insert(
+            entry.name.clone(),
+            ColdFilter {
+                last_access_time: Utc::now(),
+                capacity: None,
+                fpp: None,
+                size: None,
             },
-            None => Response::Error(format!("no scalable filter named {}", name)),
-        },
-        Request::List => {
-            let filters = db
-                .filters
-                .iter()
-                .map(|(_, v)| FilterProps {
-                    name: v.name().clone(),
-                    fpp: v.fpp(),
-                    capacity: v.capacity(),
-                })
-                .collect();
-            Response::List { filters }
+        );
+    }
+    if !db.locations.is_empty() {
+        save_locations(db).await?;
+    }
+    Ok(entries.len())
+}
+
+/// Back up every filter changed since its last backup every `interval` seconds, meant to run as
+/// a tokio task when backups are enabled.
+async fn backup_filters(db: &FilterDb, config: &BackupConfig) -> AsyncResult<()> {
+    loop {
+        sleep(Duration::from_secs(config.interval().max(1))).await;
+        backup_changed_filters(db, config.keep()).await;
+    }
+}
+
+/// Back up the filters changed since their last backup, keeping the `keep` most recent backups
+/// of each one, and return how many were written. The filters are collected at one consistent
+/// point while holding the lock and written without it. A failed backup is retried on the next
+/// run.
+async fn backup_changed_filters(db: &FilterDb, keep: usize) -> usize {
+    let (dir, entries, compression, key) = {
+        let mut db = db.lock().await;
+        let dir = match db.backup_dir.clone() {
+            Some(dir) => dir,
+            None => return 0,
+        };
+        let names: Vec<String> = db.unbacked.drain().collect();
+        let mut entries = Vec::with_capacity(names.len());
+        for name in names {
+            match snapshot_entry(&db, &name).await {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    error!("can't back up filter {}: {}", name, e);
+                    db.unbacked.insert(name);
+                }
+            }
+        }
+        (dir, entries, db.compression, db.key.clone())
+    };
+    let time = Utc::now();
+    let mut written = 0;
+    for entry in entries {
+        match backup::write(&dir, &entry, &time, compression, key.as_ref(), keep).await {
+            Ok(path) => {
+                info!("{} filter backed up to {}", entry.name, path.display());
+                written += 1;
+            }
+            Err(e) => {
+                error!("can't back up filter {}: {}", entry.name, e);
+                db.lock().await.unbacked.insert(entry.name);
+            }
+        }
+    }
+    written
+}
+
+/// Mark as changed since their last backup the filters whose file was written after it, or
+/// that were never backed up
+async fn find_unbacked_filters(db: &mut FilterDatabase, dir: &Path) -> AsyncResult<()> {
+    let names: Vec<String> = db
+        .filters
+        .keys()
+        .chain(db.cold_filters.keys())
+        .cloned()
+        .collect();
+    for name in names {
+        let last_backup = backup::list(dir, &name).await?.pop();
+        let last_write = fs::metadata(db.filter_path(&name))
+            .await
+            .and_then(|m| m.modified())
+            .map(DateTime::<Utc>::from)
+            .ok();
+        match (last_backup, last_write) {
+            (Some(backup), Some(write)) if write <= backup => {}
+            _ => {
+                db.unbacked.insert(name);
+            }
         }
     }
+    Ok(())
+}
+
+/// Replace the filter named `name`, warm or cold, with its backup taken at `time`, a dropped
+/// filter is brought back. The records of the filter are purged from the append-only log, they
+/// apply to the replaced content.
+async fn rollback(db: &mut FilterDatabase, name: &str, time: &DateTime<Utc>) -> AsyncResult<()> {
+    let dir = db.backup_dir.clone().ok_or("backups are disabled")?;
+    let entry = backup::read(&dir, name, time, db.key.as_ref()).await?;
+    check_encryptable(db, &entry)?;
+    let location = db.restored_location(name, entry.location.as_ref());
+    if let (Some(location), false) = (location, db.contains(name)) {
+        fs::create_dir_all(&location).await?;
+        db.locations.insert(name.to_string(), location);
+        save_locations(db).await?;
+    }
+    db.filters.remove(name);
+    db.cold_filters.remove(name);
+    db.eviction_failures.remove(name);
+    let path = db.filter_path(name);
+    ScalableBloomFilter::remove_files(&path).await?;
+    entry.files.write(&path).await?;
+    db.cold_filters.insert(
+        name.to_string(),
+        ColdFilter {
+            last_access_time: Utc::now(),
+            capacity: None,
+            fpp: None,
+            size: None,
+        },
+    );
+    db.unbacked.remove(name);
+    if let Some(aof) = db.aof.as_mut() {
+        aof.retain(|op| op.name() != name).await?;
+    }
+    Ok(())
+}
+
+/// Import the filter written by another library into the file at `path` as a new filter named
+/// `name`. It's written to disk right away, the append-only log only records the changes made from
+/// then on.
+async fn import(
+    db: &mut FilterDatabase,
+    name: &str,
+    path: &Path,
+    format: ImportFormat,
+) -> AsyncResult<()> {
+    if db.contains(name) {
+        return Err(format!("filter {} already exists", name).into());
+    }
+    let sbf = match format {
+        ImportFormat::RedisBloom => redisbloom::import(name.to_string(), path).await?,
+        ImportFormat::Guava => guava::import(name.to_string(), path).await?,
+        ImportFormat::Pybloom => pybloom::import(name.to_string(), path).await?,
+    };
+    let mut sbf = sbf.with_path(db.filter_path(name));
+    sbf.to_file(db.compression, db.key.as_ref()).await?;
+    sbf.mark_saved();
+    // Records left by a previous filter with the same name don't apply to this one
+    if let Some(aof) = db.aof.as_mut() {
+        aof.retain(|op| op.name() != name).await?;
+    }
+    db.unbacked.insert(name.to_string());
+    db.filters.insert(name.to_string(), sbf);
+    Ok(())
 }
 
-// Read filter info and format them into a `Response::Info`
-fn get_filter_info(f: &ScalableBloomFilter) -> Response {
+// Read filter info and format them into a `Response::Info`, the disk space is the size of the
+// last persisted snapshot plus its mapped bitmaps if any, 0 if the filter has never been written
+// to disk
+async fn get_filter_info(f: &ScalableBloomFilter, failure: Option<&EvictionFailure>) -> Response {
     let sec = f.creation_time().timestamp();
     let lat = f.last_access_time().timestamp();
+    let mut disk_space = 0;
+    for path in std::iter::once(f.path().to_path_buf()).chain(f.bitmap_paths()) {
+        disk_space += fs::metadata(path).await.map(|m| m.len()).unwrap_or(0);
+    }
     Response::Info {
         name: f.name().clone(),
         capacity: f.capacity(),
+        fpp: f.fpp(),
         size: f.size(),
         space: format!("{}", f.byte_space()),
+        disk_space,
         filters: f.filter_count() as u32,
+        scale_factor: f.scale_factor(),
         hash_count: f.hash_count(),
         hits: f.hits(),
         miss: f.miss(),
@@ -666,24 +4945,130 @@ fn get_filter_info(f: &ScalableBloomFilter) -> Response {
             .to_rfc3339(),
         last_access_time: DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(lat, 0), Utc)
             .to_rfc3339(),
+        changes: f.changes(),
+        storage: f.storage(),
+        eviction_failures: failure.map(|f| f.attempts).unwrap_or(0),
+        eviction_error: failure.map(|f| f.error.clone()),
     }
 }
 
 /// Run a tokio async server, init the shared filters database and accepts and handle new
 /// connections asynchronously.
 ///
-/// Requires single, already bound `TcpListener` argument
-pub async fn run(listener: TcpListener) -> AsyncResult<()> {
-    fs::create_dir_all(DEFAULT_DATA_DIR).await?;
+/// Requires single, already bound `Listener` argument and the server `Config`, binding the
+/// Unix domain socket, RESP, bloomd, HTTP and gRPC listeners too if configured
+pub async fn run(listener: Listener, config: Config) -> AsyncResult<()> {
+    fs::create_dir_all(config.data_dir()).await?;
+    let key = encryption_key(&config).await?;
     let filter_db = Arc::new(Mutex::new(FilterDatabase {
         filters: HashMap::new(),
-        cold_filters: HashSet::new(),
+        cold_filters: HashMap::new(),
+        aof: None,
+        compression: config.compression(),
+        key,
+        data_dir: config.data_dir().to_path_buf(),
+        locations: HashMap::new(),
+        volumes: config.volumes().clone(),
+        eviction_failures: HashMap::new(),
+        stats: Stats::default(),
+        last_snapshot: None,
+        snapshot_dir: config.snapshot_dir(),
+        interop_dir: config.interop_dir(),
+        backup_dir: config.backup().map(|b| b.dir(config.data_dir())),
+        unbacked: HashSet::new(),
     }));
+    let unix_listener = match config.unix_socket() {
+        Some(path) => {
+            let listener = Listener::bind_unix(path, config.unix_socket_mode()).await?;
+            info!("listening on {}", listener.local_addr());
+            Some(listener)
+        }
+        None => None,
+    };
+    let resp_listener = match config.resp_listen_on() {
+        Some(address) => {
+            let listener = Listener::bind(address, config.unix_socket_mode()).await?;
+            info!("listening for RESP clients on {}", address);
+            Some(listener)
+        }
+        None => None,
+    };
+    let bloomd_listener = match config.bloomd_listen_on() {
+        Some(address) => {
+            let listener = Listener::bind(address, config.unix_socket_mode()).await?;
+            info!("listening for bloomd clients on {}", address);
+            Some(listener)
+        }
+        None => None,
+    };
+    let http_listener = match config.http_listen_on() {
+        Some(address) => {
+            let listener = Listener::bind(address, config.unix_socket_mode()).await?;
+            info!("serving the HTTP API on {}", address);
+            Some(listener)
+        }
+        None => None,
+    };
+    let grpc_listener = match config.grpc_listen_on() {
+        Some(address) => {
+            let listener = Listener::bind(address, config.unix_socket_mode()).await?;
+            info!("serving the gRPC service on {}", address);
+            Some(listener)
+        }
+        None => None,
+    };
     let mut server = Server {
         listener,
+        unix_listener,
+        resp_listener,
+        bloomd_listener,
+        http_listener,
+        grpc_listener,
         backoff: BACKOFF,
         db: filter_db,
+        config,
     };
     server.init().await?;
     server.run().await
 }
+
+/// Upgrade every filter file of the configured data directory to the current on-disk format,
+/// along with the filters stored on other volumes. Files already up to date are left untouched,
+/// the upgraded ones are encrypted if encryption at rest is configured.
+///
+/// # Errors
+///
+/// Returns `Err` on the first file that can't be read or rewritten.
+pub async fn migrate(config: &Config) -> AsyncResult<()> {
+    let data_dir = config.data_dir();
+    let key = encryption_key(config).await?;
+    let mut paths = Vec::new();
+    let mut entries = fs::read_dir(data_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension() == Some(OsStr::new(FILTER_EXTENSION)) {
+            paths.push(path);
+        }
+    }
+    for (name, dir) in load_locations(data_dir).await? {
+        paths.push(ScalableBloomFilter::file_path(&dir, &name));
+    }
+    let (mut migrated, mut current) = (0, 0);
+    for path in paths {
+        if let Some(name) = path.to_str() {
+            if ScalableBloomFilter::migrate(name, key.as_ref()).await? {
+                info!("migrated {} to the current format", name);
+                migrated += 1;
+            } else {
+                current += 1;
+            }
+            let header = storage::read_header(&path).await?;
+            warn_plaintext_bitmaps(&path, header.as_ref(), key.as_ref());
+        }
+    }
+    info!(
+        "{} filters migrated, {} already up to date",
+        migrated, current
+    );
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use crate::crypto::Key;
+use crate::filter::{self, FilterFiles};
+use crate::storage::{self, Compression, HashAlgorithm, FLAG_ARCHIVE};
+use crate::AsyncResult;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+// Directory the snapshot archives are written to and restored from when not configured, under the
+// data directory
+pub const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+
+/// A filter stored into a snapshot archive
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub name: String,
+    /// Directory the filter is stored in, if out of the data directory
+    pub location: Option<PathBuf>,
+    pub files: FilterFiles,
+}
+
+/// Write the filters `entries` into a single archive at `path`, encrypted with `key` if given.
+/// The archive shares the header of the filter files, flagged with `FLAG_ARCHIVE`, followed by
+/// the bincode serialized entries.
+///
+/// # Errors
+///
+/// Returns `Err` if a file already exists at `path` or the archive can't be written.
+pub async fn write(
+    path: &Path,
+    entries: &[Entry],
+    compression: Compression,
+    key: Option<&Key>,
+) -> AsyncResult<()> {
+    if fs::metadata(path).await.is_ok() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+    let serialized = bincode::serialize(entries)?;
+    let data = storage::encode(
+        HashAlgorithm::Gxhash32,
+        compression,
+        FLAG_ARCHIVE,
+        &serialized,
+        key,
+    )?;
+    storage::write_atomic(path, &data).await
+}
+
+/// Read the filters stored into the archive at `path`, decrypting it with `key` if encrypted.
+///
+/// # Errors
+///
+/// Returns `Err` if the file can't be read, isn't an archive, is corrupt, can't be decrypted or
+/// holds an invalid filter name.
+pub async fn read(path: &Path, key: Option<&Key>) -> AsyncResult<Vec<Entry>> {
+    let data = fs::read(path).await?;
+    let decoded = storage::decode(&data, key).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if decoded.flags & FLAG_ARCHIVE == 0 {
+        return Err(format!("{}: not a snapshot archive", path.display()).into());
+    }
+    let entries: Vec<Entry> = bincode::deserialize(&decoded.payload)
+        .map_err(|e| format!("{}: corrupt snapshot archive: {}", path.display(), e))?;
+    for entry in entries.iter() {
+        filter::validate_name(&entry.name).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod snapshot_tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("snapshot.rbs");
+        let entries = vec![Entry {
+            name: "foo".into(),
+            location: None,
+            files: FilterFiles {
+                data: b"filter".to_vec(),
+                bitmaps: vec![b"bitmap".to_vec()],
+            },
+        }];
+        write(&path, &entries, Compression::Lz4, None)
+            .await
+            .unwrap();
+        assert_eq!(read(&path, None).await.unwrap(), entries);
+        // Existing files are never overwritten
+        assert!(write(&path, &entries, Compression::None, None)
+            .await
+            .is_err());
+        // Nor is a filter file mistaken for an archive
+        let data =
+            storage::encode(HashAlgorithm::Gxhash32, Compression::None, 0, b"", None).unwrap();
+        fs::write(&path, &data).await.unwrap();
+        let err = read(&path, None).await.unwrap_err();
+        assert!(err.to_string().contains("not a snapshot archive"));
+    }
+}
@@ -0,0 +1,569 @@
+use crate::crypto::Key;
+use crate::AsyncResult;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// Extension appended to a file name while it's being written
+pub const TMP_EXTENSION: &str = "tmp";
+// Magic number opening every filter file
+pub const MAGIC: &[u8; 4] = b"RBLO";
+// Current version of the on-disk format, version 0 is the legacy headerless bincode dump,
+// version 2 introduced payload compression and version 3 the header flags
+pub const FORMAT_VERSION: u16 = 3;
+// Size in bytes of the header, versions 1 and 2 lack the trailing flags
+const HEADER_SIZE: usize = 24;
+const HEADER_SIZE_V2: usize = 20;
+// Header flag marking filters whose bitmaps are stored in separate memory-mapped files
+pub const FLAG_MAPPED: u32 = 1;
+// Header flag marking snapshot archives of a whole database, rather than a single filter
+pub const FLAG_ARCHIVE: u32 = 2;
+// Header flag marking encrypted payloads, see `crypto::Key`
+pub const FLAG_ENCRYPTED: u32 = 4;
+// Compression level used with zstd
+const ZSTD_LEVEL: i32 = 3;
+
+/// Hash algorithm used to compute the bit positions of a filter, recorded in the header so that
+/// a file is never read back with a different one:
+/// - `Gxhash32` one gxhash32 digest per hash function, seeded with its index
+/// - `Murmur64A` double hashing of two MurmurHash64A digests, the scheme of RedisBloom filters
+///   imported with `redisbloom::load`
+/// - `Murmur128Mitz32` and `Murmur128Mitz64` the strategies of the same names of Guava filters
+///   imported with `guava::load`
+/// - `Pybloom` salted MD5 or SHA digests split across slices, the scheme of pybloom filters
+///   imported with `pybloom::load`
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum HashAlgorithm {
+    #[default]
+    Gxhash32 = 1,
+    Murmur64A = 2,
+    Murmur128Mitz32 = 3,
+    Murmur128Mitz64 = 4,
+    Pybloom = 5,
+}
+
+impl HashAlgorithm {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(HashAlgorithm::Gxhash32),
+            2 => Some(HashAlgorithm::Murmur64A),
+            3 => Some(HashAlgorithm::Murmur128Mitz32),
+            4 => Some(HashAlgorithm::Murmur128Mitz64),
+            5 => Some(HashAlgorithm::Pybloom),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashAlgorithm::Gxhash32 => write!(f, "gxhash32"),
+            HashAlgorithm::Murmur64A => write!(f, "redisbloom murmur64a"),
+            HashAlgorithm::Murmur128Mitz32 => write!(f, "guava murmur128_mitz_32"),
+            HashAlgorithm::Murmur128Mitz64 => write!(f, "guava murmur128_mitz_64"),
+            HashAlgorithm::Pybloom => write!(f, "pybloom"),
+        }
+    }
+}
+
+/// Compression applied to the serialized filter before writing it to disk, recorded in the
+/// header so that any file can be read back whatever the current configuration.
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+pub enum Compression {
+    #[serde(rename(deserialize = "none"))]
+    None = 0,
+    #[serde(rename(deserialize = "lz4"))]
+    Lz4 = 1,
+    #[serde(rename(deserialize = "zstd"))]
+    Zstd = 2,
+}
+
+impl Compression {
+    pub fn none() -> Self {
+        Compression::None
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    fn compress(self, payload: &[u8]) -> AsyncResult<Vec<u8>> {
+        match self {
+            Compression::None => Ok(payload.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(payload)),
+            Compression::Zstd => Ok(zstd::encode_all(payload, ZSTD_LEVEL)?),
+        }
+    }
+
+    fn decompress(self, payload: &[u8]) -> Result<Cow<'_, [u8]>, FormatError> {
+        match self {
+            Compression::None => Ok(Cow::Borrowed(payload)),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(payload)
+                .map(Cow::Owned)
+                .map_err(|e| FormatError::Decompression(e.to_string())),
+            Compression::Zstd => zstd::decode_all(payload)
+                .map(Cow::Owned)
+                .map_err(|e| FormatError::Decompression(e.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FormatError {
+    Truncated,
+    UnsupportedVersion(u16),
+    UnknownHashAlgorithm(u8),
+    UnknownCompression(u8),
+    ChecksumMismatch { expected: u32, found: u32 },
+    Decompression(String),
+    MissingKey,
+    WrongKey,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::Truncated => write!(f, "corrupt filter file: truncated"),
+            FormatError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported filter file version {}, latest supported is {}",
+                v, FORMAT_VERSION
+            ),
+            FormatError::UnknownHashAlgorithm(id) => {
+                write!(f, "unknown hash algorithm id {}", id)
+            }
+            FormatError::UnknownCompression(id) => write!(f, "unknown compression id {}", id),
+            FormatError::ChecksumMismatch { expected, found } => write!(
+                f,
+                "corrupt filter file: checksum mismatch, expected {:#010x} found {:#010x}",
+                expected, found
+            ),
+            FormatError::Decompression(e) => {
+                write!(f, "corrupt filter file: decompression failed: {}", e)
+            }
+            FormatError::MissingKey => {
+                write!(f, "encrypted file but no encryption key is configured")
+            }
+            FormatError::WrongKey => write!(f, "decryption failed: wrong encryption key"),
+        }
+    }
+}
+
+impl Error for FormatError {}
+
+impl FormatError {
+    /// Whether the error comes from damaged content rather than from the configuration, such as
+    /// a missing key or a file written by a newer version
+    pub fn is_corrupt(&self) -> bool {
+        matches!(
+            self,
+            FormatError::Truncated
+                | FormatError::ChecksumMismatch { .. }
+                | FormatError::Decompression(_)
+        )
+    }
+}
+
+/// Header of a filter file, see `encode` for its layout
+#[derive(Debug, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub hash_algorithm: HashAlgorithm,
+    pub compression: Compression,
+    pub flags: u32,
+    /// Length of the payload as stored, that is after compression
+    pub length: usize,
+    checksum: u32,
+    size: usize,
+}
+
+impl Header {
+    /// Parse the header at the start of `data`, `None` for legacy version 0 files which have none
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FormatError` if `data` is shorter than the header or the version, hash
+    /// algorithm or compression are unknown.
+    pub fn parse(data: &[u8]) -> Result<Option<Header>, FormatError> {
+        if !data.starts_with(MAGIC) {
+            return Ok(None);
+        }
+        if data.len() < HEADER_SIZE_V2 {
+            return Err(FormatError::Truncated);
+        }
+        let version = u16::from_le_bytes(data[4..6].try_into().unwrap());
+        if version > FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        let size = if version < 3 {
+            HEADER_SIZE_V2
+        } else {
+            HEADER_SIZE
+        };
+        if data.len() < size {
+            return Err(FormatError::Truncated);
+        }
+        let hash_algorithm =
+            HashAlgorithm::from_id(data[6]).ok_or(FormatError::UnknownHashAlgorithm(data[6]))?;
+        let compression =
+            Compression::from_id(data[7]).ok_or(FormatError::UnknownCompression(data[7]))?;
+        let flags = if version < 3 {
+            0
+        } else {
+            u32::from_le_bytes(data[20..24].try_into().unwrap())
+        };
+        Ok(Some(Header {
+            version,
+            hash_algorithm,
+            compression,
+            flags,
+            length: u64::from_le_bytes(data[8..16].try_into().unwrap()) as usize,
+            checksum: u32::from_le_bytes(data[16..20].try_into().unwrap()),
+            size,
+        }))
+    }
+}
+
+/// Decoded content of a filter file
+#[derive(Debug)]
+pub struct Decoded<'a> {
+    pub version: u16,
+    pub hash_algorithm: HashAlgorithm,
+    pub compression: Compression,
+    pub flags: u32,
+    pub payload: Cow<'a, [u8]>,
+}
+
+/// Compress a serialized filter, encrypt it if a `key` is given, and prepend the header to it.
+/// The header is laid out as follows, all integers are little endian:
+///
+/// | magic   | version | hash algorithm | compression | payload length | crc32 of payload | flags |
+/// | 4 bytes | u16     | u8             | u8          | u64            | u32              | u32   |
+///
+/// Length and checksum refer to the payload as stored, that is after compression and encryption,
+/// so that a corrupt file is told apart from a wrong key. Encrypted payloads are flagged with
+/// `FLAG_ENCRYPTED`. Version 1 and 2 files share the same layout without the flags, version 1
+/// with the compression byte reserved and always zero.
+pub fn encode(
+    hash_algorithm: HashAlgorithm,
+    compression: Compression,
+    mut flags: u32,
+    payload: &[u8],
+    key: Option<&Key>,
+) -> AsyncResult<Vec<u8>> {
+    let mut payload = compression.compress(payload)?;
+    if let Some(key) = key {
+        payload = key.encrypt(&payload)?;
+        flags |= FLAG_ENCRYPTED;
+    }
+    let mut data = Vec::with_capacity(HEADER_SIZE + payload.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    data.push(hash_algorithm as u8);
+    data.push(compression as u8);
+    data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    data.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    data.extend_from_slice(&flags.to_le_bytes());
+    data.extend_from_slice(&payload);
+    Ok(data)
+}
+
+/// Validate the header of a filter file and return its payload, decrypted with `key` and
+/// decompressed. Files not starting with the magic number are legacy version 0 dumps, carrying
+/// only the serialized filter. Unencrypted files are read whether a key is given or not.
+///
+/// # Errors
+///
+/// Returns a `FormatError` if the file is truncated, the checksum doesn't match the payload, the
+/// payload is encrypted and `key` is missing or wrong, the payload can't be decompressed or the
+/// version, hash algorithm or compression are unknown.
+pub fn decode<'a>(data: &'a [u8], key: Option<&Key>) -> Result<Decoded<'a>, FormatError> {
+    let header = match Header::parse(data)? {
+        Some(header) => header,
+        None => {
+            return Ok(Decoded {
+                version: 0,
+                hash_algorithm: HashAlgorithm::Gxhash32,
+                compression: Compression::None,
+                flags: 0,
+                payload: Cow::Borrowed(data),
+            })
+        }
+    };
+    let payload = &data[header.size..];
+    if payload.len() != header.length {
+        return Err(FormatError::Truncated);
+    }
+    let found = crc32fast::hash(payload);
+    if found != header.checksum {
+        return Err(FormatError::ChecksumMismatch {
+            expected: header.checksum,
+            found,
+        });
+    }
+    let payload = if header.flags & FLAG_ENCRYPTED != 0 {
+        let decrypted = key
+            .ok_or(FormatError::MissingKey)?
+            .decrypt(payload)
+            .ok_or(FormatError::WrongKey)?;
+        Cow::Owned(header.compression.decompress(&decrypted)?.into_owned())
+    } else {
+        header.compression.decompress(payload)?
+    };
+    Ok(Decoded {
+        version: header.version,
+        hash_algorithm: header.hash_algorithm,
+        compression: header.compression,
+        flags: header.flags,
+        payload,
+    })
+}
+
+/// Read and parse only the header of the filter file at `path`, without loading the payload.
+/// Returns `None` for legacy version 0 files.
+///
+/// # Errors
+///
+/// Returns `Err` if the file can't be read or its header is invalid, see `Header::parse`.
+pub async fn read_header(path: &Path) -> AsyncResult<Option<Header>> {
+    let mut file = fs::File::open(path).await?;
+    let mut data = Vec::with_capacity(HEADER_SIZE);
+    (&mut file)
+        .take(HEADER_SIZE as u64)
+        .read_to_end(&mut data)
+        .await?;
+    Ok(Header::parse(&data).map_err(|e| format!("{}: {}", path.display(), e))?)
+}
+
+/// Return the temporary path used while writing `path`, e.g. `rublo/foo.rbl.tmp`
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path
+        .file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_default();
+    name.push(".");
+    name.push(TMP_EXTENSION);
+    path.with_file_name(name)
+}
+
+/// Write `data` to `path` atomically: the content is first written to a temporary file in the
+/// same directory and synced to disk, then renamed over `path`, finally the parent directory is
+/// synced as well to persist the rename. A crash at any point leaves either the previous file or
+/// the new one, never a truncated mix of the two.
+///
+/// # Errors
+///
+/// Returns `Err` if any of the write, sync or rename fails, in which case the temporary file is
+/// removed and `path` is left untouched.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> AsyncResult<()> {
+    let tmp = tmp_path(path);
+    if let Err(e) = write_and_sync(&tmp, data).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&tmp, path).await {
+        let _ = fs::remove_file(&tmp).await;
+        return Err(e.into());
+    }
+    sync_parent_dir(path).await
+}
+
+async fn write_and_sync(path: &Path, data: &[u8]) -> AsyncResult<()> {
+    let mut file = fs::File::create(path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn sync_parent_dir(path: &Path) -> AsyncResult<()> {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn sync_parent_dir(_path: &Path) -> AsyncResult<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let data = encode(
+            HashAlgorithm::Gxhash32,
+            Compression::None,
+            0,
+            b"payload",
+            None,
+        )
+        .unwrap();
+        assert_eq!(&data[..4], MAGIC);
+        let decoded = decode(&data, None).unwrap();
+        assert_eq!(decoded.version, FORMAT_VERSION);
+        assert_eq!(decoded.hash_algorithm, HashAlgorithm::Gxhash32);
+        assert_eq!(&decoded.payload[..], b"payload");
+        // Legacy files carry no header at all
+        let decoded = decode(b"payload", None).unwrap();
+        assert_eq!(decoded.version, 0);
+        assert_eq!(&decoded.payload[..], b"payload");
+        // Version 1 and 2 files share the layout, without the flags
+        let mut v2 = data[..HEADER_SIZE_V2].to_vec();
+        v2.extend_from_slice(b"payload");
+        v2[4..6].copy_from_slice(&2u16.to_le_bytes());
+        let decoded = decode(&v2, None).unwrap();
+        assert_eq!(decoded.version, 2);
+        assert_eq!(decoded.flags, 0);
+        assert_eq!(&decoded.payload[..], b"payload");
+        let flagged = encode(
+            HashAlgorithm::Gxhash32,
+            Compression::None,
+            FLAG_MAPPED,
+            b"payload",
+            None,
+        )
+        .unwrap();
+        assert_eq!(decode(&flagged, None).unwrap().flags, FLAG_MAPPED);
+    }
+
+    #[test]
+    fn test_compression() {
+        let payload = vec![0u8; 64 * 1024];
+        for compression in [Compression::Lz4, Compression::Zstd].iter() {
+            let data = encode(HashAlgorithm::Gxhash32, *compression, 0, &payload, None).unwrap();
+            assert!(data.len() < payload.len() / 10);
+            let decoded = decode(&data, None).unwrap();
+            assert_eq!(decoded.compression, *compression);
+            assert_eq!(&decoded.payload[..], &payload[..]);
+        }
+        let mut unknown =
+            encode(HashAlgorithm::Gxhash32, Compression::Lz4, 0, &payload, None).unwrap();
+        unknown[7] = 42;
+        assert_eq!(
+            decode(&unknown, None).unwrap_err(),
+            FormatError::UnknownCompression(42)
+        );
+    }
+
+    #[test]
+    fn test_encryption() {
+        let key = Key::from_hex(&"2a".repeat(crate::crypto::KEY_SIZE)).unwrap();
+        let payload = vec![7u8; 1024];
+        let data = encode(
+            HashAlgorithm::Gxhash32,
+            Compression::Zstd,
+            FLAG_MAPPED,
+            &payload,
+            Some(&key),
+        )
+        .unwrap();
+        let decoded = decode(&data, Some(&key)).unwrap();
+        assert_eq!(decoded.flags, FLAG_MAPPED | FLAG_ENCRYPTED);
+        assert_eq!(&decoded.payload[..], &payload[..]);
+        assert_eq!(decode(&data, None).unwrap_err(), FormatError::MissingKey);
+        let wrong = Key::from_hex(&"00".repeat(crate::crypto::KEY_SIZE)).unwrap();
+        assert_eq!(
+            decode(&data, Some(&wrong)).unwrap_err(),
+            FormatError::WrongKey
+        );
+        // Corruption is still reported as such, not as a wrong key
+        let mut flipped = data.clone();
+        flipped[HEADER_SIZE + 20] ^= 0xff;
+        assert!(matches!(
+            decode(&flipped, Some(&key)).unwrap_err(),
+            FormatError::ChecksumMismatch { .. }
+        ));
+        // Unencrypted files are still read once a key is configured
+        let plain = encode(
+            HashAlgorithm::Gxhash32,
+            Compression::None,
+            0,
+            &payload,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            &decode(&plain, Some(&key)).unwrap().payload[..],
+            &payload[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("test.rbl");
+        let data = encode(
+            HashAlgorithm::Gxhash32,
+            Compression::Lz4,
+            0,
+            &[0u8; 1024],
+            None,
+        )
+        .unwrap();
+        fs::write(&path, &data).await.unwrap();
+        let header = read_header(&path).await.unwrap().unwrap();
+        assert_eq!(header.version, FORMAT_VERSION);
+        assert_eq!(header.compression, Compression::Lz4);
+        assert_eq!(header.length, data.len() - HEADER_SIZE);
+        fs::write(&path, b"legacy").await.unwrap();
+        assert!(read_header(&path).await.unwrap().is_none());
+        fs::write(&path, &data[..10]).await.unwrap();
+        assert!(read_header(&path).await.is_err());
+    }
+
+    #[test]
+    fn test_decode_corrupt() {
+        let data = encode(
+            HashAlgorithm::Gxhash32,
+            Compression::None,
+            0,
+            b"payload",
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            decode(&data[..10], None).unwrap_err(),
+            FormatError::Truncated
+        );
+        assert_eq!(
+            decode(&data[..data.len() - 1], None).unwrap_err(),
+            FormatError::Truncated
+        );
+        let mut flipped = data.clone();
+        flipped[HEADER_SIZE] ^= 0xff;
+        assert!(matches!(
+            decode(&flipped, None).unwrap_err(),
+            FormatError::ChecksumMismatch { .. }
+        ));
+        let mut newer = data.clone();
+        newer[4] = 0xff;
+        assert_eq!(
+            decode(&newer, None).unwrap_err(),
+            FormatError::UnsupportedVersion(0xff)
+        );
+        let mut unknown = data;
+        unknown[6] = 42;
+        assert_eq!(
+            decode(&unknown, None).unwrap_err(),
+            FormatError::UnknownHashAlgorithm(42)
+        );
+    }
+}
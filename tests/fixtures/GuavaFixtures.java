@@ -0,0 +1,33 @@
+// Write the Guava fixtures with Guava itself, e.g. with Guava 31:
+//
+//     javac -cp guava-31.1-jre.jar -d . GuavaFixtures.java
+//     java -cp guava-31.1-jre.jar:. com.google.common.hash.GuavaFixtures
+//
+// The class lives in Guava's package to pick the MURMUR128_MITZ_32 strategy, only
+// MURMUR128_MITZ_64 is public.
+package com.google.common.hash;
+
+import java.io.FileOutputStream;
+import java.io.IOException;
+import java.io.OutputStream;
+import java.nio.charset.StandardCharsets;
+
+public class GuavaFixtures {
+    static final String[] KEYS = {"apple", "banana", "cherry", "damson", "elderberry"};
+
+    static void write(String file, BloomFilter.Strategy strategy) throws IOException {
+        BloomFilter<CharSequence> filter =
+                BloomFilter.create(Funnels.stringFunnel(StandardCharsets.UTF_8), 100, 0.01, strategy);
+        for (String key : KEYS) {
+            filter.put(key);
+        }
+        try (OutputStream out = new FileOutputStream(file)) {
+            filter.writeTo(out);
+        }
+    }
+
+    public static void main(String[] args) throws IOException {
+        write("guava-mitz32.bin", BloomFilterStrategies.MURMUR128_MITZ_32);
+        write("guava-mitz64.bin", BloomFilterStrategies.MURMUR128_MITZ_64);
+    }
+}
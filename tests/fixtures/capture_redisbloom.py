@@ -0,0 +1,38 @@
+#!/usr/bin/env python3
+"""Capture the RedisBloom dump used by the import tests.
+
+Needs redis-py and a Redis server with RedisBloom 2.2 or later loaded, e.g.
+`docker run -p 6379:6379 redis/redis-stack-server`. The filter is dumped with
+`BF.SCANDUMP` into the file format of `rublo` exports: each chunk as its
+iterator (signed 64 bits) and its length (unsigned 64 bits), little-endian,
+followed by its data.
+
+    ./capture_redisbloom.py [host [port]]
+"""
+
+import os
+import struct
+import sys
+
+import redis
+
+KEY = "rublo-fixture"
+KEYS = ["foo", "bar"]
+
+if __name__ == "__main__":
+    host = sys.argv[1] if len(sys.argv) > 1 else "localhost"
+    port = int(sys.argv[2]) if len(sys.argv) > 2 else 6379
+    r = redis.Redis(host=host, port=port)
+    r.delete(KEY)
+    r.execute_command("BF.RESERVE", KEY, 0.01, 100)
+    for key in KEYS:
+        r.execute_command("BF.ADD", KEY, key)
+    directory = os.path.dirname(os.path.abspath(__file__))
+    with open(os.path.join(directory, "redisbloom-foo-bar.rdb"), "wb") as f:
+        it = 0
+        while True:
+            it, data = r.execute_command("BF.SCANDUMP", KEY, it)
+            if it == 0:
+                break
+            f.write(struct.pack("<qQ", it, len(data)) + data)
+    r.delete(KEY)
@@ -0,0 +1,169 @@
+#!/usr/bin/env python3
+"""Reproduce the Guava and pybloom fixtures with the Python standard library.
+
+The committed fixtures were written by this script, a reimplementation of
+`BloomFilter.writeTo` of Guava 31 and `BloomFilter.tofile` of pybloom-live 4,
+hashing included. GuavaFixtures.java and pybloom_fixtures.py write them with
+the libraries themselves, their output replaces this script's.
+"""
+
+import hashlib
+import math
+import os
+import struct
+
+KEYS = ["apple", "banana", "cherry", "damson", "elderberry"]
+MASK64 = (1 << 64) - 1
+
+
+def rotl64(x, r):
+    return ((x << r) | (x >> (64 - r))) & MASK64
+
+
+def fmix64(k):
+    k ^= k >> 33
+    k = (k * 0xFF51AFD7ED558CCD) & MASK64
+    k ^= k >> 33
+    k = (k * 0xC4CEB9FE1A85EC53) & MASK64
+    k ^= k >> 33
+    return k
+
+
+def murmur3_x64_128(data, seed=0):
+    """MurmurHash3_x64_128, returned as its two little-endian halves h1, h2"""
+    c1, c2 = 0x87C37B91114253D5, 0x4CF5AD432745937F
+    h1 = h2 = seed
+    blocks = len(data) // 16
+    for i in range(blocks):
+        k1, k2 = struct.unpack_from("<QQ", data, i * 16)
+        k1 = (k1 * c1) & MASK64
+        k1 = rotl64(k1, 31)
+        k1 = (k1 * c2) & MASK64
+        h1 ^= k1
+        h1 = rotl64(h1, 27)
+        h1 = (h1 + h2) & MASK64
+        h1 = (h1 * 5 + 0x52DCE729) & MASK64
+        k2 = (k2 * c2) & MASK64
+        k2 = rotl64(k2, 33)
+        k2 = (k2 * c1) & MASK64
+        h2 ^= k2
+        h2 = rotl64(h2, 31)
+        h2 = (h2 + h1) & MASK64
+        h2 = (h2 * 5 + 0x38495AB5) & MASK64
+    tail = data[blocks * 16:]
+    k1 = int.from_bytes(tail[:8], "little")
+    k2 = int.from_bytes(tail[8:], "little")
+    if len(tail) > 8:
+        k2 = (k2 * c2) & MASK64
+        k2 = rotl64(k2, 33)
+        k2 = (k2 * c1) & MASK64
+        h2 ^= k2
+    if len(tail) > 0:
+        k1 = (k1 * c1) & MASK64
+        k1 = rotl64(k1, 31)
+        k1 = (k1 * c2) & MASK64
+        h1 ^= k1
+    h1 ^= len(data)
+    h2 ^= len(data)
+    h1 = (h1 + h2) & MASK64
+    h2 = (h2 + h1) & MASK64
+    h1 = fmix64(h1)
+    h2 = fmix64(h2)
+    h1 = (h1 + h2) & MASK64
+    h2 = (h2 + h1) & MASK64
+    return h1, h2
+
+
+def to_signed(x, bits):
+    return x - (1 << bits) if x >= 1 << (bits - 1) else x
+
+
+def guava(strategy, expected_insertions, fpp):
+    """Bytes written by BloomFilter.writeTo, strategy 0 is MURMUR128_MITZ_32, 1 MURMUR128_MITZ_64"""
+    num_bits = int(-expected_insertions * math.log(fpp) / (math.log(2) ** 2))
+    num_hashes = max(1, round(num_bits / expected_insertions * math.log(2)))
+    longs = [0] * ((num_bits + 63) // 64)
+    bit_size = len(longs) * 64
+    for key in KEYS:
+        h1, h2 = murmur3_x64_128(key.encode("utf-8"))
+        if strategy == 0:
+            hash1 = to_signed(h1 & 0xFFFFFFFF, 32)
+            hash2 = to_signed(h1 >> 32, 32)
+            positions = []
+            for i in range(1, num_hashes + 1):
+                combined = to_signed((hash1 + i * hash2) & 0xFFFFFFFF, 32)
+                if combined < 0:
+                    combined = ~combined
+                positions.append(combined % bit_size)
+        else:
+            positions = []
+            combined = h1
+            for _ in range(num_hashes):
+                positions.append((combined & (MASK64 >> 1)) % bit_size)
+                combined = (combined + h2) & MASK64
+        for x in positions:
+            longs[x >> 6] |= 1 << (x & 63)
+    data = struct.pack(">bBi", strategy, num_hashes, len(longs))
+    return data + b"".join(struct.pack(">q", to_signed(l, 64)) for l in longs)
+
+
+def pybloom(capacity, error_rate):
+    """Bytes written by BloomFilter.tofile"""
+    num_slices = int(math.ceil(math.log(1.0 / error_rate, 2)))
+    bits_per_slice = int(math.ceil(
+        (capacity * abs(math.log(error_rate))) / (num_slices * (math.log(2) ** 2))))
+    num_bits = num_slices * bits_per_slice
+    if bits_per_slice >= 1 << 31:
+        fmt_code, chunk_size = "Q", 8
+    elif bits_per_slice >= 1 << 15:
+        fmt_code, chunk_size = "I", 4
+    else:
+        fmt_code, chunk_size = "H", 2
+    total_hash_bits = 8 * num_slices * chunk_size
+    if total_hash_bits > 384:
+        hashfn = hashlib.sha512
+    elif total_hash_bits > 256:
+        hashfn = hashlib.sha384
+    elif total_hash_bits > 160:
+        hashfn = hashlib.sha256
+    elif total_hash_bits > 128:
+        hashfn = hashlib.sha1
+    else:
+        hashfn = hashlib.md5
+    fmt = "<" + fmt_code * (hashfn().digest_size // chunk_size)
+    num_salts = -(-num_slices // (len(fmt) - 1))
+    salts = [hashfn(hashfn(struct.pack("<I", i)).digest()) for i in range(num_salts)]
+    bits = [False] * num_bits
+    for key in KEYS:
+        hashes = []
+        for salt in salts:
+            h = salt.copy()
+            h.update(key.encode("utf-8"))
+            hashes.extend(struct.unpack(fmt, h.digest()))
+        for offset, h in enumerate(hashes[:num_slices]):
+            bits[offset * bits_per_slice + h % bits_per_slice] = True
+    bitmap = bytearray((num_bits + 7) // 8)
+    for i, bit in enumerate(bits):
+        if bit:
+            bitmap[i // 8] |= 1 << (i % 8)
+    header = struct.pack("<dQQQQ", error_rate, num_slices, bits_per_slice, capacity, len(KEYS))
+    return header + bytes(bitmap)
+
+
+FIXTURES = {
+    "guava-mitz32.bin": lambda: guava(0, 100, 0.01),
+    "guava-mitz64.bin": lambda: guava(1, 100, 0.01),
+    # Small slices are hashed with MD5, larger ones with SHA-256
+    "pybloom-md5.bin": lambda: pybloom(100, 0.01),
+    "pybloom-sha256.bin": lambda: pybloom(1000, 0.0001),
+}
+
+if __name__ == "__main__":
+    # Known values of Guava's Murmur3_128HashFunction tests
+    assert murmur3_x64_128(b"hello", 1) == (0xA78DDFF5ADAE8D10, 0x128900EF20900135)
+    assert murmur3_x64_128(b"The quick brown fox jumps over the lazy dog") == (
+        0xE34BBC7BBC071B6C, 0x7A433CA9C49A9347)
+    directory = os.path.dirname(os.path.abspath(__file__))
+    for name, generate in FIXTURES.items():
+        with open(os.path.join(directory, name), "wb") as f:
+            f.write(generate())
@@ -0,0 +1,23 @@
+#!/usr/bin/env python3
+"""Write the pybloom fixtures with pybloom-live itself (pip install pybloom-live)."""
+
+import os
+
+from pybloom_live import BloomFilter
+
+KEYS = ["apple", "banana", "cherry", "damson", "elderberry"]
+
+FIXTURES = {
+    # Small slices are hashed with MD5, larger ones with SHA-256
+    "pybloom-md5.bin": (100, 0.01),
+    "pybloom-sha256.bin": (1000, 0.0001),
+}
+
+if __name__ == "__main__":
+    directory = os.path.dirname(os.path.abspath(__file__))
+    for name, (capacity, error_rate) in FIXTURES.items():
+        f = BloomFilter(capacity=capacity, error_rate=error_rate)
+        for key in KEYS:
+            f.add(key)
+        with open(os.path.join(directory, name), "wb") as out:
+            f.tofile(out)